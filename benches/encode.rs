@@ -0,0 +1,76 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ergvein_protocol::message::*;
+use ergvein_protocol::util::{try_serialize, LengthVecRef};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+fn filters_resp(amount: usize) -> FiltersResp {
+    FiltersResp {
+        currency: Currency::Btc,
+        filters: (0..amount)
+            .map(|i| Filter {
+                block_id: vec![i as u8; 32],
+                filter: (0..40).map(|j| (i * 31 + j * 7) as u8).collect(),
+            })
+            .collect(),
+    }
+}
+
+fn peers(amount: usize) -> Vec<Address> {
+    (0..amount)
+        .map(|i| Address::Ipv4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, i as u8), 8667)))
+        .collect()
+}
+
+/// Encoding as it was done before payloads could report their size: the payload is serialized
+/// into a temporary vector to learn the length prefix and then copied into the sink.
+fn encode_via_buffer<T: Encodable + ?Sized>(id: u32, payload: &T, sink: &mut Vec<u8>) {
+    let buf = try_serialize(payload).unwrap();
+    VarInt(id as u64).consensus_encode(&mut *sink).unwrap();
+    VarInt(buf.len() as u64)
+        .consensus_encode(&mut *sink)
+        .unwrap();
+    sink.extend_from_slice(&buf);
+}
+
+fn encode_filters(c: &mut Criterion) {
+    let resp = filters_resp(2000);
+    let msg = Message::Filters(resp.clone());
+    let mut sink = Vec::with_capacity(msg.encoded_len_with(CodecKind::Gzip).unwrap());
+    let mut group = c.benchmark_group("encode filters");
+    group.bench_function("direct", |b| {
+        b.iter(|| {
+            sink.clear();
+            black_box(&msg).consensus_encode(&mut sink).unwrap();
+        })
+    });
+    group.bench_function("temporary buffer", |b| {
+        b.iter(|| {
+            sink.clear();
+            encode_via_buffer(3, black_box(&resp), &mut sink);
+        })
+    });
+    group.finish();
+}
+
+fn encode_peers(c: &mut Criterion) {
+    let addrs = peers(100);
+    let msg = Message::Peers(addrs.clone());
+    let mut sink = Vec::with_capacity(msg.encoded_len_with(CodecKind::Gzip).unwrap());
+    let mut group = c.benchmark_group("encode peers");
+    group.bench_function("direct", |b| {
+        b.iter(|| {
+            sink.clear();
+            black_box(&msg).consensus_encode(&mut sink).unwrap();
+        })
+    });
+    group.bench_function("temporary buffer", |b| {
+        b.iter(|| {
+            sink.clear();
+            encode_via_buffer(6, &LengthVecRef(black_box(&addrs)), &mut sink);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, encode_filters, encode_peers);
+criterion_main!(benches);
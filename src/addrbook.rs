@@ -0,0 +1,617 @@
+//! Book of indexer addresses learned from `Peers`, `PeerIntroduce` and `PeerRecords` messages.
+//! Tracks when addresses were seen and connected to, picks diverse outbound candidates and
+//! persists to a compact binary file that reuses addrv2 encoding of `Address`.
+use crate::message::*;
+use crate::scoring::Scoreboard;
+use consensus_encode::{Decodable, Encodable, Error, VarInt};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Magic bytes at the start of the address book file
+const FILE_MAGIC: [u8; 4] = *b"EGAB";
+
+/// Version of the address book file format. Version 1 stored addresses in the original encoding.
+const FILE_VERSION: u8 = 2;
+
+/// Addresses the book keeps, unless it is changed with `set_capacity`
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Addresses of a single bucket the book keeps, unless it is changed with `set_capacity`. Limit
+/// of the bucket stops announcements of a single subnet from pushing other addresses out.
+pub const DEFAULT_BUCKET_CAPACITY: usize = 1_000;
+
+/// Addresses of a single message that `ingest` considers, the rest of them is ignored. Limits
+/// the work a peer can cause with one message, as every address may evict another one.
+pub const MAX_INGEST: usize = 1_000;
+
+/// What is known about an address
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub struct PeerInfo {
+    pub address: Address,
+    /// Last time the address was announced or connected to
+    pub last_seen: Timestamp,
+    /// Last time connection to the address succeeded
+    pub last_success: Option<Timestamp>,
+    /// Failed connection attempts since the last success
+    pub failures: u32,
+}
+
+impl PeerInfo {
+    fn new(address: Address, now: Timestamp) -> Self {
+        PeerInfo {
+            address,
+            last_seen: now,
+            last_success: None,
+            failures: 0,
+        }
+    }
+}
+
+impl PeerInfo {
+    fn decode_fields<D: io::Read>(address: Address, mut d: D) -> Result<Self, Error> {
+        let last_seen = Decodable::consensus_decode(&mut d)?;
+        let last_success: u64 = Decodable::consensus_decode(&mut d)?;
+        let failures = Decodable::consensus_decode(&mut d)?;
+        Ok(PeerInfo {
+            address,
+            last_seen,
+            last_success: Some(last_success).filter(|t| *t != 0),
+            failures,
+        })
+    }
+}
+
+impl Encodable for PeerInfo {
+    fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
+        let mut len = self.address.encode_v2(&mut s)?;
+        len += self.last_seen.consensus_encode(&mut s)?;
+        // Zero stands for never, connection can't succeed at the epoch
+        len += self.last_success.unwrap_or(0).consensus_encode(&mut s)?;
+        len += self.failures.consensus_encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for PeerInfo {
+    /// Fails on address of unknown network, the book can't contain such addresses
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+        let address =
+            Address::decode_v2(&mut d)?.ok_or(Error::ParseFailed("Unknown address network"))?;
+        PeerInfo::decode_fields(address, d)
+    }
+}
+
+/// Network group of an address. Candidates are picked from different groups, so that a single
+/// operator of a subnet can't take all outbound connections.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub enum Bucket {
+    /// First two octets of IPv4 address
+    Ipv4([u8; 2]),
+    /// First 32 bits of IPv6 address
+    Ipv6([u8; 4]),
+    Onion,
+    I2p,
+    Cjdns,
+    Hostname,
+}
+
+impl Bucket {
+    pub fn of(address: &Address) -> Bucket {
+        match address {
+            Address::Ipv4(addr) => {
+                let o = addr.ip().octets();
+                Bucket::Ipv4([o[0], o[1]])
+            }
+            Address::Ipv6(addr) => {
+                let o = addr.ip().octets();
+                Bucket::Ipv6([o[0], o[1], o[2], o[3]])
+            }
+            Address::OnionV3(..) => Bucket::Onion,
+            Address::I2p(..) => Bucket::I2p,
+            Address::Cjdns(..) => Bucket::Cjdns,
+            Address::Hostname(..) => Bucket::Hostname,
+        }
+    }
+}
+
+/// Addresses are kept up to the capacity of the book and of their bucket. When either is full,
+/// a new address replaces the worst one of the bucket or of the book, like in addrman of
+/// bitcoin core, unless the new address isn't better. Addresses are ordered by rank in every
+/// bucket and in the whole book, so the worst one is found without scanning.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct AddressBook {
+    peers: BTreeMap<Address, PeerInfo>,
+    /// Addresses of every nonempty bucket from the best to the worst
+    buckets: HashMap<Bucket, Ranking>,
+    /// All addresses from the best to the worst
+    ranking: Ranking,
+    capacity: usize,
+    bucket_capacity: usize,
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        AddressBook {
+            peers: BTreeMap::new(),
+            buckets: HashMap::new(),
+            ranking: BTreeSet::new(),
+            capacity: DEFAULT_CAPACITY,
+            bucket_capacity: DEFAULT_BUCKET_CAPACITY,
+        }
+    }
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        AddressBook::default()
+    }
+
+    /// Change amount of addresses the book and a single bucket keep. Worst addresses are evicted
+    /// if there are more of them already.
+    pub fn set_capacity(&mut self, capacity: usize, bucket_capacity: usize) {
+        self.capacity = capacity;
+        self.bucket_capacity = bucket_capacity;
+        let buckets: Vec<Bucket> = self.buckets.keys().copied().collect();
+        for bucket in buckets {
+            while self.bucket_len(bucket) > bucket_capacity {
+                self.evict(Some(bucket));
+            }
+        }
+        while self.peers.len() > capacity {
+            self.evict(None);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn get(&self, address: &Address) -> Option<&PeerInfo> {
+        self.peers.get(address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PeerInfo> {
+        self.peers.values()
+    }
+
+    /// Add address or refresh its last seen time. Returns whether the address is new and there
+    /// was room for it.
+    pub fn add(&mut self, address: Address, now: Timestamp) -> bool {
+        if self.update(&address, |info| info.last_seen = info.last_seen.max(now)) {
+            return false;
+        }
+        self.insert(PeerInfo::new(address, now))
+    }
+
+    /// Change known address keeping its rank indexed. Returns whether the address is known.
+    fn update<F: FnOnce(&mut PeerInfo)>(&mut self, address: &Address, f: F) -> bool {
+        let info = match self.peers.get_mut(address) {
+            Some(info) => info,
+            None => return false,
+        };
+        let old = (rank(info), address.clone());
+        f(info);
+        let new = (rank(info), address.clone());
+        if old != new {
+            let bucket = self.buckets.get_mut(&Bucket::of(address));
+            let bucket = bucket.expect("known address is indexed");
+            bucket.remove(&old);
+            bucket.insert(new.clone());
+            self.ranking.remove(&old);
+            self.ranking.insert(new);
+        }
+        true
+    }
+
+    /// Insert unknown address. When its bucket or the book is full, the worst address of the
+    /// bucket or of the book is evicted, unless the new one isn't better.
+    fn insert(&mut self, info: PeerInfo) -> bool {
+        let bucket = Bucket::of(&info.address);
+        let worst = if self.bucket_len(bucket) >= self.bucket_capacity {
+            Some(self.worst(Some(bucket)))
+        } else if self.peers.len() >= self.capacity {
+            Some(self.worst(None))
+        } else {
+            None
+        };
+        let key = (rank(&info), info.address.clone());
+        if let Some(worst) = worst {
+            match worst {
+                Some((worst, address)) if *worst > key.0 => {
+                    let address = address.clone();
+                    self.remove(&address);
+                }
+                _ => return false,
+            }
+        }
+        self.buckets.entry(bucket).or_default().insert(key.clone());
+        self.ranking.insert(key);
+        self.peers.insert(info.address.clone(), info);
+        true
+    }
+
+    fn bucket_len(&self, bucket: Bucket) -> usize {
+        self.buckets.get(&bucket).map_or(0, |ranking| ranking.len())
+    }
+
+    /// Address of the bucket or of the whole book with the worst rank
+    fn worst(&self, bucket: Option<Bucket>) -> Option<&(Rank, Address)> {
+        match bucket {
+            Some(bucket) => self.buckets.get(&bucket)?.iter().next_back(),
+            None => self.ranking.iter().next_back(),
+        }
+    }
+
+    fn evict(&mut self, bucket: Option<Bucket>) {
+        if let Some((_, address)) = self.worst(bucket).cloned() {
+            self.remove(&address);
+        }
+    }
+
+    /// Add addresses from `Peers` and `PeerIntroduce` messages of either encoding and from
+    /// `PeerRecords`, tagged or not. Last seen time of records is taken unless it is in the
+    /// future. Other messages are ignored, and so are addresses beyond the first `MAX_INGEST`.
+    /// Returns amount of added addresses.
+    pub fn ingest(&mut self, msg: &Message, now: Timestamp) -> usize {
+        match msg {
+            Message::Tagged { message, .. } => return self.ingest(message, now),
+            Message::PeerRecords(records) => {
+                return records
+                    .iter()
+                    .take(MAX_INGEST)
+                    .filter(|r| self.add(r.address.clone(), r.last_seen.min(now)))
+                    .count()
+            }
+            _ => (),
+        }
+        msg.addresses().map_or(0, |addrs| {
+            addrs
+                .iter()
+                .take(MAX_INGEST)
+                .filter(|addr| self.add((*addr).clone(), now))
+                .count()
+        })
+    }
+
+    pub fn remove(&mut self, address: &Address) -> Option<PeerInfo> {
+        let info = self.peers.remove(address)?;
+        let key = (rank(&info), address.clone());
+        self.ranking.remove(&key);
+        let bucket = Bucket::of(address);
+        if let Some(ranking) = self.buckets.get_mut(&bucket) {
+            ranking.remove(&key);
+            if ranking.is_empty() {
+                self.buckets.remove(&bucket);
+            }
+        }
+        Some(info)
+    }
+
+    /// Record successful connection, the address is added if it is unknown
+    pub fn mark_success(&mut self, address: &Address, now: Timestamp) {
+        let known = self.update(address, |info| {
+            info.last_seen = info.last_seen.max(now);
+            info.last_success = Some(now);
+            info.failures = 0;
+        });
+        if !known {
+            let mut info = PeerInfo::new(address.clone(), now);
+            info.last_success = Some(now);
+            self.insert(info);
+        }
+    }
+
+    /// Record failed connection attempt. Unknown addresses are ignored.
+    pub fn mark_failure(&mut self, address: &Address) {
+        self.update(address, |info| info.failures += 1);
+    }
+
+    /// Pick up to `count` addresses to connect to. The best address of every bucket goes first,
+    /// then the second best of every bucket and so on. Addresses with fewer failures, then
+    /// with more recent success and then more recently seen are better.
+    pub fn candidates(&self, count: usize) -> Vec<Address> {
+        self.pick(count, |_| true)
+    }
+
+    /// Pick candidates like `candidates` skipping banned addresses
+    pub fn candidates_unbanned(
+        &self,
+        count: usize,
+        scores: &Scoreboard,
+        now: Timestamp,
+    ) -> Vec<Address> {
+        self.pick(count, |info| !scores.is_banned(&info.address, now))
+    }
+
+    fn pick<F: Fn(&PeerInfo) -> bool>(&self, count: usize, allowed: F) -> Vec<Address> {
+        let mut buckets: HashMap<Bucket, Vec<&PeerInfo>> = HashMap::new();
+        for info in self.peers.values().filter(|info| allowed(info)) {
+            buckets
+                .entry(Bucket::of(&info.address))
+                .or_default()
+                .push(info);
+        }
+        let mut buckets: Vec<Vec<&PeerInfo>> = buckets.into_values().collect();
+        for bucket in buckets.iter_mut() {
+            bucket.sort_by_key(|info| rank(info));
+        }
+        // Order of buckets is decided by their best addresses
+        buckets.sort_by_key(|bucket| rank(bucket[0]));
+
+        let mut picked = vec![];
+        for round in 0.. {
+            let before = picked.len();
+            for bucket in buckets.iter() {
+                if picked.len() == count {
+                    return picked;
+                }
+                if let Some(info) = bucket.get(round) {
+                    picked.push(info.address.clone());
+                }
+            }
+            if picked.len() == before {
+                break;
+            }
+        }
+        picked
+    }
+
+    /// Write the book in binary format
+    pub fn save<W: Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        w.write_all(&FILE_MAGIC)?;
+        let mut len = FILE_MAGIC.len();
+        len += FILE_VERSION.consensus_encode(&mut w)?;
+        len += VarInt(self.peers.len() as u64).consensus_encode(&mut w)?;
+        for info in self.peers.values() {
+            len += info.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+
+    /// Read the book written by `save`. The book has default capacity, addresses beyond it are
+    /// evicted like when they are added.
+    pub fn load<R: Read>(mut r: R) -> Result<Self, Error> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != FILE_MAGIC {
+            return Err(Error::ParseFailed("Not an address book file"));
+        }
+        let version: u8 = Decodable::consensus_decode(&mut r)?;
+        if version != 1 && version != FILE_VERSION {
+            return Err(Error::ParseFailed("Unsupported address book version"));
+        }
+        let count = VarInt::consensus_decode(&mut r)?.0;
+        let mut book = AddressBook::new();
+        // Entries are read one by one, so bogus count can't cause large allocation
+        for _ in 0..count {
+            let info = if version == 1 {
+                let address = Decodable::consensus_decode(&mut r)?;
+                PeerInfo::decode_fields(address, &mut r)?
+            } else {
+                PeerInfo::consensus_decode(&mut r)?
+            };
+            book.remove(&info.address);
+            book.insert(info);
+        }
+        Ok(book)
+    }
+
+    /// Write the book to a file replacing it
+    pub fn save_file<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.save(&mut w)?;
+        w.flush()
+    }
+
+    pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        AddressBook::load(BufReader::new(File::open(path)?))
+    }
+}
+
+/// Sort key of an address, smaller is better
+type Rank = (u32, Reverse<Option<Timestamp>>, Reverse<Timestamp>);
+
+/// Addresses ordered by rank from the best to the worst
+type Ranking = BTreeSet<(Rank, Address)>;
+
+fn rank(info: &PeerInfo) -> Rank {
+    (
+        info.failures,
+        Reverse(info.last_success),
+        Reverse(info.last_seen),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    fn ipv4(a: u8, b: u8, c: u8) -> Address {
+        Address::Ipv4(SocketAddrV4::new(Ipv4Addr::new(a, b, c, 1), 8667))
+    }
+
+    fn ipv6(segment: u16) -> Address {
+        let ip = Ipv6Addr::new(0x2001, segment, 0, 0, 0, 0, 0, 1);
+        Address::Ipv6(SocketAddrV6::new(ip, 8667, 0, 0))
+    }
+
+    fn onion(b: u8) -> Address {
+        Address::OnionV3(OnionV3Address::from_public_key([b; 32]), 8667)
+    }
+
+    #[test]
+    fn ingest_messages() {
+        let mut book = AddressBook::new();
+        let peers = Message::Peers(vec![ipv4(1, 2, 3), ipv4(1, 2, 3), ipv6(1)]);
+        assert_eq!(book.ingest(&peers, 100), 2);
+        let introduce = Message::PeerIntroduce(vec![ipv6(1), onion(b'a')]).tagged(5);
+        assert_eq!(book.ingest(&introduce, 200), 1);
+        assert_eq!(book.ingest(&Message::GetPeers, 300), 0);
+        let records = Message::PeerRecords(vec![PeerRecord {
+            address: ipv4(5, 6, 7),
+            last_seen: 50,
+            currencies: vec![Currency::Btc],
+            services: Services::FILTERS,
+        }]);
+        assert_eq!(book.ingest(&records, 300), 1);
+        assert_eq!(book.get(&ipv4(5, 6, 7)).unwrap().last_seen, 50);
+        book.remove(&ipv4(5, 6, 7));
+        assert_eq!(book.len(), 3);
+        assert_eq!(book.get(&ipv4(1, 2, 3)).unwrap().last_seen, 100);
+        assert_eq!(book.get(&ipv6(1)).unwrap().last_seen, 200);
+        // Stale announcement doesn't move last seen back
+        book.add(ipv6(1), 150);
+        assert_eq!(book.get(&ipv6(1)).unwrap().last_seen, 200);
+    }
+
+    #[test]
+    fn success_and_failures() {
+        let mut book = AddressBook::new();
+        book.add(ipv4(1, 2, 3), 100);
+        book.mark_failure(&ipv4(1, 2, 3));
+        book.mark_failure(&ipv4(1, 2, 3));
+        book.mark_failure(&ipv4(9, 9, 9));
+        assert_eq!(book.get(&ipv4(1, 2, 3)).unwrap().failures, 2);
+        assert_eq!(book.get(&ipv4(9, 9, 9)), None);
+        book.mark_success(&ipv4(1, 2, 3), 300);
+        assert_eq!(
+            book.get(&ipv4(1, 2, 3)),
+            Some(&PeerInfo {
+                address: ipv4(1, 2, 3),
+                last_seen: 300,
+                last_success: Some(300),
+                failures: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn diverse_candidates() {
+        let mut book = AddressBook::new();
+        for (i, addr) in [
+            ipv4(1, 2, 3),
+            ipv4(1, 2, 4),
+            ipv4(1, 2, 5),
+            ipv4(1, 3, 3),
+            ipv6(1),
+            ipv6(2),
+            onion(b'a'),
+            onion(b'b'),
+        ]
+        .iter()
+        .enumerate()
+        {
+            book.add(addr.clone(), 100 + i as u64);
+        }
+        book.mark_success(&ipv4(1, 2, 5), 500);
+        book.mark_failure(&onion(b'b'));
+
+        let picked = book.candidates(5);
+        assert_eq!(
+            picked,
+            vec![ipv4(1, 2, 5), onion(b'a'), ipv6(2), ipv6(1), ipv4(1, 3, 3)]
+        );
+        assert_eq!(book.candidates(100).len(), book.len());
+        // Two addresses of the same /16 come only after every bucket had its turn
+        let all = book.candidates(100);
+        assert_eq!(&all[5..], &[ipv4(1, 2, 4), onion(b'b'), ipv4(1, 2, 3)]);
+
+        let mut scores = Scoreboard::default();
+        scores.ban(ipv4(1, 2, 5), 1000);
+        scores.ban(onion(b'a'), 100);
+        assert_eq!(
+            book.candidates_unbanned(2, &scores, 500),
+            vec![onion(b'a'), ipv6(2)]
+        );
+    }
+
+    #[test]
+    fn capacity_evicts_worst() {
+        let mut book = AddressBook::new();
+        book.set_capacity(10, 4);
+        // Flood of a single /16 stays within its bucket, most recently seen are kept
+        for i in 0..100 {
+            book.add(ipv4(1, 2, i), 100 + i as u64);
+        }
+        assert_eq!(book.len(), 4);
+        assert!(book.get(&ipv4(1, 2, 99)).is_some());
+        assert!(book.get(&ipv4(1, 2, 95)).is_none());
+        // Stale address isn't better than any of the bucket
+        assert!(!book.add(ipv4(1, 2, 100), 50));
+
+        book.mark_success(&ipv6(1), 50);
+        for i in 0..100 {
+            let peers = Message::Peers(vec![ipv4(10 + i, 0, 0)]);
+            assert_eq!(book.ingest(&peers, 1000 + i as u64), 1);
+            assert!(book.len() <= 10);
+        }
+        assert_eq!(book.len(), 10);
+        // Address that was connected to outranks fresh announcements
+        assert!(book.get(&ipv6(1)).is_some());
+        assert!(book.get(&ipv4(1, 2, 99)).is_none());
+
+        book.set_capacity(5, 5);
+        assert_eq!(book.len(), 5);
+        assert_eq!(book.candidates(1), vec![ipv6(1)]);
+    }
+
+    #[test]
+    fn large_peers_message() {
+        let mut book = AddressBook::new();
+        for i in 0..DEFAULT_CAPACITY {
+            book.add(ipv4((i >> 8) as u8, 0, i as u8), 1000);
+        }
+        assert_eq!(book.len(), DEFAULT_CAPACITY);
+
+        // Frame of 10MB carries about as many addresses
+        let addr = |i: usize| ipv4(100 + (i >> 16) as u8, (i >> 8) as u8, i as u8);
+        let peers = Message::Peers((0..1_000_000).map(addr).collect());
+        assert_eq!(book.ingest(&peers, 2000), MAX_INGEST);
+        assert_eq!(book.len(), DEFAULT_CAPACITY);
+        assert!(book.get(&addr(MAX_INGEST - 1)).is_some());
+        assert!(book.get(&addr(MAX_INGEST)).is_none());
+        // Index agrees with the book after evictions
+        assert_eq!(book.ranking.len(), book.len());
+        let indexed: usize = book.buckets.values().map(|ranking| ranking.len()).sum();
+        assert_eq!(indexed, book.len());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut book = AddressBook::new();
+        book.ingest(
+            &Message::Peers(vec![ipv4(1, 2, 3), ipv6(1), onion(b'a')]),
+            1621437700,
+        );
+        book.mark_success(&ipv6(1), 1621437800);
+        book.mark_failure(&onion(b'a'));
+
+        // Book of the first format version
+        let mut bytes = b"EGAB\x01\x03".to_vec();
+        for info in book.iter() {
+            bytes.extend(serialize(&info.address));
+            bytes.extend(serialize(&info.last_seen));
+            bytes.extend(serialize(&info.last_success.unwrap_or(0)));
+            bytes.extend(serialize(&info.failures));
+        }
+        assert_eq!(AddressBook::load(&bytes[..]).unwrap(), book);
+
+        book.add(Address::I2p([7; 32], 0), 1621437900);
+        let mut bytes = vec![];
+        let len = book.save(&mut bytes).unwrap();
+        assert_eq!(len, bytes.len());
+        assert_eq!(&bytes[..6], b"EGAB\x02\x04");
+        assert_eq!(AddressBook::load(&bytes[..]).unwrap(), book);
+
+        assert!(AddressBook::load(&b"EGAX\x01\x00"[..]).is_err());
+        assert!(AddressBook::load(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,485 @@
+//! Field-by-field byte map of a message frame, used to inspect malformed frames.
+use crate::error::ProtocolError;
+use crate::message::*;
+use crate::util::peek_varint;
+use consensus_encode::util::hex::ToHex;
+use std::convert::TryInto;
+
+/// Byte range of a frame and the field it holds
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Field {
+    pub offset: usize,
+    pub len: usize,
+    pub name: String,
+    /// Decoded value of the field
+    pub value: String,
+}
+
+/// Map of a frame. Fields are listed until the first one that can't be read, in that case
+/// `error` tells why decoding fails.
+#[derive(Debug)]
+pub struct FrameMap {
+    pub fields: Vec<Field>,
+    pub error: Option<ProtocolError>,
+}
+
+/// Split frame into fields
+pub fn annotate(frame: &[u8]) -> FrameMap {
+    annotate_with(frame, &DecodeOptions::default())
+}
+
+/// Split frame into fields, compressed bodies are inflated with the codec and limits of the
+/// connection
+pub fn annotate_with(frame: &[u8], options: &DecodeOptions) -> FrameMap {
+    let mut w = Walker {
+        buf: frame,
+        pos: 0,
+        fields: vec![],
+        options,
+    };
+    let _ = w.frame();
+    FrameMap {
+        fields: w.fields,
+        error: Message::from_frame_with(frame, options).err(),
+    }
+}
+
+struct Walker<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    fields: Vec<Field>,
+    options: &'a DecodeOptions,
+}
+
+/// Field doesn't fit into the frame, annotation stops
+struct Stop;
+
+type Step<T> = Result<T, Stop>;
+
+impl<'a> Walker<'a> {
+    fn push(&mut self, len: usize, name: &str, value: String) {
+        self.fields.push(Field {
+            offset: self.pos,
+            len,
+            name: name.to_owned(),
+            value,
+        });
+        self.pos += len;
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn varint(&mut self, name: &str) -> Step<u64> {
+        let (v, len) = peek_varint(&self.buf[self.pos..]).ok_or(Stop)?;
+        self.push(len, name, v.to_string());
+        Ok(v)
+    }
+
+    fn bytes(&mut self, name: &str, len: usize) -> Step<&'a [u8]> {
+        if len > self.remaining() {
+            return Err(Stop);
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.push(len, name, bytes.to_hex());
+        Ok(bytes)
+    }
+
+    /// Fixed size value that is shown decoded
+    fn value<T>(&mut self, name: &str, len: usize, show: impl Fn(T) -> String) -> Step<T>
+    where
+        T: Decodable + Clone,
+    {
+        if len > self.remaining() {
+            return Err(Stop);
+        }
+        let v: T = deserialize(&self.buf[self.pos..self.pos + len]).map_err(|_| Stop)?;
+        self.push(len, name, show(v.clone()));
+        Ok(v)
+    }
+
+    fn currency(&mut self, name: &str) -> Step<Currency> {
+        let (i, len) = peek_varint(&self.buf[self.pos..]).ok_or(Stop)?;
+        let cur = Currency::from_index(i as u32);
+        self.push(len, name, format!("{} ({})", i, cur));
+        Ok(cur)
+    }
+
+    fn fiat(&mut self, name: &str) -> Step<()> {
+        let (i, len) = peek_varint(&self.buf[self.pos..]).ok_or(Stop)?;
+        self.push(len, name, format!("{} ({})", i, Fiat::from_index(i as u32)));
+        Ok(())
+    }
+
+    /// Length prefixed bytes
+    fn var_bytes(&mut self, name: &str) -> Step<&'a [u8]> {
+        let len = self.varint(&format!("{} length", name))?;
+        self.bytes(name, len.try_into().map_err(|_| Stop)?)
+    }
+
+    /// Length prefixed vector, `item` annotates element with given index
+    fn vec(&mut self, name: &str, mut item: impl FnMut(&mut Self, u64) -> Step<()>) -> Step<()> {
+        let count = self.varint(&format!("{} count", name))?;
+        for i in 0..count {
+            item(self, i)?;
+        }
+        Ok(())
+    }
+
+    /// The rest of payload is compressed with the codec of connection
+    fn compressed(&mut self, end: usize) -> Step<()> {
+        let body = &self.buf[self.pos..end];
+        let kind = self.options.codec;
+        let inflated = kind
+            .codec()
+            .map(|codec| codec.decompress(body, self.options.max_decompressed_size));
+        let value = match inflated {
+            Some(Ok(inflated)) => {
+                format!("{} bytes, inflates to {} bytes", body.len(), inflated.len())
+            }
+            Some(Err(e)) => format!("{} bytes, {}", body.len(), e),
+            None => format!("{} bytes, {} codec is not supported", body.len(), kind),
+        };
+        let name = match kind {
+            CodecKind::Identity => "uncompressed body".to_owned(),
+            kind => format!("{} body", kind),
+        };
+        self.push(body.len(), &name, value);
+        Ok(())
+    }
+
+    fn address(&mut self, name: &str) -> Step<()> {
+        let start = self.pos;
+        let ty = self.value::<u8>(&format!("{} type", name), 1, |t| {
+            match t {
+                0 => "0 (ipv4)",
+                1 => "1 (ipv6)",
+                2 => "2 (onion v3)",
+                _ => "unknown",
+            }
+            .to_owned()
+        })?;
+        let host_len = match ty {
+            0 => 4,
+            1 => 16,
+            2 => 56,
+            _ => return Err(Stop),
+        };
+        self.bytes(&format!("{} host", name), host_len)?;
+        self.value::<u16>(&format!("{} port", name), 2, |p| p.to_be().to_string())?;
+        if let Ok(addr) = deserialize::<Address>(&self.buf[start..self.pos]) {
+            self.fields.last_mut().unwrap().value += &format!(" ({})", addr);
+        }
+        Ok(())
+    }
+
+    fn address_v2(&mut self, name: &str) -> Step<()> {
+        let start = self.pos;
+        self.value::<u8>(&format!("{} network", name), 1, |n| match n {
+            0 => "0 (ipv4)".to_owned(),
+            1 => "1 (ipv6)".to_owned(),
+            2 => "2 (onion v3)".to_owned(),
+            3 => "3 (i2p)".to_owned(),
+            4 => "4 (cjdns)".to_owned(),
+            5 => "5 (hostname)".to_owned(),
+            n => format!("{} (unknown, skipped)", n),
+        })?;
+        self.var_bytes(&format!("{} host", name))?;
+        self.value::<u16>(&format!("{} port", name), 2, |p| p.to_be().to_string())?;
+        if let Ok(Some(addr)) = Address::decode_v2(&self.buf[start..self.pos]) {
+            self.fields.last_mut().unwrap().value += &format!(" ({})", addr);
+        }
+        Ok(())
+    }
+
+    fn frame(&mut self) -> Step<()> {
+        let (id, id_len) = peek_varint(&self.buf[self.pos..]).ok_or(Stop)?;
+        let id: u32 = match id.try_into() {
+            Ok(id) => id,
+            Err(_) => {
+                self.push(id_len, "id", format!("{} (out of range)", id));
+                return Err(Stop);
+            }
+        };
+        let name = Message::name_from_id(id).unwrap_or("unknown");
+        self.push(id_len, "id", format!("{} ({})", id, name));
+        if !Message::has_payload(id) {
+            return self.trailing(self.buf.len());
+        }
+        let len = self.varint("length")?;
+        let end = (self.pos as u64)
+            .saturating_add(len)
+            .min(self.buf.len() as u64) as usize;
+        self.payload(id, end)?;
+        self.trailing(end)
+    }
+
+    fn trailing(&mut self, end: usize) -> Step<()> {
+        if self.pos < end {
+            self.bytes("unparsed", end - self.pos)?;
+        }
+        if end < self.buf.len() {
+            self.bytes("trailing bytes", self.buf.len() - end)?;
+        }
+        Ok(())
+    }
+
+    fn payload(&mut self, id: u32, end: usize) -> Step<()> {
+        // Fields must not run past the declared payload
+        let buf = self.buf;
+        self.buf = &buf[..end];
+        let res = self.payload_fields(id, end);
+        self.buf = buf;
+        res
+    }
+
+    fn payload_fields(&mut self, id: u32, end: usize) -> Step<()> {
+        match id {
+            0 => {
+                self.value::<u32>("version", 4, |w| {
+                    let w = w.to_be();
+                    format!("{} codec {}", Version::unpack(w), CodecKind::from_bits(w))
+                })?;
+                self.value::<u64>("time", 8, |t| t.to_string())?;
+                self.bytes("nonce", 8)?;
+                self.vec("scan blocks", |w, i| {
+                    w.currency(&format!("scan block {} currency", i))?;
+                    w.value::<u32>(&format!("scan block {} version", i), 4, |v| {
+                        Version::unpack(v.to_be()).to_string()
+                    })?;
+                    w.varint(&format!("scan block {} scan height", i))?;
+                    w.varint(&format!("scan block {} height", i))?;
+                    Ok(())
+                })
+            }
+            2 => {
+                self.currency("currency")?;
+                self.varint("start")?;
+                self.varint("amount")?;
+                Ok(())
+            }
+            3 => {
+                self.currency("currency")?;
+                self.varint("amount")?;
+                self.compressed(end)
+            }
+            4 => {
+                self.currency("currency")?;
+                self.varint("height")?;
+                self.bytes("block id", 32)?;
+                self.var_bytes("filter")?;
+                Ok(())
+            }
+            6 | 9 => self.vec("addresses", |w, i| w.address(&format!("address {}", i))),
+            7 => self.vec("currencies", |w, i| {
+                w.currency(&format!("currency {}", i)).map(|_| ())
+            }),
+            8 => self.vec("fees", |w, i| {
+                let fields: &[&str] = match w.currency(&format!("fee {} currency", i))? {
+                    Currency::Btc | Currency::TBtc => &[
+                        "fast conservative",
+                        "fast economical",
+                        "moderate conservative",
+                        "moderate economical",
+                        "cheap conservative",
+                        "cheap economical",
+                    ],
+                    _ => &["fast", "moderate", "cheap"],
+                };
+                for f in fields {
+                    w.varint(&format!("fee {} {}", i, f))?;
+                }
+                Ok(())
+            }),
+            10 => {
+                self.varint("rejected id")?;
+                self.varint("code")?;
+                let msg = self.var_bytes("message")?;
+                self.fields.last_mut().unwrap().value = String::from_utf8_lossy(msg).into_owned();
+                Ok(())
+            }
+            11 | 12 => self.bytes("nonce", 8).map(|_| ()),
+            13 => self.vec("requests", |w, i| {
+                w.currency(&format!("request {} currency", i))?;
+                w.vec(&format!("request {} fiats", i), |w, j| {
+                    w.fiat(&format!("request {} fiat {}", i, j))
+                })
+            }),
+            14 => self.vec("rates", |w, i| {
+                w.currency(&format!("rates {} currency", i))?;
+                w.vec(&format!("rates {} fiats", i), |w, j| {
+                    w.fiat(&format!("rates {} fiat {}", i, j))?;
+                    w.value::<u64>(&format!("rates {} rate {}", i, j), 8, |r| {
+                        format!("{}.{:02}", r / 100, r % 100)
+                    })?;
+                    Ok(())
+                })
+            }),
+            17 => self.var_bytes("filter").map(|_| ()),
+            19 => self.vec("filters", |w, i| {
+                w.bytes(&format!("filter {} prefix", i), 2)?;
+                w.var_bytes(&format!("filter {}", i)).map(|_| ())
+            }),
+            20 => self.vec("prefixes", |w, i| {
+                w.bytes(&format!("prefix {}", i), 2).map(|_| ())
+            }),
+            21 => {
+                self.bytes("prefix", 2)?;
+                self.varint("amount")?;
+                self.compressed(end)
+            }
+            22 => {
+                self.varint("request id")?;
+                self.frame()
+            }
+            23 | 24 => self.vec("addresses", |w, i| w.address_v2(&format!("address {}", i))),
+            26 => self.vec("records", |w, i| {
+                w.address_v2(&format!("record {} address", i))?;
+                w.value::<u64>(&format!("record {} last seen", i), 8, |t| t.to_string())?;
+                w.vec(&format!("record {} currencies", i), |w, j| {
+                    w.currency(&format!("record {} currency {}", i, j))
+                        .map(|_| ())
+                })?;
+                w.value::<u64>(&format!("record {} services", i), 8, |s| {
+                    format!("{:#x}", s)
+                })?;
+                Ok(())
+            }),
+            25 => Ok(()),
+            _ => self.bytes("payload", end - self.pos).map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ProtocolErrorKind;
+    use consensus_encode::util::hex::FromHex;
+
+    fn names(map: &FrameMap) -> Vec<&str> {
+        map.fields.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    #[test]
+    fn annotate_filters_req() {
+        let msg = Message::GetFilters(FiltersReq {
+            currency: Currency::Ergo,
+            start: 445123,
+            amount: 2000,
+        });
+        let map = annotate(&serialize(&msg));
+        assert!(map.error.is_none());
+        assert_eq!(
+            names(&map),
+            vec!["id", "length", "currency", "start", "amount"]
+        );
+        assert_eq!(map.fields[0].value, "2 (req filters)");
+        assert_eq!(map.fields[2].value, "2 (Ergo)");
+        let last = map.fields.last().unwrap();
+        assert_eq!(last.offset, 8);
+        assert_eq!(last.len, 3);
+        assert_eq!(last.value, "2000");
+    }
+
+    #[test]
+    fn annotate_covers_frame() {
+        let msgs = vec![
+            Message::Peers(vec![
+                Address::Ipv4("127.0.0.1:8667".parse().unwrap()),
+                Address::Ipv6("[::1]:8667".parse().unwrap()),
+            ]),
+            Message::Filters(FiltersResp {
+                currency: Currency::Btc,
+                filters: vec![Filter {
+                    block_id: vec![1; 32],
+                    filter: vec![2; 10],
+                }],
+            }),
+            Message::Fee(vec![FeeResp::Other((
+                Currency::Dash,
+                FeeOther {
+                    fast: 4,
+                    moderate: 8,
+                    cheap: 15,
+                },
+            ))]),
+            Message::Reject(RejectMessage {
+                id: 2,
+                data: RejectData::InternalError,
+                message: "oops".to_owned(),
+            }),
+            Message::VersionAck,
+            Message::GetPeers.tagged(7),
+            Message::GetPeerRecords,
+            Message::PeersV2(vec![
+                Address::I2p([3; 32], 0),
+                Address::Hostname("example.com".to_owned(), 8667),
+            ]),
+        ];
+        for msg in msgs {
+            let bytes = serialize(&msg);
+            let map = annotate(&bytes);
+            assert!(map.error.is_none(), "{}", msg);
+            let mut pos = 0;
+            for f in map.fields.iter() {
+                assert_eq!(f.offset, pos, "{}", msg);
+                pos += f.len;
+            }
+            assert_eq!(pos, bytes.len(), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn annotate_codec_body() {
+        let resp = FiltersResp {
+            currency: Currency::Btc,
+            filters: vec![Filter {
+                block_id: vec![1; 32],
+                filter: vec![2; 10],
+            }],
+        };
+        let map = annotate(&serialize(&Message::Filters(resp.clone())));
+        assert_eq!(map.fields.last().unwrap().name, "gzip body");
+
+        let mut bytes = vec![];
+        Message::Filters(resp)
+            .encode_with(&mut bytes, CodecKind::Identity)
+            .unwrap();
+        let options = DecodeOptions {
+            codec: CodecKind::Identity,
+            ..Default::default()
+        };
+        let map = annotate_with(&bytes, &options);
+        assert!(map.error.is_none());
+        let body = map.fields.last().unwrap();
+        assert_eq!(body.name, "uncompressed body");
+        assert_eq!(body.value, "43 bytes, inflates to 43 bytes");
+    }
+
+    #[test]
+    fn annotate_malformed() {
+        // Peers with unknown address type
+        let bytes = Vec::from_hex("06080107000000000000").unwrap();
+        let map = annotate(&bytes);
+        assert_eq!(
+            names(&map),
+            vec!["id", "length", "addresses count", "address 0 type"]
+        );
+        assert_eq!(map.fields[3].value, "unknown");
+        let e = map.error.unwrap();
+        assert!(matches!(e.kind, ProtocolErrorKind::UnknownAddressType));
+
+        // Id beyond 32 bits, it would read as version ack if truncated
+        let bytes = Vec::from_hex("ff0100000001000000").unwrap();
+        let map = annotate(&bytes);
+        assert_eq!(names(&map), vec!["id"]);
+        assert_eq!(map.fields[0].value, "4294967297 (out of range)");
+        let e = map.error.unwrap();
+        assert!(matches!(
+            e.kind,
+            ProtocolErrorKind::IdOutOfRange(0x100000001)
+        ));
+    }
+}
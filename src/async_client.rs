@@ -0,0 +1,757 @@
+//! Async client of an indexer that allows many requests in flight over one connection.
+//!
+//! When both peers support `Version::REQUEST_IDS`, requests are sent as `Message::Tagged` and
+//! replies are matched by request id. Older peers get untagged requests, and replies are
+//! correlated with requests by their type:
+//!
+//! - Every request type has exactly one reply type: `GetFilters` is answered with `Filters`,
+//!   `GetPeers` with `Peers` or `PeersV2`, `GetPeerRecords` with `PeerRecords`, `GetFee` with
+//!   `Fee`, `GetRates` with `Rates`,
+//!   `GetFullFilter` with `FullFilter`, `GetMemFilters` with `MemFilters`, `GetMempool` with one
+//!   `MempoolChunk` per requested prefix and `Ping` with `Pong`.
+//! - Indexer answers requests of the same type in the order they were sent, so a reply goes to
+//!   the oldest outstanding request of the matching type. Requests of different types don't
+//!   wait for each other.
+//! - `Reject` refers to the type id of rejected request and fails the oldest outstanding request
+//!   of that type.
+//! - Request that timed out keeps its place in the queue until its reply arrives, the late reply
+//!   is dropped. Otherwise it would be handed to the next request of the same type. Tagged
+//!   requests that timed out are forgotten when the next request is sent instead, and their late
+//!   replies are dropped too.
+//! - Reply with malformed payload fails the request it answers with `ClientError::Protocol`, the
+//!   connection keeps serving other requests. Malformed frame header closes the connection.
+//! - Messages that answer no outstanding request, including `Filter` and `FullFilterInv` pushes,
+//!   go to the `Subscription`. Pings of the peer are answered by the client itself.
+use crate::client::ClientError;
+use crate::codec::MessageCodec;
+use crate::error::ProtocolError;
+use crate::handshake::{Agreement, Handshake, Role};
+use crate::message::*;
+use bytes::BytesMut;
+use futures::{SinkExt, Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// Timeout of requests unless it is changed with `set_timeout`
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Type id of the reply to request with given type id
+fn reply_id(request_id: u32) -> Option<u32> {
+    match request_id {
+        2 => Some(3),
+        5 => Some(6),
+        25 => Some(26),
+        7 => Some(8),
+        11 => Some(12),
+        13 => Some(14),
+        16 => Some(17),
+        18 => Some(19),
+        20 => Some(21),
+        _ => None,
+    }
+}
+
+type Reply = Result<Vec<Message>, ClientError>;
+
+/// Outstanding request that collects `expected` replies
+struct Waiter {
+    expected: usize,
+    replies: Vec<Message>,
+    reply: oneshot::Sender<Reply>,
+}
+
+/// Codec that yields payload errors as items, so that `Framed` doesn't stop at them. Frames are
+/// still in sync after such error, unlike after malformed header.
+struct ClientCodec(MessageCodec);
+
+impl Decoder for ClientCodec {
+    type Item = Result<Message, ProtocolError>;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, ProtocolError> {
+        match self.0.decode(src) {
+            Ok(msg) => Ok(msg.map(Ok)),
+            Err(e) if !e.is_header() => Ok(Some(Err(e))),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Encoder<Message> for ClientCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        self.0.encode(msg, dst)
+    }
+}
+
+enum Command {
+    Request {
+        msg: Message,
+        expected: usize,
+        reply: oneshot::Sender<Reply>,
+    },
+    Send(Message),
+}
+
+/// Handle to connection, cheap to clone and share between tasks
+#[derive(Clone, Debug)]
+pub struct Client {
+    commands: mpsc::UnboundedSender<Command>,
+    agreement: Agreement,
+    timeout: Duration,
+}
+
+/// Stream of messages that answer no request, e.g. new filters pushed by the indexer. Ends when
+/// connection is closed.
+#[derive(Debug)]
+pub struct Subscription {
+    pushes: mpsc::UnboundedReceiver<Message>,
+}
+
+impl Subscription {
+    /// Wait for next pushed message
+    pub async fn next(&mut self) -> Option<Message> {
+        self.pushes.recv().await
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.pushes.poll_recv(cx)
+    }
+}
+
+impl Client {
+    /// Perform handshake over the stream and spawn task that serves the connection. Must be
+    /// called within tokio runtime.
+    pub async fn connect<T>(
+        io: T,
+        version: VersionMessage,
+    ) -> Result<(Client, Subscription), ClientError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut handshake = Handshake::new(Role::Client, version);
+        let mut framed = Framed::new(io, ClientCodec(MessageCodec::new()));
+        for msg in handshake.start() {
+            framed.send(msg).await?;
+        }
+        while !handshake.is_done() {
+            let msg = framed.next().await.ok_or(ClientError::Closed)???;
+            match handshake.handle(msg) {
+                Ok(replies) => {
+                    for msg in replies {
+                        framed.send(msg).await?;
+                    }
+                }
+                Err(e) => {
+                    if let Some(reject) = e.reject() {
+                        // Peer is not usable anyway, so failed reject is not reported
+                        let _ = framed.send(Message::Reject(reject)).await;
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        let agreement = handshake.finish().expect("handshake is done");
+        framed.codec_mut().0.set_payload_codec(agreement.codec);
+
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (pushes_tx, pushes) = mpsc::unbounded_channel();
+        tokio::spawn(serve(framed, commands_rx, pushes_tx, agreement.request_ids));
+        let client = Client {
+            commands,
+            agreement,
+            timeout: DEFAULT_TIMEOUT,
+        };
+        Ok((client, Subscription { pushes }))
+    }
+
+    /// Version message that the peer sent during handshake
+    pub fn peer_version(&self) -> &VersionMessage {
+        &self.agreement.peer
+    }
+
+    /// Everything that was agreed during handshake, including scan blocks and clock skew
+    pub fn agreement(&self) -> &Agreement {
+        &self.agreement
+    }
+
+    /// Codec of compressed payloads agreed with the peer
+    pub fn codec(&self) -> CodecKind {
+        self.agreement.codec
+    }
+
+    /// Change timeout of requests made with this handle
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Send message that expects no reply
+    pub fn send(&self, msg: Message) -> Result<(), ClientError> {
+        self.commands
+            .send(Command::Send(msg))
+            .map_err(|_| ClientError::Closed)
+    }
+
+    /// Send request and wait for the reply to it with given timeout. Fails with `Unexpected`
+    /// when the message is not a request.
+    pub async fn request(&self, msg: Message, timeout: Duration) -> Result<Message, ClientError> {
+        let mut replies = self.call(msg, 1, timeout).await?;
+        Ok(replies.remove(0))
+    }
+
+    async fn call(
+        &self,
+        msg: Message,
+        expected: usize,
+        timeout: Duration,
+    ) -> Result<Vec<Message>, ClientError> {
+        if reply_id(msg.id()).is_none() {
+            return Err(ClientError::Unexpected(msg));
+        }
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(Command::Request {
+                msg,
+                expected,
+                reply,
+            })
+            .map_err(|_| ClientError::Closed)?;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) => Err(ClientError::Closed),
+            Err(_) => Err(ClientError::Timeout),
+        }
+    }
+
+    /// Request `amount` filters of blocks starting from `start` height
+    pub async fn get_filters(
+        &self,
+        currency: Currency,
+        start: u64,
+        amount: u32,
+    ) -> Result<FiltersResp, ClientError> {
+        let req = FiltersReq {
+            currency,
+            start,
+            amount,
+        };
+        match self.request(Message::GetFilters(req), self.timeout).await? {
+            Message::Filters(resp) => Ok(resp),
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Request current fees of given currencies
+    pub async fn get_fee(&self, currencies: &[Currency]) -> Result<Vec<FeeResp>, ClientError> {
+        let req = Message::GetFee(currencies.to_vec());
+        match self.request(req, self.timeout).await? {
+            Message::Fee(resp) => Ok(resp),
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Request exchange rates of currencies to fiats
+    pub async fn get_rates(&self, reqs: &[RateReq]) -> Result<Vec<RateResp>, ClientError> {
+        let req = Message::GetRates(reqs.to_vec());
+        match self.request(req, self.timeout).await? {
+            Message::Rates(resp) => Ok(resp),
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Request addresses of other indexers known to the peer
+    pub async fn get_peers(&self) -> Result<Vec<Address>, ClientError> {
+        match self.request(Message::GetPeers, self.timeout).await? {
+            Message::Peers(resp) | Message::PeersV2(resp) => Ok(resp),
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Request known indexers with their last seen time, currencies and services. Peers older
+    /// than `Version::PEER_RECORDS` are asked for plain addresses instead, their records have
+    /// unknown time and capabilities.
+    pub async fn get_peer_records(&self) -> Result<Vec<PeerRecord>, ClientError> {
+        if !self.agreement.peer_records {
+            let addrs = self.get_peers().await?;
+            return Ok(addrs.into_iter().map(PeerRecord::from).collect());
+        }
+        match self.request(Message::GetPeerRecords, self.timeout).await? {
+            Message::PeerRecords(resp) => Ok(resp),
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Request filters of mempool transactions grouped by prefixes
+    pub async fn get_mem_filters(&self) -> Result<Vec<FilterPrefixPair>, ClientError> {
+        match self.request(Message::GetMemFilters, self.timeout).await? {
+            Message::MemFilters(resp) => Ok(resp),
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Request mempool transactions with given prefixes, one chunk per prefix
+    pub async fn get_mempool(
+        &self,
+        prefixes: &[TxPrefix],
+    ) -> Result<Vec<MempoolChunkResp>, ClientError> {
+        if prefixes.is_empty() {
+            return Ok(vec![]);
+        }
+        let req = Message::GetMempool(prefixes.to_vec());
+        self.call(req, prefixes.len(), self.timeout)
+            .await?
+            .into_iter()
+            .map(|msg| match msg {
+                Message::MempoolChunk(resp) => Ok(resp),
+                msg => Err(ClientError::Unexpected(msg)),
+            })
+            .collect()
+    }
+}
+
+/// Outstanding requests of the connection
+struct Router {
+    /// Untagged requests by type id of expected reply
+    queues: HashMap<u32, VecDeque<Waiter>>,
+    /// Tagged requests by request id, `None` when the peer doesn't support request ids
+    tagged: Option<HashMap<u64, Waiter>>,
+    next_id: u64,
+}
+
+impl Router {
+    fn new(request_ids: bool) -> Self {
+        Router {
+            queues: HashMap::new(),
+            tagged: if request_ids {
+                Some(HashMap::new())
+            } else {
+                None
+            },
+            next_id: 0,
+        }
+    }
+
+    /// Remember request and get message that should be sent for it
+    fn register(&mut self, msg: Message, waiter: Waiter) -> Message {
+        match self.tagged.as_mut() {
+            Some(tagged) => {
+                // Requests that timed out don't wait for their replies anymore
+                tagged.retain(|_, w| !w.reply.is_closed());
+                let request_id = self.next_id;
+                self.next_id = self.next_id.wrapping_add(1);
+                tagged.insert(request_id, waiter);
+                msg.tagged(request_id)
+            }
+            None => {
+                let id = reply_id(msg.id()).expect("checked by client");
+                self.queues.entry(id).or_default().push_back(waiter);
+                msg
+            }
+        }
+    }
+
+    /// Hand message to the request it answers, the message is returned if there is none
+    fn route(&mut self, msg: Message) -> Option<Message> {
+        match msg {
+            Message::Tagged {
+                request_id,
+                message,
+            } => {
+                let tagged = self.tagged.as_mut()?;
+                match tagged.remove(&request_id) {
+                    Some(w) => {
+                        if let Some(w) = deliver(w, *message) {
+                            tagged.insert(request_id, w);
+                        }
+                        None
+                    }
+                    // Late reply to forgotten request
+                    None if request_id < self.next_id => None,
+                    None => Some(message.tagged(request_id)),
+                }
+            }
+            msg => {
+                let id = match &msg {
+                    Message::Reject(reject) => reply_id(reject.id),
+                    // Peers of either encoding answer `GetPeers`
+                    Message::PeersV2(_) => reply_id(5),
+                    msg => Some(msg.id()),
+                };
+                let queue = id.and_then(|id| self.queues.get_mut(&id));
+                match queue.and_then(|queue| queue.pop_front().map(|w| (queue, w))) {
+                    Some((queue, w)) => {
+                        if let Some(w) = deliver(w, msg) {
+                            queue.push_front(w);
+                        }
+                        None
+                    }
+                    None => Some(msg),
+                }
+            }
+        }
+    }
+
+    /// Fail the request whose reply couldn't be decoded, the error is dropped if there is none
+    fn fail(&mut self, e: ProtocolError) {
+        let waiter = match (e.request_id, self.tagged.as_mut()) {
+            (Some(request_id), Some(tagged)) => tagged.remove(&request_id),
+            (Some(_), None) => None,
+            (None, _) => {
+                let id = match e.message_id {
+                    // Peers of either encoding answer `GetPeers`
+                    Some(23) => reply_id(5),
+                    id => id,
+                };
+                id.and_then(|id| self.queues.get_mut(&id))
+                    .and_then(|queue| queue.pop_front())
+            }
+        };
+        if let Some(w) = waiter {
+            let _ = w.reply.send(Err(ClientError::Protocol(e)));
+        }
+    }
+}
+
+/// Add reply to the request, the request is returned if it waits for more replies
+fn deliver(mut w: Waiter, msg: Message) -> Option<Waiter> {
+    match msg {
+        Message::Reject(reject) => {
+            let _ = w.reply.send(Err(ClientError::Rejected(reject)));
+            None
+        }
+        msg => {
+            w.replies.push(msg);
+            if w.replies.len() < w.expected {
+                return Some(w);
+            }
+            let _ = w.reply.send(Ok(w.replies));
+            None
+        }
+    }
+}
+
+/// Serve connection until it is closed or all client handles are dropped
+async fn serve<T>(
+    mut framed: Framed<T, ClientCodec>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    pushes: mpsc::UnboundedSender<Message>,
+    request_ids: bool,
+) where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut router = Router::new(request_ids);
+    loop {
+        tokio::select! {
+            cmd = commands.recv() => match cmd {
+                Some(Command::Request { msg, expected, reply }) => {
+                    let waiter = Waiter {
+                        expected,
+                        replies: vec![],
+                        reply,
+                    };
+                    if framed.send(router.register(msg, waiter)).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Command::Send(msg)) => {
+                    if framed.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            msg = framed.next() => match msg {
+                Some(Ok(Ok(Message::Ping(nonce)))) => {
+                    if framed.send(Message::Pong(nonce)).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Ok(msg))) => {
+                    if let Some(msg) = router.route(msg) {
+                        let _ = pushes.send(msg);
+                    }
+                }
+                Some(Ok(Err(e))) => router.fail(e),
+                Some(Err(_)) | None => break,
+            },
+        }
+    }
+    // Dropped reply senders fail outstanding requests with `Closed`
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    type Peer = Framed<DuplexStream, MessageCodec>;
+
+    /// Version of peer that doesn't support request ids
+    fn version() -> VersionMessage {
+        VersionMessage {
+            version: Version {
+                major: 2,
+                minor: 0,
+                patch: 0,
+            },
+            codec: CodecKind::Gzip,
+            time: 1621437700,
+            nonce: [1, 2, 3, 4, 5, 6, 7, 8],
+            scan_blocks: vec![],
+        }
+    }
+
+    /// Connect client to in-memory peer that completes handshake
+    async fn connect() -> (Client, Subscription, Peer) {
+        connect_to(version()).await
+    }
+
+    async fn connect_to(version: VersionMessage) -> (Client, Subscription, Peer) {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let mut peer = Framed::new(theirs, MessageCodec::new());
+        let handshake = tokio::spawn(async move {
+            assert!(matches!(peer.next().await, Some(Ok(Message::Version(_)))));
+            peer.send(Message::Version(version)).await.unwrap();
+            peer.send(Message::VersionAck).await.unwrap();
+            assert_eq!(peer.next().await.unwrap().unwrap(), Message::VersionAck);
+            peer
+        });
+        let (client, subscription) = Client::connect(ours, VersionMessage::new([8; 8], vec![]))
+            .await
+            .unwrap();
+        (client, subscription, handshake.await.unwrap())
+    }
+
+    fn filters(currency: Currency) -> FiltersResp {
+        FiltersResp {
+            currency,
+            filters: vec![Filter {
+                block_id: vec![1; 32],
+                filter: vec![2; 10],
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn correlation() {
+        let (client, mut subscription, mut peer) = connect().await;
+        assert_eq!(client.peer_version(), &version());
+
+        let first = tokio::spawn({
+            let client = client.clone();
+            async move { client.get_filters(Currency::Btc, 0, 1).await }
+        });
+        assert!(matches!(
+            peer.next().await,
+            Some(Ok(Message::GetFilters(_)))
+        ));
+        let second = tokio::spawn({
+            let client = client.clone();
+            async move { client.get_filters(Currency::Dash, 0, 1).await }
+        });
+        assert!(matches!(
+            peer.next().await,
+            Some(Ok(Message::GetFilters(_)))
+        ));
+        let fee = tokio::spawn({
+            let client = client.clone();
+            async move { client.get_fee(&[Currency::Btc]).await }
+        });
+        assert!(matches!(peer.next().await, Some(Ok(Message::GetFee(_)))));
+
+        // Replies of different types may come in any order, the same types come in FIFO order
+        let event = FilterEvent {
+            currency: Currency::Btc,
+            height: 10,
+            block_id: vec![3; 32],
+            filter: vec![4; 4],
+        };
+        peer.send(Message::Filter(event.clone())).await.unwrap();
+        peer.send(Message::Ping([7; 8])).await.unwrap();
+        peer.send(Message::Reject(RejectMessage {
+            id: 7,
+            data: RejectData::InternalError,
+            message: "no fees".to_owned(),
+        }))
+        .await
+        .unwrap();
+        peer.send(Message::Filters(filters(Currency::Btc)))
+            .await
+            .unwrap();
+        peer.send(Message::Filters(filters(Currency::Dash)))
+            .await
+            .unwrap();
+
+        assert_eq!(first.await.unwrap().unwrap(), filters(Currency::Btc));
+        assert_eq!(second.await.unwrap().unwrap(), filters(Currency::Dash));
+        assert!(matches!(
+            fee.await.unwrap(),
+            Err(ClientError::Rejected(RejectMessage { id: 7, .. }))
+        ));
+        assert_eq!(peer.next().await.unwrap().unwrap(), Message::Pong([7; 8]));
+        assert_eq!(subscription.next().await, Some(Message::Filter(event)));
+
+        drop(peer);
+        assert_eq!(subscription.next().await, None);
+        assert!(matches!(client.get_peers().await, Err(ClientError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn late_reply() {
+        let (mut client, _subscription, mut peer) = connect().await;
+        client.set_timeout(Duration::from_millis(50));
+        assert!(matches!(
+            client.get_peers().await,
+            Err(ClientError::Timeout)
+        ));
+        assert_eq!(peer.next().await.unwrap().unwrap(), Message::GetPeers);
+
+        let addr = Address::Ipv4("127.0.0.1:8667".parse().unwrap());
+        let next = tokio::spawn({
+            let client = client.clone();
+            async move { client.get_peers().await }
+        });
+        assert_eq!(peer.next().await.unwrap().unwrap(), Message::GetPeers);
+        // Reply to the timed out request is skipped
+        peer.send(Message::Peers(vec![])).await.unwrap();
+        peer.send(Message::Peers(vec![addr.clone()])).await.unwrap();
+        assert_eq!(next.await.unwrap().unwrap(), vec![addr]);
+    }
+
+    #[tokio::test]
+    async fn mempool_chunks() {
+        let (client, _subscription, mut peer) = connect().await;
+        let prefixes = vec![TxPrefix([1, 1]), TxPrefix([2, 2])];
+        let chunks: Vec<_> = prefixes
+            .iter()
+            .map(|p| MempoolChunkResp {
+                prefix: p.clone(),
+                txs: vec![p.0.to_vec()],
+            })
+            .collect();
+        let req = tokio::spawn({
+            let client = client.clone();
+            let prefixes = prefixes.clone();
+            async move { client.get_mempool(&prefixes).await }
+        });
+        assert_eq!(
+            peer.next().await.unwrap().unwrap(),
+            Message::GetMempool(prefixes)
+        );
+        for chunk in chunks.iter() {
+            peer.send(Message::MempoolChunk(chunk.clone()))
+                .await
+                .unwrap();
+        }
+        assert_eq!(req.await.unwrap().unwrap(), chunks);
+        assert!(matches!(
+            client.request(Message::VersionAck, DEFAULT_TIMEOUT).await,
+            Err(ClientError::Unexpected(Message::VersionAck))
+        ));
+    }
+
+    #[tokio::test]
+    async fn request_ids() {
+        let mut version = version();
+        version.version = Version::current();
+        let (client, _subscription, mut peer) = connect_to(version).await;
+
+        let mut requests = vec![];
+        for currency in [Currency::Btc, Currency::Dash].iter().cloned() {
+            let client = client.clone();
+            requests.push(tokio::spawn(async move {
+                client.get_filters(currency, 0, 1).await
+            }));
+            let (id, msg) = peer.next().await.unwrap().unwrap().untagged();
+            assert!(matches!(msg, Message::GetFilters(_)));
+            assert_eq!(id, Some(requests.len() as u64 - 1));
+        }
+
+        // Replies come in reverse order, request ids tell where they belong
+        let reject = RejectMessage {
+            id: 2,
+            data: RejectData::InternalError,
+            message: "unknown currency".to_owned(),
+        };
+        peer.send(Message::Reject(reject.clone()).tagged(1))
+            .await
+            .unwrap();
+        peer.send(Message::Filters(filters(Currency::Btc)).tagged(0))
+            .await
+            .unwrap();
+        let second = requests.pop().unwrap().await.unwrap();
+        assert!(matches!(second, Err(ClientError::Rejected(msg)) if msg == reject));
+        let first = requests.pop().unwrap().await.unwrap();
+        assert_eq!(first.unwrap(), filters(Currency::Btc));
+    }
+
+    #[tokio::test]
+    async fn malformed_reply() {
+        let (client, _subscription, mut peer) = connect().await;
+        let req = tokio::spawn({
+            let client = client.clone();
+            async move { client.get_peers().await }
+        });
+        assert_eq!(peer.next().await.unwrap().unwrap(), Message::GetPeers);
+        // Peers with one address that is missing
+        peer.get_mut().write_all(&[6, 1, 1]).await.unwrap();
+        assert!(matches!(
+            req.await.unwrap(),
+            Err(ClientError::Protocol(ProtocolError {
+                message_id: Some(6),
+                ..
+            }))
+        ));
+
+        // Connection is still usable
+        let addr = Address::Ipv4("127.0.0.1:8667".parse().unwrap());
+        let req = tokio::spawn({
+            let client = client.clone();
+            async move { client.get_peers().await }
+        });
+        assert_eq!(peer.next().await.unwrap().unwrap(), Message::GetPeers);
+        peer.send(Message::Peers(vec![addr.clone()])).await.unwrap();
+        assert_eq!(req.await.unwrap().unwrap(), vec![addr]);
+    }
+
+    #[tokio::test]
+    async fn forgotten_requests() {
+        let mut router = Router::new(true);
+        let waiter = || {
+            let (reply, rx) = oneshot::channel();
+            let waiter = Waiter {
+                expected: 1,
+                replies: vec![],
+                reply,
+            };
+            (waiter, rx)
+        };
+        for _ in 0..100 {
+            let (w, rx) = waiter();
+            router.register(Message::GetPeers, w);
+            // Request times out
+            drop(rx);
+        }
+        let (w, rx) = waiter();
+        let msg = router.register(Message::GetPeers, w);
+        assert_eq!(msg, Message::GetPeers.tagged(100));
+        assert_eq!(router.tagged.as_ref().unwrap().len(), 1);
+
+        // Late reply is dropped, the reply to the live request is delivered
+        assert_eq!(router.route(Message::Peers(vec![]).tagged(5)), None);
+        assert_eq!(router.route(Message::Peers(vec![]).tagged(100)), None);
+        assert_eq!(rx.await.unwrap().unwrap(), vec![Message::Peers(vec![])]);
+        let push = Message::Peers(vec![]).tagged(200);
+        assert_eq!(router.route(push.clone()), Some(push));
+    }
+}
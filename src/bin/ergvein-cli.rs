@@ -0,0 +1,170 @@
+extern crate ergvein_protocol;
+extern crate serde_json;
+
+use consensus_encode::util::hex::{FromHex, ToHex};
+use ergvein_protocol::annotate::annotate;
+use ergvein_protocol::message::*;
+use std::fmt::Write;
+use std::{env, process};
+
+/// Bytes of a field that are printed before they are cut with ellipsis
+const MAX_SHOWN_BYTES: usize = 16;
+
+fn usage() -> ! {
+    eprintln!("Usage: ergvein-cli <command> <argument>");
+    eprintln!();
+    eprintln!("Commands:");
+    eprintln!("    decode <hex>     print message encoded in the frame");
+    eprintln!("    encode <json>    print frame of the message in hex");
+    eprintln!("    annotate <hex>   print byte map of the frame field by field");
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        usage();
+    }
+    let mut out = String::new();
+    let res = match args[1].as_str() {
+        "decode" => decode(&args[2], &mut out),
+        "encode" => encode(&args[2], &mut out),
+        "annotate" => annotate_frame(&args[2], &mut out),
+        _ => usage(),
+    };
+    print!("{}", out);
+    if let Err(error) = res {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    Vec::from_hex(s.trim()).map_err(|error| format!("Error parsing hex: {:?}", error))
+}
+
+fn decode(hex: &str, out: &mut String) -> Result<(), String> {
+    let msg = Message::from_frame(&parse_hex(hex)?)
+        .map_err(|error| format!("Error decoding frame: {}", error))?;
+    writeln!(out, "{}", msg).unwrap();
+    Ok(())
+}
+
+fn encode(json: &str, out: &mut String) -> Result<(), String> {
+    let msg: Message =
+        serde_json::from_str(json).map_err(|error| format!("Error parsing message: {}", error))?;
+    let bytes = ergvein_protocol::util::try_serialize(&msg)
+        .map_err(|error| format!("Error encoding message: {}", error))?;
+    writeln!(out, "{}", bytes.to_hex()).unwrap();
+    Ok(())
+}
+
+fn annotate_frame(hex: &str, out: &mut String) -> Result<(), String> {
+    let bytes = parse_hex(hex)?;
+    let map = annotate(&bytes);
+    let mut end = 0;
+    for field in map.fields.iter() {
+        let shown = &bytes[field.offset..field.offset + field.len.min(MAX_SHOWN_BYTES)];
+        let ellipsis = if field.len > MAX_SHOWN_BYTES {
+            ".."
+        } else {
+            ""
+        };
+        writeln!(
+            out,
+            "{:>6} {:>5}  {:<34} {:<28} {}",
+            field.offset,
+            field.len,
+            format!("{}{}", shown.to_hex(), ellipsis),
+            field.name,
+            field.value
+        )
+        .unwrap();
+        end = field.offset + field.len;
+    }
+    if end < bytes.len() {
+        writeln!(
+            out,
+            "{:>6} {:>5}  {:<34} not annotated",
+            end,
+            bytes.len() - end,
+            ""
+        )
+        .unwrap();
+    }
+    match map.error {
+        Some(error) => Err(format!("error: {}", error)),
+        None => {
+            writeln!(out, "ok").unwrap();
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(
+        command: fn(&str, &mut String) -> Result<(), String>,
+        arg: &str,
+    ) -> Result<String, String> {
+        let mut out = String::new();
+        command(arg, &mut out).map(|_| out)
+    }
+
+    fn samples() -> Vec<Message> {
+        vec![
+            Message::VersionAck,
+            Message::GetFilters(FiltersReq {
+                currency: Currency::Btc,
+                start: 630000,
+                amount: 100,
+            }),
+            Message::Peers(vec![Address::Ipv4("127.0.0.1:8667".parse().unwrap())]),
+            Message::Ping([1, 2, 3, 4, 5, 6, 7, 8]),
+        ]
+    }
+
+    #[test]
+    fn decode_roundtrip() {
+        for msg in samples() {
+            let out = run(decode, &serialize_hex(&msg)).unwrap();
+            assert_eq!(out, format!("{}\n", msg));
+        }
+        assert!(run(decode, "0b03cf7806").is_err());
+        assert!(run(decode, "xyz").is_err());
+    }
+
+    #[test]
+    fn encode_roundtrip() {
+        for msg in samples() {
+            let json = serde_json::to_string(&msg).unwrap();
+            let hex = run(encode, &json).unwrap();
+            assert_eq!(hex.trim(), serialize_hex(&msg));
+            assert_eq!(
+                Message::from_frame(&Vec::from_hex(hex.trim()).unwrap()).unwrap(),
+                msg
+            );
+        }
+        assert!(run(encode, "{}").is_err());
+    }
+
+    #[test]
+    fn annotate_roundtrip() {
+        for msg in samples() {
+            let hex = serialize_hex(&msg);
+            let out = run(annotate_frame, &hex).unwrap();
+            assert!(out.ends_with("ok\n"), "{}", out);
+            // Shown bytes of fields put together give the frame back
+            let shown: String = out
+                .lines()
+                .filter_map(|line| line.split_whitespace().nth(2))
+                .filter(|bytes| Vec::<u8>::from_hex(bytes).is_ok())
+                .collect();
+            assert_eq!(shown, hex, "{}", out);
+        }
+        let e = run(annotate_frame, "06080107000000000000").unwrap_err();
+        assert!(e.starts_with("error: "), "{}", e);
+    }
+}
@@ -0,0 +1,530 @@
+//! Blocking client of an indexer that works over any `Read + Write` stream. The connection
+//! performs handshake on creation and offers typed requests afterwards.
+use crate::error::ProtocolError;
+use crate::handshake::{Agreement, Handshake, HandshakeError, Role};
+use crate::message::*;
+use crate::scoring::Scoreboard;
+use crate::transport::Transport;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+/// Failure of a request or handshake
+#[derive(Debug)]
+pub enum ClientError {
+    /// Stream failed while sending a message
+    Io(io::Error),
+    /// Received frame can't be decoded or stream failed while reading it
+    Protocol(ProtocolError),
+    /// Peer speaks major version that we don't support
+    IncompatibleVersion(Version),
+    /// Peer rejected our message
+    Rejected(RejectMessage),
+    /// Peer replied with message that doesn't answer the request
+    Unexpected(Message),
+    /// Peer broke handshake rules, e.g. connection to self
+    Handshake(HandshakeError),
+    /// Peer is banned until given time for misbehaviour
+    Banned(u64),
+    /// Reply didn't arrive in time
+    Timeout,
+    /// Connection is closed before reply arrived
+    Closed,
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "io error: {}", e),
+            ClientError::Protocol(e) => e.fmt(f),
+            ClientError::IncompatibleVersion(v) => {
+                write!(
+                    f,
+                    "peer version {} is not compatible with {}",
+                    v,
+                    Version::current()
+                )
+            }
+            ClientError::Rejected(msg) => write!(
+                f,
+                "peer rejected {}: {}",
+                Message::name_from_id(msg.id).unwrap_or("message"),
+                msg.message
+            ),
+            ClientError::Unexpected(msg) => write!(f, "unexpected message: {}", msg),
+            ClientError::Handshake(e) => e.fmt(f),
+            ClientError::Banned(until) => write!(f, "peer is banned until {}", until),
+            ClientError::Timeout => write!(f, "request timed out"),
+            ClientError::Closed => write!(f, "connection is closed"),
+        }
+    }
+}
+
+impl error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            ClientError::Protocol(e) => Some(e),
+            ClientError::Handshake(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<ProtocolError> for ClientError {
+    fn from(e: ProtocolError) -> Self {
+        ClientError::Protocol(e)
+    }
+}
+
+impl From<HandshakeError> for ClientError {
+    fn from(e: HandshakeError) -> Self {
+        match e {
+            HandshakeError::IncompatibleVersion(v) => ClientError::IncompatibleVersion(v),
+            HandshakeError::Rejected(msg) => ClientError::Rejected(msg),
+            HandshakeError::Unexpected(msg) => ClientError::Unexpected(msg),
+            e => ClientError::Handshake(e),
+        }
+    }
+}
+
+/// Connect to the indexer with the transport and perform handshake, unless the indexer is
+/// banned. Handshake failures that are fault of the indexer are penalized in the scoreboard.
+/// Use `Transport::connect` and `Connection::handshake` to isolate the connection with
+/// credentials.
+pub fn connect(
+    transport: &Transport,
+    address: &Address,
+    version: VersionMessage,
+    scores: &mut Scoreboard,
+    now: u64,
+) -> Result<Connection<TcpStream>, ClientError> {
+    if let Some(until) = scores.banned_until(address, now) {
+        return Err(ClientError::Banned(until));
+    }
+    let stream = transport.connect(address, None)?;
+    let res = Connection::handshake(stream, version);
+    if let Err(e) = &res {
+        scores.penalize_error(address, e, now);
+    }
+    res
+}
+
+/// Connection to an indexer after successful handshake.
+///
+/// Requests are answered in order, so each typed call waits for the next reply. Pings of the
+/// peer are answered while waiting, and unsolicited messages like `Filter` or `PeerIntroduce`
+/// are queued for `receive`.
+#[derive(Debug)]
+pub struct Connection<S> {
+    stream: S,
+    agreement: Agreement,
+    options: DecodeOptions,
+    pending: VecDeque<Message>,
+}
+
+impl<S: Read + Write> Connection<S> {
+    /// Send our version message and wait until the peer sends its own and acknowledges ours
+    pub fn handshake(stream: S, version: VersionMessage) -> Result<Self, ClientError> {
+        let mut handshake = Handshake::new(Role::Client, version);
+        let mut stream = stream;
+        let options = DecodeOptions::default();
+        for msg in handshake.start() {
+            send_to(&mut stream, &msg, options.codec)?;
+        }
+        while !handshake.is_done() {
+            let msg = Message::read_from_with(&mut stream, &options)?;
+            match handshake.handle(msg) {
+                Ok(replies) => {
+                    for msg in replies {
+                        send_to(&mut stream, &msg, options.codec)?;
+                    }
+                }
+                Err(e) => {
+                    if let Some(reject) = e.reject() {
+                        // Peer is not usable anyway, so failed reject is not reported
+                        let _ = send_to(&mut stream, &Message::Reject(reject), options.codec);
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        let agreement = handshake.finish().expect("handshake is done");
+        Ok(Connection {
+            stream,
+            options: DecodeOptions {
+                codec: agreement.codec,
+                ..options
+            },
+            agreement,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Version message that the peer sent during handshake
+    pub fn peer_version(&self) -> &VersionMessage {
+        &self.agreement.peer
+    }
+
+    /// Indexer progress of currencies that both sides are interested in
+    pub fn scan_blocks(&self) -> &[ScanBlock] {
+        &self.agreement.scan_blocks
+    }
+
+    /// Everything that was agreed during handshake, including clock skew of the peer
+    pub fn agreement(&self) -> &Agreement {
+        &self.agreement
+    }
+
+    /// Codec of compressed payloads agreed with the peer
+    pub fn codec(&self) -> CodecKind {
+        self.options.codec
+    }
+
+    /// Send any message to the peer
+    pub fn send(&mut self, msg: &Message) -> Result<(), ClientError> {
+        Ok(send_to(&mut self.stream, msg, self.options.codec)?)
+    }
+
+    /// Next message from the peer, including queued ones that arrived while waiting for replies
+    pub fn receive(&mut self) -> Result<Message, ClientError> {
+        match self.pending.pop_front() {
+            Some(msg) => Ok(msg),
+            None => self.read(),
+        }
+    }
+
+    fn read(&mut self) -> Result<Message, ClientError> {
+        Ok(Message::read_from_with(&mut self.stream, &self.options)?)
+    }
+
+    /// Send request and wait for the reply to it
+    pub fn request(&mut self, msg: &Message) -> Result<Message, ClientError> {
+        self.send(msg)?;
+        loop {
+            match self.read()? {
+                Message::Ping(nonce) => self.send(&Message::Pong(nonce))?,
+                msg @ Message::Filter(_)
+                | msg @ Message::FullFilterInv
+                | msg @ Message::PeerIntroduce(_)
+                | msg @ Message::PeerIntroduceV2(_) => self.pending.push_back(msg),
+                Message::Reject(msg) => return Err(ClientError::Rejected(msg)),
+                msg => return Ok(msg),
+            }
+        }
+    }
+
+    /// Request `amount` filters of blocks starting from `start` height
+    pub fn get_filters(
+        &mut self,
+        currency: Currency,
+        start: u64,
+        amount: u32,
+    ) -> Result<FiltersResp, ClientError> {
+        let req = FiltersReq {
+            currency,
+            start,
+            amount,
+        };
+        match self.request(&Message::GetFilters(req))? {
+            Message::Filters(resp) => Ok(resp),
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Request current fees of given currencies
+    pub fn get_fee(&mut self, currencies: &[Currency]) -> Result<Vec<FeeResp>, ClientError> {
+        match self.request(&Message::GetFee(currencies.to_vec()))? {
+            Message::Fee(resp) => Ok(resp),
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Request exchange rates of currencies to fiats
+    pub fn get_rates(&mut self, reqs: &[RateReq]) -> Result<Vec<RateResp>, ClientError> {
+        match self.request(&Message::GetRates(reqs.to_vec()))? {
+            Message::Rates(resp) => Ok(resp),
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Request addresses of other indexers known to the peer
+    pub fn get_peers(&mut self) -> Result<Vec<Address>, ClientError> {
+        match self.request(&Message::GetPeers)? {
+            Message::Peers(resp) | Message::PeersV2(resp) => Ok(resp),
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Request known indexers with their last seen time, currencies and services. Peers older
+    /// than `Version::PEER_RECORDS` are asked for plain addresses instead, their records have
+    /// unknown time and capabilities.
+    pub fn get_peer_records(&mut self) -> Result<Vec<PeerRecord>, ClientError> {
+        if !self.agreement.peer_records {
+            let addrs = self.get_peers()?;
+            return Ok(addrs.into_iter().map(PeerRecord::from).collect());
+        }
+        match self.request(&Message::GetPeerRecords)? {
+            Message::PeerRecords(resp) => Ok(resp),
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Get the stream back, e.g. to shut it down
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// Write message to the stream. Frame is written at once to not split it into many small writes.
+pub(crate) fn send_to<W: Write>(
+    stream: &mut W,
+    msg: &Message,
+    codec: CodecKind,
+) -> Result<(), io::Error> {
+    let mut buf = vec![];
+    msg.encode_with(&mut buf, codec)?;
+    stream.write_all(&buf)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Stream that replays scripted replies of the peer and records what is sent to it
+    struct Scripted {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Scripted {
+        fn new(replies: &[Message]) -> Self {
+            Scripted {
+                input: Cursor::new(replies.iter().flat_map(serialize).collect()),
+                output: vec![],
+            }
+        }
+
+        fn sent(&self) -> Vec<Message> {
+            let mut r = &self.output[..];
+            let mut msgs = vec![];
+            while !r.is_empty() {
+                msgs.push(Message::read_from(&mut r).unwrap());
+            }
+            msgs
+        }
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn version(major: u16, codec: CodecKind) -> VersionMessage {
+        VersionMessage {
+            version: Version {
+                major,
+                minor: 0,
+                patch: 0,
+            },
+            codec,
+            time: 1621437700,
+            nonce: [1, 2, 3, 4, 5, 6, 7, 8],
+            scan_blocks: vec![ScanBlock {
+                currency: Currency::Btc,
+                version: Version::current(),
+                scan_height: 100,
+                height: 200,
+            }],
+        }
+    }
+
+    fn ours() -> VersionMessage {
+        VersionMessage::new([8; 8], vec![])
+    }
+
+    #[test]
+    fn handshake() {
+        let peer = version(2, CodecKind::Identity);
+        let stream = Scripted::new(&[
+            Message::Version(peer.clone()),
+            Message::Ping([3; 8]),
+            Message::VersionAck,
+        ]);
+        let mut ours = ours();
+        ours.codec = CodecKind::Identity;
+        let conn = Connection::handshake(stream, ours.clone()).unwrap();
+        assert_eq!(conn.peer_version(), &peer);
+        assert_eq!(conn.scan_blocks(), &peer.scan_blocks[..]);
+        assert_eq!(conn.codec(), CodecKind::Identity);
+        assert_eq!(
+            conn.into_inner().sent(),
+            vec![
+                Message::Version(ours),
+                Message::VersionAck,
+                Message::Pong([3; 8])
+            ]
+        );
+    }
+
+    #[test]
+    fn handshake_incompatible() {
+        let stream = Scripted::new(&[Message::Version(version(3, CodecKind::Gzip))]);
+        match Connection::handshake(stream, ours()).map(|_| ()) {
+            Err(ClientError::IncompatibleVersion(v)) => assert_eq!(v.major, 3),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn handshake_closed() {
+        let stream = Scripted::new(&[Message::Version(version(2, CodecKind::Gzip))]);
+        match Connection::handshake(stream, ours()).map(|_| ()) {
+            Err(ClientError::Protocol(e)) => assert!(e.is_header()),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn handshake_self() {
+        let stream = Scripted::new(&[Message::Version(ours())]);
+        match Connection::handshake(stream, ours()).map(|_| ()) {
+            Err(ClientError::Handshake(HandshakeError::SelfConnection)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn requests() {
+        let filters = FiltersResp {
+            currency: Currency::Btc,
+            filters: vec![Filter {
+                block_id: vec![1; 32],
+                filter: vec![2; 20],
+            }],
+        };
+        let fee = vec![FeeResp::Other((
+            Currency::Dash,
+            FeeOther {
+                fast: 4,
+                moderate: 2,
+                cheap: 1,
+            },
+        ))];
+        let event = FilterEvent {
+            currency: Currency::Btc,
+            height: 201,
+            block_id: vec![3; 32],
+            filter: vec![4; 10],
+        };
+        let reject = RejectMessage {
+            id: 13,
+            data: RejectData::InternalError,
+            message: "no rates".to_owned(),
+        };
+        let stream = Scripted::new(&[
+            Message::Version(version(2, CodecKind::Gzip)),
+            Message::VersionAck,
+            Message::Filter(event.clone()),
+            Message::Filters(filters.clone()),
+            Message::Ping([5; 8]),
+            Message::Fee(fee.clone()),
+            Message::Reject(reject.clone()),
+            Message::Pong([0; 8]),
+        ]);
+        let mut conn = Connection::handshake(stream, ours()).unwrap();
+        assert_eq!(conn.get_filters(Currency::Btc, 100, 1).unwrap(), filters);
+        assert_eq!(conn.get_fee(&[Currency::Dash]).unwrap(), fee);
+        match conn.get_rates(&[]) {
+            Err(ClientError::Rejected(msg)) => assert_eq!(msg, reject),
+            res => panic!("unexpected result {:?}", res),
+        }
+        match conn.get_peers() {
+            Err(ClientError::Unexpected(msg)) => assert_eq!(msg, Message::Pong([0; 8])),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(conn.receive().unwrap(), Message::Filter(event));
+        let sent = conn.into_inner().sent();
+        assert_eq!(
+            sent[2..].to_vec(),
+            vec![
+                Message::GetFilters(FiltersReq {
+                    currency: Currency::Btc,
+                    start: 100,
+                    amount: 1
+                }),
+                Message::GetFee(vec![Currency::Dash]),
+                Message::Pong([5; 8]),
+                Message::GetRates(vec![]),
+                Message::GetPeers,
+            ]
+        );
+    }
+
+    #[test]
+    fn peer_records_of_old_peer() {
+        let addr = Address::Ipv4("1.2.3.4:8667".parse().unwrap());
+        let stream = Scripted::new(&[
+            Message::Version(version(2, CodecKind::Gzip)),
+            Message::VersionAck,
+            Message::Peers(vec![addr.clone()]),
+        ]);
+        let mut conn = Connection::handshake(stream, ours()).unwrap();
+        assert!(!conn.agreement().peer_records);
+        assert_eq!(
+            conn.get_peer_records().unwrap(),
+            vec![PeerRecord::from(addr)]
+        );
+        assert_eq!(conn.into_inner().sent()[2..], [Message::GetPeers]);
+    }
+
+    #[test]
+    fn connect_scored() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => Address::Ipv4(addr),
+            addr => panic!("unexpected address {}", addr),
+        };
+        let peer = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Header declares payload beyond the limit
+            stream
+                .write_all(&[3, 0xfe, 0xff, 0xff, 0xff, 0xff])
+                .unwrap();
+        });
+        let mut scores = Scoreboard::default();
+        match connect(&Transport::Direct, &addr, ours(), &mut scores, 100).map(|_| ()) {
+            Err(ClientError::Protocol(e)) => assert!(e.is_header()),
+            res => panic!("unexpected result {:?}", res),
+        }
+        peer.join().unwrap();
+        assert_eq!(scores.score(&addr, 100), 50);
+
+        scores.ban(addr.clone(), 200);
+        match connect(&Transport::Direct, &addr, ours(), &mut scores, 100).map(|_| ()) {
+            Err(ClientError::Banned(until)) => assert_eq!(until, 200),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Framing of protocol messages for tokio transports.
+use crate::error::ProtocolError;
+use crate::message::{CodecKind, DecodeOptions, FrameHeader, Message};
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Codec that splits byte stream into protocol messages. Use it with `tokio_util::codec::Framed`.
+///
+/// Header of a frame is parsed as soon as it arrives, so oversized messages are rejected before
+/// their payload is buffered. Partial frames are kept in the read buffer until complete.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct MessageCodec {
+    options: DecodeOptions,
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Codec that applies given limits when decoding payloads
+    pub fn with_options(options: DecodeOptions) -> Self {
+        MessageCodec { options }
+    }
+
+    /// Switch codec of compressed payloads after it is agreed in version exchange. Applies to
+    /// both decoded and encoded messages.
+    pub fn set_payload_codec(&mut self, codec: CodecKind) {
+        self.options.codec = codec;
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, ProtocolError> {
+        let header = match FrameHeader::peek(src)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let frame_len = header.frame_len();
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(frame_len);
+        Message::from_frame_with(&frame, &self.options).map(Some)
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        msg.encode_with(dst.writer(), self.options.codec)
+            .map_err(|e| ProtocolError::encode(msg.id(), e))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{
+        serialize, Address, Currency, FiltersReq, MempoolChunkResp, PeerRecord, TxPrefix,
+        MAX_MESSAGE_SIZE,
+    };
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn partial_frames() {
+        let msg = Message::GetFilters(FiltersReq {
+            currency: Currency::Btc,
+            start: 445123,
+            amount: 2000,
+        });
+        let bytes = serialize(&msg);
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        for b in bytes.iter() {
+            assert_eq!(codec.decode(&mut buf).unwrap(), None);
+            buf.put_u8(*b);
+        }
+        buf.extend_from_slice(&serialize(&Message::VersionAck));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::VersionAck));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(3);
+        buf.put_u8(0xFE);
+        buf.put_u32_le(MAX_MESSAGE_SIZE as u32 + 1);
+        assert!(MessageCodec::new().decode(&mut buf).is_err());
+        assert!(buf.capacity() < MAX_MESSAGE_SIZE);
+    }
+
+    #[test]
+    fn encode_roundtrip() {
+        let msg = Message::Ping([1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut buf = BytesMut::new();
+        MessageCodec::new().encode(msg.clone(), &mut buf).unwrap();
+        assert_eq!(&buf[..], &serialize(&msg)[..]);
+        assert_eq!(MessageCodec::new().decode(&mut buf).unwrap(), Some(msg));
+    }
+
+    #[test]
+    fn negotiated_codec() {
+        let msg = Message::MempoolChunk(MempoolChunkResp {
+            prefix: TxPrefix([1, 2]),
+            txs: vec![vec![0; 100], vec![1; 20]],
+        });
+        let mut codec = MessageCodec::new();
+        codec.set_payload_codec(CodecKind::Identity);
+        let mut buf = BytesMut::new();
+        codec.encode(msg.clone(), &mut buf).unwrap();
+        assert_ne!(&buf[..], &serialize(&msg)[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg));
+    }
+
+    #[test]
+    fn encode_failure_writes_nothing() {
+        let hostname = Address::Hostname("Example.com".to_string(), 8667);
+        let cjdns = Address::Cjdns(Ipv6Addr::LOCALHOST, 8667);
+        let i2p = Address::I2p([7; 32], 8667);
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Message::VersionAck, &mut buf).unwrap();
+        let before = buf.clone();
+        for msg in [
+            Message::Peers(vec![i2p.clone()]),
+            Message::PeerIntroduce(vec![cjdns.clone()]),
+            Message::PeersV2(vec![i2p, hostname.clone()]),
+            Message::PeerIntroduceV2(vec![cjdns.clone()]),
+            Message::PeerRecords(vec![PeerRecord::from(hostname)]),
+            Message::Peers(vec![cjdns]).tagged(1),
+        ] {
+            assert!(codec.encode(msg, &mut buf).is_err());
+            assert_eq!(buf, before);
+        }
+    }
+}
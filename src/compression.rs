@@ -0,0 +1,260 @@
+//! Codecs for compressed parts of filters and mempool payloads. Peers advertise preferred codec
+//! in version message and fall back to gzip when they don't agree.
+use crate::error::ProtocolErrorKind;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+
+/// Compression algorithm for filters and mempool payloads
+pub trait PayloadCodec {
+    /// Identifier of the codec that is advertised in version message
+    fn kind(&self) -> CodecKind;
+
+    /// Compress everything that `f` writes into the given writer
+    fn compress_with(
+        &self,
+        f: &mut dyn FnMut(&mut dyn Write) -> Result<(), io::Error>,
+    ) -> Result<Vec<u8>, io::Error>;
+
+    /// Wrap reader of compressed bytes into reader of decompressed ones
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> Result<Box<dyn Read + 'a>, io::Error>;
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, io::Error> {
+        self.compress_with(&mut |w| w.write_all(data))
+    }
+
+    /// Decompress data refusing to produce more than `limit` bytes
+    fn decompress(&self, data: &[u8], limit: usize) -> Result<Vec<u8>, ProtocolErrorKind> {
+        let mut out = vec![];
+        self.decoder(Box::new(data))
+            .and_then(|r| r.take(limit as u64 + 1).read_to_end(&mut out))
+            .map_err(|_| ProtocolErrorKind::Decompression)?;
+        if out.len() > limit {
+            return Err(ProtocolErrorKind::DecompressedTooLarge);
+        }
+        Ok(out)
+    }
+}
+
+/// Gzip with default compression level, understood by every peer
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash, Default)]
+pub struct Gzip;
+
+impl PayloadCodec for Gzip {
+    fn kind(&self) -> CodecKind {
+        CodecKind::Gzip
+    }
+
+    fn compress_with(
+        &self,
+        f: &mut dyn FnMut(&mut dyn Write) -> Result<(), io::Error>,
+    ) -> Result<Vec<u8>, io::Error> {
+        let mut e = GzEncoder::new(Vec::new(), Compression::default());
+        f(&mut e)?;
+        e.finish()
+    }
+
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> Result<Box<dyn Read + 'a>, io::Error> {
+        Ok(Box::new(GzDecoder::new(r)))
+    }
+}
+
+/// Zstandard with default compression level
+#[cfg(feature = "zstd")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash, Default)]
+pub struct Zstd;
+
+#[cfg(feature = "zstd")]
+impl PayloadCodec for Zstd {
+    fn kind(&self) -> CodecKind {
+        CodecKind::Zstd
+    }
+
+    fn compress_with(
+        &self,
+        f: &mut dyn FnMut(&mut dyn Write) -> Result<(), io::Error>,
+    ) -> Result<Vec<u8>, io::Error> {
+        let mut e = zstd::stream::write::Encoder::new(Vec::new(), 0)?;
+        f(&mut e)?;
+        e.finish()
+    }
+
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> Result<Box<dyn Read + 'a>, io::Error> {
+        Ok(Box::new(zstd::stream::read::Decoder::new(r)?))
+    }
+}
+
+/// No compression, bytes are sent as is
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash, Default)]
+pub struct Identity;
+
+impl PayloadCodec for Identity {
+    fn kind(&self) -> CodecKind {
+        CodecKind::Identity
+    }
+
+    fn compress_with(
+        &self,
+        f: &mut dyn FnMut(&mut dyn Write) -> Result<(), io::Error>,
+    ) -> Result<Vec<u8>, io::Error> {
+        let mut out = vec![];
+        f(&mut out)?;
+        Ok(out)
+    }
+
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> Result<Box<dyn Read + 'a>, io::Error> {
+        Ok(r)
+    }
+}
+
+/// Codec identifier carried in the 2 reserved bits of version word of version message. Older
+/// peers leave the bits zeroed, that is gzip.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CodecKind {
+    Gzip,
+    Zstd,
+    Identity,
+}
+
+impl Default for CodecKind {
+    fn default() -> Self {
+        CodecKind::Gzip
+    }
+}
+
+impl Display for CodecKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecKind::Gzip => write!(f, "gzip"),
+            CodecKind::Zstd => write!(f, "zstd"),
+            CodecKind::Identity => write!(f, "none"),
+        }
+    }
+}
+
+impl CodecKind {
+    /// Codec that this build prefers: zstd when compiled with `zstd` feature, gzip otherwise
+    pub fn preferred() -> Self {
+        if cfg!(feature = "zstd") {
+            CodecKind::Zstd
+        } else {
+            CodecKind::Gzip
+        }
+    }
+
+    /// Get implementation of the codec, `None` if it is not compiled in
+    pub fn codec(&self) -> Option<&'static dyn PayloadCodec> {
+        match self {
+            CodecKind::Gzip => Some(&Gzip),
+            #[cfg(feature = "zstd")]
+            CodecKind::Zstd => Some(&Zstd),
+            #[cfg(not(feature = "zstd"))]
+            CodecKind::Zstd => None,
+            CodecKind::Identity => Some(&Identity),
+        }
+    }
+
+    /// Codec for connection: the one both sides prefer if it is supported, gzip otherwise
+    pub fn negotiate(ours: CodecKind, theirs: CodecKind) -> CodecKind {
+        if ours == theirs && ours.codec().is_some() {
+            ours
+        } else {
+            CodecKind::Gzip
+        }
+    }
+
+    pub(crate) fn to_bits(self) -> u32 {
+        match self {
+            CodecKind::Gzip => 0,
+            CodecKind::Zstd => 1,
+            CodecKind::Identity => 2,
+        }
+    }
+
+    /// Unassigned value is read as gzip to stay compatible with future codecs
+    pub(crate) fn from_bits(w: u32) -> Self {
+        match w & 0b11 {
+            1 => CodecKind::Zstd,
+            2 => CodecKind::Identity,
+            _ => CodecKind::Gzip,
+        }
+    }
+
+    /// Get implementation of the codec or error for encoders if it is not compiled in
+    pub(crate) fn require(&self) -> Result<&'static dyn PayloadCodec, io::Error> {
+        self.codec().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Payload codec {} is not supported by this build", self),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    fn supported() -> Vec<CodecKind> {
+        [CodecKind::Gzip, CodecKind::Zstd, CodecKind::Identity]
+            .iter()
+            .cloned()
+            .filter(|k| k.codec().is_some())
+            .collect()
+    }
+
+    #[test]
+    fn codecs_roundtrip() {
+        let mut rng = thread_rng();
+        for kind in supported() {
+            let codec = kind.codec().unwrap();
+            assert_eq!(codec.kind(), kind);
+            for _ in 0..100 {
+                let len = rng.gen_range(0..4096);
+                let data: Vec<u8> = (0..len).map(|_| rng.gen_range(0..4)).collect();
+                let compressed = codec.compress(&data).unwrap();
+                assert_eq!(codec.decompress(&compressed, len).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn decompress_limit() {
+        for kind in supported() {
+            let codec = kind.codec().unwrap();
+            let compressed = codec.compress(&[0; 1024]).unwrap();
+            assert!(matches!(
+                codec.decompress(&compressed, 1023),
+                Err(ProtocolErrorKind::DecompressedTooLarge)
+            ));
+        }
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(
+            CodecKind::negotiate(CodecKind::Identity, CodecKind::Identity),
+            CodecKind::Identity
+        );
+        assert_eq!(
+            CodecKind::negotiate(CodecKind::Identity, CodecKind::Gzip),
+            CodecKind::Gzip
+        );
+        assert_eq!(
+            CodecKind::negotiate(CodecKind::Zstd, CodecKind::Identity),
+            CodecKind::Gzip
+        );
+        assert_eq!(
+            CodecKind::negotiate(CodecKind::Zstd, CodecKind::Zstd) == CodecKind::Zstd,
+            cfg!(feature = "zstd")
+        );
+        for kind in [CodecKind::Gzip, CodecKind::Zstd, CodecKind::Identity].iter() {
+            assert_eq!(CodecKind::from_bits(kind.to_bits()), *kind);
+        }
+        assert_eq!(CodecKind::from_bits(3), CodecKind::Gzip);
+    }
+}
@@ -0,0 +1,463 @@
+//! Structured errors of message decoding that tell which message and field failed and why.
+use crate::message::{Error, Message, RejectData, RejectMessage};
+use std::fmt::{Display, Formatter};
+use std::{error, io};
+
+pub(crate) const INVALID_ADDRESS: &str = "Invalid address body";
+pub(crate) const ADDR_V2_REQUIRED: &str = "Address can be sent only in addrv2 encoding";
+pub(crate) const NESTED_TAGGED: &str = "Tagged message can't be nested";
+pub(crate) const KNOWN_ID: &str = "Unknown message can't have id of a known message";
+
+/// Cause of decoding failure
+#[derive(Debug)]
+pub enum ProtocolErrorKind {
+    /// Message type id doesn't fit into 32 bits
+    IdOutOfRange(u64),
+    /// Declared payload size is larger than allowed
+    Oversize { size: u64, max: usize },
+    /// Length prefix of a vector requests too large allocation
+    OversizedVector { requested: usize, max: usize },
+    /// Input ended before all fields were decoded
+    Truncated,
+    /// Bytes left in payload after message was decoded
+    TrailingBytes,
+    /// Type byte of address is not known in v1 encoding
+    UnknownAddressType,
+    /// Onion address has invalid characters, version or checksum
+    InvalidOnionAddress,
+    /// Address of known network has body of wrong length or content
+    InvalidAddress,
+    /// Compressed part of payload is corrupted
+    Decompression,
+    /// Compressed part of payload inflates beyond the configured limit
+    DecompressedTooLarge,
+    /// Declared amount of items can't fit into the bytes of payload
+    AmountExceedsPayload,
+    /// Tagged message wraps another tagged message
+    NestedTagged,
+    /// Our message can't be encoded, e.g. it holds address that the encoding can't carry
+    Encode(io::Error),
+    /// Underlying reader failed
+    Io(io::Error),
+    /// Any other failure reported by field decoders
+    Encoding(Error),
+}
+
+impl Display for ProtocolErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolErrorKind::IdOutOfRange(id) => write!(f, "message id {} exceeds 32 bits", id),
+            ProtocolErrorKind::Oversize { size, max } => {
+                write!(f, "payload of {} bytes exceeds limit of {} bytes", size, max)
+            }
+            ProtocolErrorKind::OversizedVector { requested, max } => write!(
+                f,
+                "vector allocation of {} bytes exceeds limit of {} bytes",
+                requested, max
+            ),
+            ProtocolErrorKind::Truncated => write!(f, "unexpected end of data"),
+            ProtocolErrorKind::TrailingBytes => write!(f, "trailing bytes after message"),
+            ProtocolErrorKind::UnknownAddressType => write!(f, "unknown address type"),
+            ProtocolErrorKind::InvalidOnionAddress => write!(f, "invalid onion v3 address"),
+            ProtocolErrorKind::InvalidAddress => write!(f, "invalid address body"),
+            ProtocolErrorKind::Decompression => write!(f, "failed to decompress"),
+            ProtocolErrorKind::DecompressedTooLarge => {
+                write!(f, "decompressed payload exceeds limit")
+            }
+            ProtocolErrorKind::AmountExceedsPayload => {
+                write!(f, "declared amount of items exceeds payload")
+            }
+            ProtocolErrorKind::NestedTagged => write!(f, "tagged message can't be nested"),
+            ProtocolErrorKind::Encode(e) => e.fmt(f),
+            ProtocolErrorKind::Io(e) => write!(f, "io error: {}", e),
+            ProtocolErrorKind::Encoding(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<Error> for ProtocolErrorKind {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => ProtocolErrorKind::Truncated,
+            Error::Io(e) => ProtocolErrorKind::Io(e),
+            Error::OversizedVectorAllocation { requested, max } => {
+                ProtocolErrorKind::OversizedVector { requested, max }
+            }
+            e => ProtocolErrorKind::Encoding(e),
+        }
+    }
+}
+
+/// Failure of a payload field before its offset in the frame is known. Decoders behind
+/// `Decodable` return it inside `Error::Io`, so the kind and the field survive nested decoders.
+#[derive(Debug)]
+pub(crate) struct FieldError {
+    pub field: &'static str,
+    pub kind: ProtocolErrorKind,
+    /// Request id and type id of the message inside tagged frame which failed
+    pub tagged: Option<(u64, u32)>,
+}
+
+impl FieldError {
+    pub(crate) fn new(field: &'static str, kind: ProtocolErrorKind) -> Self {
+        FieldError {
+            field,
+            kind,
+            tagged: None,
+        }
+    }
+
+    /// Failure inside tagged frame which header is decoded
+    pub(crate) fn tagged(self, request_id: u64, message_id: u32) -> Self {
+        FieldError {
+            tagged: Some((request_id, message_id)),
+            ..self
+        }
+    }
+
+    /// Recover failure of a decoder. The innermost decoder that named the field wins, otherwise
+    /// the failure is attributed to `field`.
+    pub(crate) fn from_decode(field: &'static str, e: Error) -> Self {
+        match e {
+            Error::Io(e) => match take_field_error(e) {
+                Ok(e) => e,
+                Err(e) => FieldError::new(field, Error::Io(e).into()),
+            },
+            e => FieldError::new(field, e.into()),
+        }
+    }
+}
+
+/// Take field error out of IO error that carries it
+fn take_field_error(e: io::Error) -> Result<FieldError, io::Error> {
+    if !e.get_ref().map_or(false, |inner| inner.is::<FieldError>()) {
+        return Err(e);
+    }
+    match e.into_inner().map(|inner| inner.downcast::<FieldError>()) {
+        Some(Ok(e)) => Ok(*e),
+        _ => unreachable!("io error carries field error"),
+    }
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.kind)
+    }
+}
+
+impl error::Error for FieldError {}
+
+impl From<FieldError> for Error {
+    fn from(e: FieldError) -> Self {
+        Error::Io(io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Name the field that a decoder reads
+pub(crate) trait DecodeField<T> {
+    fn field(self, name: &'static str) -> Result<T, Error>;
+}
+
+impl<T> DecodeField<T> for Result<T, Error> {
+    fn field(self, name: &'static str) -> Result<T, Error> {
+        self.map_err(|e| FieldError::from_decode(name, e).into())
+    }
+}
+
+impl<T> DecodeField<T> for Result<T, io::Error> {
+    fn field(self, name: &'static str) -> Result<T, Error> {
+        self.map_err(Error::from).field(name)
+    }
+}
+
+/// Failure of the named field with given cause
+pub(crate) fn field_error(field: &'static str, kind: ProtocolErrorKind) -> Error {
+    FieldError::new(field, kind).into()
+}
+
+/// Failure to decode a message frame
+#[derive(Debug)]
+pub struct ProtocolError {
+    /// Type id of message, known if it was decoded before the failure. For tagged frame that
+    /// failed after its header it is the type id of the tagged message.
+    pub message_id: Option<u32>,
+    /// Request id of tagged frame that failed after its header
+    pub request_id: Option<u64>,
+    /// Name of the field that was being decoded: "id", "length", "payload" for frame header and
+    /// raw payload, otherwise the field of the message itself.
+    pub field: &'static str,
+    /// Offset in bytes from the start of frame where decoding stopped
+    pub offset: u64,
+    pub kind: ProtocolErrorKind,
+}
+
+impl ProtocolError {
+    pub fn new(
+        message_id: Option<u32>,
+        field: &'static str,
+        offset: u64,
+        kind: ProtocolErrorKind,
+    ) -> Self {
+        ProtocolError {
+            message_id,
+            request_id: None,
+            field,
+            offset,
+            kind,
+        }
+    }
+
+    pub(crate) fn from_encode(
+        message_id: Option<u32>,
+        field: &'static str,
+        offset: u64,
+        e: Error,
+    ) -> Self {
+        ProtocolError::new(message_id, field, offset, e.into())
+    }
+
+    /// Failure inside payload of message at the field named by the decoder
+    pub(crate) fn payload(message_id: u32, offset: u64, e: FieldError) -> Self {
+        match e.tagged {
+            Some((request_id, message_id)) => ProtocolError {
+                request_id: Some(request_id),
+                ..ProtocolError::new(Some(message_id), e.field, offset, e.kind)
+            },
+            None => ProtocolError::new(Some(message_id), e.field, offset, e.kind),
+        }
+    }
+
+    /// Our message of given type can't be encoded
+    pub fn encode(message_id: u32, e: io::Error) -> Self {
+        ProtocolError::new(Some(message_id), "payload", 0, ProtocolErrorKind::Encode(e))
+    }
+
+    /// Whether the stream ended at frame boundary, that is the peer closed the connection
+    pub fn is_end_of_stream(&self) -> bool {
+        self.field == "id" && self.offset == 0 && matches!(self.kind, ProtocolErrorKind::Truncated)
+    }
+
+    /// Whether the frame header failed to decode. The stream can't be parsed further then.
+    pub fn is_header(&self) -> bool {
+        self.field == "id" || self.field == "length"
+    }
+
+    /// Reject code that corresponds to the error
+    pub fn reject_data(&self) -> RejectData {
+        match self.kind {
+            ProtocolErrorKind::Io(_) | ProtocolErrorKind::Encode(_) => RejectData::InternalError,
+            _ if self.is_header() => RejectData::HeaderParsing,
+            _ => RejectData::PayloadParsing,
+        }
+    }
+
+    /// Reject message that should be sent back to the peer. When message type is unknown the
+    /// reject refers to `u32::MAX` id.
+    pub fn to_reject(&self) -> RejectMessage {
+        RejectMessage {
+            id: self.message_id.unwrap_or(u32::MAX),
+            data: self.reject_data(),
+            message: self.to_string(),
+        }
+    }
+
+    /// Reject of the frame ready to be sent, tagged by request id of the frame when it is known
+    pub fn to_reject_message(&self) -> Message {
+        let reject = Message::Reject(self.to_reject());
+        match self.request_id {
+            Some(request_id) => reject.tagged(request_id),
+            None => reject,
+        }
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = self
+            .message_id
+            .map(|id| Message::name_from_id(id).unwrap_or("unknown"))
+            .unwrap_or("message");
+        if let ProtocolErrorKind::Encode(e) = &self.kind {
+            return write!(f, "failed to encode {}: {}", name, e);
+        }
+        write!(
+            f,
+            "failed to decode {} of {} at byte {}: {}",
+            self.field, name, self.offset, self.kind
+        )
+    }
+}
+
+impl error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.kind {
+            ProtocolErrorKind::Io(e) | ProtocolErrorKind::Encode(e) => Some(e),
+            ProtocolErrorKind::Encoding(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Failure of the underlying stream outside of any frame, e.g. in tokio transports
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        ProtocolError::new(None, "stream", 0, ProtocolErrorKind::Io(e))
+    }
+}
+
+/// Failure to parse protocol value from string, e.g. from config file or command line
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseError {
+    /// Type of value: "address", "currency", "fiat" or "version"
+    pub what: &'static str,
+    /// Part of the value that is wrong, e.g. "port" of address
+    pub part: &'static str,
+    pub input: String,
+}
+
+impl ParseError {
+    pub(crate) fn new(what: &'static str, part: &'static str, input: &str) -> Self {
+        ParseError {
+            what,
+            part,
+            input: input.to_owned(),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid {} of {} {:?}", self.part, self.what, self.input)
+    }
+}
+
+impl error::Error for ParseError {}
+
+impl From<ProtocolError> for RejectMessage {
+    fn from(e: ProtocolError) -> Self {
+        e.to_reject()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::*;
+    use consensus_encode::util::hex::FromHex;
+
+    #[test]
+    fn oversized_payload() {
+        let bytes = Vec::from_hex("03fe01000001").unwrap();
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert_eq!(e.message_id, Some(3));
+        assert_eq!(e.field, "length");
+        assert_eq!(e.offset, 1);
+        assert!(matches!(e.kind, ProtocolErrorKind::Oversize { size: 0x01000001, .. }));
+        assert_eq!(e.reject_data(), RejectData::HeaderParsing);
+    }
+
+    #[test]
+    fn unknown_address_type() {
+        let bytes = Vec::from_hex("06080107000000000000").unwrap();
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert_eq!(e.message_id, Some(6));
+        assert_eq!(e.field, "address");
+        assert_eq!(e.offset, 4);
+        assert!(matches!(e.kind, ProtocolErrorKind::UnknownAddressType));
+
+        let reject = RejectMessage::from(e);
+        assert_eq!(reject.id, 6);
+        assert_eq!(reject.data, RejectData::PayloadParsing);
+    }
+
+    #[test]
+    fn invalid_onion_address() {
+        let mut payload = serialize(&VarInt(1));
+        payload.push(2);
+        payload.extend_from_slice(&[b'z'; 56]);
+        payload.extend_from_slice(&[0x23, 0xbe]);
+        let mut bytes = serialize(&VarInt(6));
+        bytes.extend(serialize(&VarInt(payload.len() as u64)));
+        bytes.extend(payload);
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert_eq!(e.message_id, Some(6));
+        assert_eq!(e.field, "address");
+        assert!(matches!(e.kind, ProtocolErrorKind::InvalidOnionAddress));
+    }
+
+    #[test]
+    fn field_of_nested_decoder() {
+        // Filter event with 2 bytes of 32 byte block id
+        let bytes = Vec::from_hex("04040005abcd").unwrap();
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert_eq!(e.message_id, Some(4));
+        assert_eq!(e.field, "block id");
+        assert!(matches!(e.kind, ProtocolErrorKind::Truncated));
+
+        // Reject with unfinished message string
+        let bytes = Vec::from_hex("0a0406000561").unwrap();
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert_eq!(e.message_id, Some(10));
+        assert_eq!(e.field, "message");
+    }
+
+    #[test]
+    fn encode_failure() {
+        let e = ProtocolError::encode(6, io::Error::new(io::ErrorKind::InvalidInput, "no v1"));
+        assert_eq!(e.message_id, Some(6));
+        assert_eq!(e.reject_data(), RejectData::InternalError);
+        assert_eq!(e.to_string(), "failed to encode peers: no v1");
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let bytes = Vec::from_hex("0b08cf7806").unwrap();
+        let e = Message::read_from(&bytes[..]).unwrap_err();
+        assert_eq!(e.message_id, Some(11));
+        assert_eq!(e.field, "payload");
+        assert_eq!(e.offset, 2);
+        assert!(matches!(e.kind, ProtocolErrorKind::Truncated));
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert_eq!(e.offset, 5);
+        assert!(matches!(e.kind, ProtocolErrorKind::Truncated));
+    }
+
+    fn filters_frame(amount: u64, uncompressed: Vec<u8>) -> Vec<u8> {
+        let mut payload = serialize(&Currency::Btc);
+        payload.extend(serialize(&VarInt(amount)));
+        payload.extend(MemFilter(uncompressed).compress().unwrap());
+        let mut bytes = serialize(&VarInt(3));
+        bytes.extend(serialize(&VarInt(payload.len() as u64)));
+        bytes.extend(payload);
+        bytes
+    }
+
+    #[test]
+    fn decompression_bomb() {
+        let bytes = filters_frame(1, vec![0; 1024 * 1024]);
+        let options = DecodeOptions {
+            max_decompressed_size: 1024,
+            ..Default::default()
+        };
+        let e = Message::from_frame_with(&bytes, &options).unwrap_err();
+        assert_eq!(e.field, "compressed body");
+        assert!(matches!(e.kind, ProtocolErrorKind::DecompressedTooLarge));
+        assert!(Message::from_frame(&bytes).is_ok());
+    }
+
+    #[test]
+    fn amount_exceeds_payload() {
+        let bytes = filters_frame(1_000_000, vec![0; 330]);
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert_eq!(e.field, "amount");
+        assert!(matches!(e.kind, ProtocolErrorKind::AmountExceedsPayload));
+    }
+
+    #[test]
+    fn decompression_failure() {
+        let bytes = Vec::from_hex("0306000201020304").unwrap();
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert_eq!(e.field, "compressed body");
+        assert!(matches!(e.kind, ProtocolErrorKind::Decompression));
+    }
+}
@@ -0,0 +1,412 @@
+//! Transport independent handshake. `Handshake` consumes incoming messages and returns messages
+//! that should be sent in reply, so the same rules apply to blocking, async and server
+//! connections.
+//!
+//! The client sends its `Version` first. The server answers with its own `Version` and
+//! `VersionAck`, and the client acknowledges the server version. Both sides must receive
+//! `Version` before anything else and `VersionAck` exactly once.
+use crate::message::*;
+use std::collections::HashMap;
+use std::error;
+use std::fmt::{Display, Formatter};
+
+/// Type id of version message
+const VERSION_ID: u32 = 0;
+
+/// Difference in seconds between clocks of peers that is flagged as skewed, unless it is changed
+/// with `set_max_clock_skew`
+pub const DEFAULT_MAX_CLOCK_SKEW: u64 = 2 * 60 * 60;
+
+/// Side of the connection. Indexer is the server, its scan blocks are the ones that are agreed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Violation of handshake rules
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum HandshakeError {
+    /// Peer speaks major version that we don't support
+    IncompatibleVersion(Version),
+    /// Peer sent our own nonce, so we are connected to ourselves
+    SelfConnection,
+    /// Peer sent second version message
+    DuplicateVersion,
+    /// Peer acknowledged our version twice
+    DuplicateAck,
+    /// Peer rejected our version
+    Rejected(RejectMessage),
+    /// Peer sent message that is not allowed at this point of handshake
+    Unexpected(Message),
+}
+
+impl HandshakeError {
+    /// Reject message that should be sent to the peer before closing the connection
+    pub fn reject(&self) -> Option<RejectMessage> {
+        match self {
+            HandshakeError::IncompatibleVersion(v) => Some(RejectMessage {
+                id: VERSION_ID,
+                data: RejectData::VersionNotSupported,
+                message: format!("Version {} is not supported", v),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::IncompatibleVersion(v) => write!(
+                f,
+                "peer version {} is not compatible with {}",
+                v,
+                Version::current()
+            ),
+            HandshakeError::SelfConnection => write!(f, "connected to self"),
+            HandshakeError::DuplicateVersion => write!(f, "peer sent version twice"),
+            HandshakeError::DuplicateAck => write!(f, "peer acknowledged version twice"),
+            HandshakeError::Rejected(msg) => write!(f, "peer rejected version: {}", msg),
+            HandshakeError::Unexpected(msg) => {
+                write!(f, "unexpected message during handshake: {}", msg)
+            }
+        }
+    }
+}
+
+impl error::Error for HandshakeError {}
+
+/// Outcome of successful handshake
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Agreement {
+    /// Version message of the peer
+    pub peer: VersionMessage,
+    /// Codec of compressed payloads
+    pub codec: CodecKind,
+    /// Whether requests can be sent as `Message::Tagged`
+    pub request_ids: bool,
+    /// Whether peers messages can be sent in addrv2 encoding
+    pub addr_v2: bool,
+    /// Whether peer can be asked with `Message::GetPeerRecords`
+    pub peer_records: bool,
+    /// Indexer progress of currencies that both sides are interested in
+    pub scan_blocks: Vec<ScanBlock>,
+    /// Seconds that clock of the peer is ahead of ours
+    pub clock_offset: i64,
+    /// Whether clock offset exceeds allowed skew. Times reported by the peer are not reliable
+    /// then, but the connection is still usable.
+    pub clock_skewed: bool,
+}
+
+/// Handshake in progress
+#[derive(Clone, Debug)]
+pub struct Handshake {
+    role: Role,
+    ours: VersionMessage,
+    peer: Option<VersionMessage>,
+    version_sent: bool,
+    acked: bool,
+    max_clock_skew: u64,
+}
+
+impl Handshake {
+    pub fn new(role: Role, ours: VersionMessage) -> Self {
+        Handshake {
+            role,
+            ours,
+            peer: None,
+            version_sent: false,
+            acked: false,
+            max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+        }
+    }
+
+    /// Change difference of clocks in seconds that is flagged as skewed
+    pub fn set_max_clock_skew(&mut self, secs: u64) {
+        self.max_clock_skew = secs;
+    }
+
+    /// Messages to send before anything is received. Client opens with its version, server
+    /// waits for it.
+    pub fn start(&mut self) -> Vec<Message> {
+        if self.role == Role::Client && !self.version_sent {
+            self.version_sent = true;
+            vec![Message::Version(self.ours.clone())]
+        } else {
+            vec![]
+        }
+    }
+
+    /// Process message of the peer and return messages to send in reply. After an error the
+    /// connection should be closed, sending `HandshakeError::reject` first if there is one.
+    pub fn handle(&mut self, msg: Message) -> Result<Vec<Message>, HandshakeError> {
+        match msg {
+            Message::Version(peer) => {
+                if self.peer.is_some() {
+                    return Err(HandshakeError::DuplicateVersion);
+                }
+                if !peer.version.compatible(&Version::current()) {
+                    return Err(HandshakeError::IncompatibleVersion(peer.version));
+                }
+                if peer.nonce == self.ours.nonce {
+                    return Err(HandshakeError::SelfConnection);
+                }
+                self.peer = Some(peer);
+                let mut out = vec![];
+                if !self.version_sent {
+                    self.version_sent = true;
+                    out.push(Message::Version(self.ours.clone()));
+                }
+                out.push(Message::VersionAck);
+                Ok(out)
+            }
+            Message::VersionAck if self.peer.is_some() && self.version_sent => {
+                if self.acked {
+                    return Err(HandshakeError::DuplicateAck);
+                }
+                self.acked = true;
+                Ok(vec![])
+            }
+            Message::Ping(nonce) if self.peer.is_some() => Ok(vec![Message::Pong(nonce)]),
+            Message::Reject(msg) => Err(HandshakeError::Rejected(msg)),
+            msg => Err(HandshakeError::Unexpected(msg)),
+        }
+    }
+
+    /// Whether both versions are exchanged and acknowledged
+    pub fn is_done(&self) -> bool {
+        self.peer.is_some() && self.acked
+    }
+
+    /// Agreed parameters of the connection, `None` if handshake is not done yet
+    pub fn finish(self) -> Option<Agreement> {
+        if !self.acked {
+            return None;
+        }
+        let peer = self.peer?;
+        let (client, indexer) = match self.role {
+            Role::Client => (&self.ours, &peer),
+            Role::Server => (&peer, &self.ours),
+        };
+        let scan_blocks = agree_scan_blocks(&client.scan_blocks, &indexer.scan_blocks);
+        // Peer may report any time, the offset saturates instead of overflowing
+        let offset = peer.time as i128 - self.ours.time as i128;
+        let clock_offset = offset.clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+        Some(Agreement {
+            codec: CodecKind::negotiate(self.ours.codec, peer.codec),
+            request_ids: self.ours.version.supports_request_ids()
+                && peer.version.supports_request_ids(),
+            addr_v2: self.ours.version.supports_addr_v2() && peer.version.supports_addr_v2(),
+            peer_records: self.ours.version.supports_peer_records()
+                && peer.version.supports_peer_records(),
+            scan_blocks,
+            clock_offset,
+            clock_skewed: clock_offset.unsigned_abs() > self.max_clock_skew,
+            peer,
+        })
+    }
+}
+
+/// Scan blocks of indexer for currencies that client listed with compatible version. Client
+/// that lists no currencies accepts all of them.
+fn agree_scan_blocks(client: &[ScanBlock], indexer: &[ScanBlock]) -> Vec<ScanBlock> {
+    if client.is_empty() {
+        return indexer.to_vec();
+    }
+    let wanted: HashMap<Currency, &Version> =
+        client.iter().map(|b| (b.currency, &b.version)).collect();
+    indexer
+        .iter()
+        .filter(|b| matches!(wanted.get(&b.currency), Some(v) if v.compatible(&b.version)))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scan_block(currency: Currency, major: u16) -> ScanBlock {
+        ScanBlock {
+            currency,
+            version: Version {
+                major,
+                minor: 0,
+                patch: 0,
+            },
+            scan_height: 100,
+            height: 200,
+        }
+    }
+
+    fn version(nonce: u8, time: u64, scan_blocks: Vec<ScanBlock>) -> VersionMessage {
+        VersionMessage {
+            version: Version::current(),
+            codec: CodecKind::Gzip,
+            time,
+            nonce: [nonce; 8],
+            scan_blocks,
+        }
+    }
+
+    /// Feed messages of one side to the other until both are done
+    fn exchange(client: &mut Handshake, server: &mut Handshake) {
+        let mut to_server = client.start();
+        assert!(server.start().is_empty());
+        while !to_server.is_empty() {
+            let mut to_client = vec![];
+            for msg in to_server.drain(..) {
+                to_client.extend(server.handle(msg).unwrap());
+            }
+            for msg in to_client {
+                to_server.extend(client.handle(msg).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn client_and_server() {
+        let client_version = version(1, 1000, vec![scan_block(Currency::Btc, 1)]);
+        let server_version = version(
+            2,
+            1010,
+            vec![scan_block(Currency::Btc, 1), scan_block(Currency::Ergo, 1)],
+        );
+        let mut client = Handshake::new(Role::Client, client_version.clone());
+        let mut server = Handshake::new(Role::Server, server_version.clone());
+        exchange(&mut client, &mut server);
+        assert!(client.is_done());
+        assert!(server.is_done());
+
+        let client = client.finish().unwrap();
+        assert_eq!(client.peer, server_version);
+        assert_eq!(client.scan_blocks, vec![scan_block(Currency::Btc, 1)]);
+        assert_eq!(client.clock_offset, 10);
+        assert!(!client.clock_skewed);
+        assert!(client.request_ids);
+        assert!(client.addr_v2);
+        assert!(client.peer_records);
+
+        let server = server.finish().unwrap();
+        assert_eq!(server.peer, client_version);
+        assert_eq!(server.scan_blocks, vec![scan_block(Currency::Btc, 1)]);
+        assert_eq!(server.clock_offset, -10);
+    }
+
+    #[test]
+    fn messages_order() {
+        let mut client = Handshake::new(Role::Client, version(1, 1000, vec![]));
+        assert_eq!(
+            client.start(),
+            vec![Message::Version(version(1, 1000, vec![]))]
+        );
+        assert!(client.start().is_empty());
+        assert_eq!(
+            client.clone().handle(Message::VersionAck),
+            Err(HandshakeError::Unexpected(Message::VersionAck))
+        );
+        assert_eq!(
+            client.clone().handle(Message::Ping([1; 8])),
+            Err(HandshakeError::Unexpected(Message::Ping([1; 8])))
+        );
+        assert_eq!(
+            client.handle(Message::Version(version(2, 1000, vec![]))),
+            Ok(vec![Message::VersionAck])
+        );
+        assert_eq!(
+            client.handle(Message::Ping([1; 8])),
+            Ok(vec![Message::Pong([1; 8])])
+        );
+        assert_eq!(
+            client
+                .clone()
+                .handle(Message::Version(version(2, 1000, vec![]))),
+            Err(HandshakeError::DuplicateVersion)
+        );
+        assert!(client.clone().finish().is_none());
+        assert_eq!(client.handle(Message::VersionAck), Ok(vec![]));
+        assert!(client.is_done());
+        assert_eq!(
+            client.handle(Message::VersionAck),
+            Err(HandshakeError::DuplicateAck)
+        );
+    }
+
+    #[test]
+    fn server_ack_before_version() {
+        let mut server = Handshake::new(Role::Server, version(2, 1000, vec![]));
+        assert_eq!(
+            server.handle(Message::VersionAck),
+            Err(HandshakeError::Unexpected(Message::VersionAck))
+        );
+    }
+
+    #[test]
+    fn invalid_versions() {
+        let mut server = Handshake::new(Role::Server, version(2, 1000, vec![]));
+        let mut old = version(1, 1000, vec![]);
+        old.version.major = 1;
+        let err = server
+            .clone()
+            .handle(Message::Version(old.clone()))
+            .unwrap_err();
+        assert_eq!(err, HandshakeError::IncompatibleVersion(old.version));
+        assert_eq!(
+            err.reject().map(|r| r.data),
+            Some(RejectData::VersionNotSupported)
+        );
+
+        let err = server
+            .handle(Message::Version(version(2, 1000, vec![])))
+            .unwrap_err();
+        assert_eq!(err, HandshakeError::SelfConnection);
+        assert_eq!(err.reject(), None);
+    }
+
+    #[test]
+    fn clock_skew() {
+        let mut client = Handshake::new(Role::Client, version(1, 10_000, vec![]));
+        let mut server = Handshake::new(Role::Server, version(2, 10_000 + 3 * 60 * 60, vec![]));
+        client.set_max_clock_skew(4 * 60 * 60);
+        exchange(&mut client, &mut server);
+        let agreement = client.finish().unwrap();
+        assert_eq!(agreement.clock_offset, 3 * 60 * 60);
+        assert!(!agreement.clock_skewed);
+        let agreement = server.finish().unwrap();
+        assert_eq!(agreement.clock_offset, -3 * 60 * 60);
+        assert!(agreement.clock_skewed);
+    }
+
+    #[test]
+    fn clock_offset_saturates() {
+        let mut client = Handshake::new(Role::Client, version(1, 1000, vec![]));
+        let mut server = Handshake::new(Role::Server, version(2, u64::MAX, vec![]));
+        exchange(&mut client, &mut server);
+        let agreement = client.finish().unwrap();
+        assert_eq!(agreement.clock_offset, i64::MAX);
+        assert!(agreement.clock_skewed);
+        let agreement = server.finish().unwrap();
+        assert_eq!(agreement.clock_offset, i64::MIN);
+        assert!(agreement.clock_skewed);
+    }
+
+    #[test]
+    fn scan_blocks_agreement() {
+        let indexer = vec![
+            scan_block(Currency::Btc, 1),
+            scan_block(Currency::Ergo, 2),
+            scan_block(Currency::Dash, 1),
+        ];
+        assert_eq!(agree_scan_blocks(&[], &indexer), indexer);
+        let client = vec![
+            scan_block(Currency::Ergo, 1),
+            scan_block(Currency::Dash, 1),
+            scan_block(Currency::Zec, 1),
+        ];
+        assert_eq!(
+            agree_scan_blocks(&client, &indexer),
+            vec![scan_block(Currency::Dash, 1)]
+        );
+    }
+}
@@ -0,0 +1,309 @@
+//! Keepalive of a connection built on `Ping` and `Pong`. `Keepalive` has no IO of its own: it
+//! tells when to send pings, matches pongs to outstanding nonces and keeps round-trip time
+//! statistics. Time is read from a `Clock`, so tests can drive it manually.
+use crate::message::*;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+/// Source of current time
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// Clock of the system
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeepaliveConfig {
+    /// Time between pings. Interval too long to be added to the current time means that no
+    /// more pings are sent after the first one.
+    pub interval: Duration,
+    /// Time to wait for pong before the ping is counted as missed. Timeout too long to be added
+    /// to the current time never expires.
+    pub timeout: Duration,
+    /// Missed pings in a row after which the peer is considered dead
+    pub max_misses: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+            max_misses: 3,
+        }
+    }
+}
+
+/// What should be done with the connection now
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Tick {
+    /// Nothing to do until `Keepalive::deadline`, or ever if there is none
+    Idle,
+    /// Send the ping to the peer
+    Ping(Message),
+    /// Peer missed too many pings, the connection should be closed
+    Dead,
+}
+
+/// Pong that doesn't answer any outstanding ping. Pong that arrives after the timeout is
+/// unsolicited too, as its ping is already counted as missed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UnsolicitedPong(pub [u8; 8]);
+
+impl Display for UnsolicitedPong {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pong with unknown nonce {:?}", self.0)
+    }
+}
+
+impl error::Error for UnsolicitedPong {}
+
+/// Round-trip time statistics of a connection
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct RttStats {
+    /// Amount of answered pings
+    pub samples: u32,
+    pub last: Option<Duration>,
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+    /// Moving average that gives 1/8 weight to the newest sample, like smoothed RTT of TCP
+    pub smoothed: Option<Duration>,
+}
+
+impl RttStats {
+    fn record(&mut self, rtt: Duration) {
+        self.samples += 1;
+        self.last = Some(rtt);
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.max = Some(self.max.map_or(rtt, |max| max.max(rtt)));
+        self.smoothed = Some(match self.smoothed {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+}
+
+/// Keepalive state of one connection
+#[derive(Debug)]
+pub struct Keepalive<C = SystemClock> {
+    clock: C,
+    config: KeepaliveConfig,
+    /// Nonces of pings without pong yet and times they were sent, oldest first
+    outstanding: VecDeque<([u8; 8], Instant)>,
+    /// `None` if no more pings are due
+    next_ping: Option<Instant>,
+    misses: u32,
+    stats: RttStats,
+    nonces: Nonces,
+}
+
+impl Keepalive<SystemClock> {
+    pub fn new(config: KeepaliveConfig) -> Self {
+        Keepalive::with_clock(config, SystemClock)
+    }
+}
+
+impl<C: Clock> Keepalive<C> {
+    /// Keepalive that reads time from given clock. First ping is due right away.
+    pub fn with_clock(config: KeepaliveConfig, clock: C) -> Self {
+        let now = clock.now();
+        Keepalive {
+            clock,
+            config,
+            outstanding: VecDeque::new(),
+            next_ping: Some(now),
+            misses: 0,
+            stats: RttStats::default(),
+            nonces: Nonces::new(),
+        }
+    }
+
+    /// Count expired pings and tell whether a ping should be sent. Should be called at
+    /// `deadline` or later.
+    pub fn tick(&mut self) -> Tick {
+        let now = self.clock.now();
+        while let Some((_, sent)) = self.outstanding.front() {
+            if now.saturating_duration_since(*sent) < self.config.timeout {
+                break;
+            }
+            self.outstanding.pop_front();
+            self.misses += 1;
+        }
+        if self.misses >= self.config.max_misses {
+            return Tick::Dead;
+        }
+        match self.next_ping {
+            Some(next_ping) if now >= next_ping => (),
+            _ => return Tick::Idle,
+        }
+        let nonce = self.nonces.next();
+        self.outstanding.push_back((nonce, now));
+        self.next_ping = now.checked_add(self.config.interval);
+        Tick::Ping(Message::Ping(nonce))
+    }
+
+    /// Match pong of the peer to outstanding ping. Returns round-trip time of the ping.
+    pub fn on_pong(&mut self, nonce: [u8; 8]) -> Result<Duration, UnsolicitedPong> {
+        let i = self
+            .outstanding
+            .iter()
+            .position(|(n, _)| *n == nonce)
+            .ok_or(UnsolicitedPong(nonce))?;
+        let (_, sent) = self.outstanding.remove(i).expect("position is in bounds");
+        let rtt = self.clock.now().saturating_duration_since(sent);
+        self.stats.record(rtt);
+        self.misses = 0;
+        Ok(rtt)
+    }
+
+    /// Time when `tick` should be called next: either the next ping is due or the oldest
+    /// outstanding ping expires. `None` if neither happens, when interval and timeout are too
+    /// long to be represented.
+    pub fn deadline(&self) -> Option<Instant> {
+        let expiry = self
+            .outstanding
+            .front()
+            .and_then(|(_, sent)| sent.checked_add(self.config.timeout));
+        self.next_ping.into_iter().chain(expiry).min()
+    }
+
+    /// Missed pings since the last pong
+    pub fn misses(&self) -> u32 {
+        self.misses
+    }
+
+    pub fn stats(&self) -> &RttStats {
+        &self.stats
+    }
+}
+
+/// Generator of ping nonces that differ between connections and processes
+#[derive(Debug)]
+struct Nonces {
+    state: RandomState,
+    counter: u64,
+}
+
+impl Nonces {
+    fn new() -> Self {
+        Nonces {
+            state: RandomState::new(),
+            counter: 0,
+        }
+    }
+
+    fn next(&mut self) -> [u8; 8] {
+        let mut hasher = self.state.build_hasher();
+        hasher.write_u64(self.counter);
+        self.counter += 1;
+        hasher.finish().to_le_bytes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    type ManualClock = Rc<Cell<Instant>>;
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.get()
+        }
+    }
+
+    fn advance(clock: &ManualClock, secs: u64) {
+        clock.set(clock.get() + Duration::from_secs(secs));
+    }
+
+    fn keepalive() -> (Keepalive<ManualClock>, ManualClock) {
+        let clock = Rc::new(Cell::new(Instant::now()));
+        (
+            Keepalive::with_clock(KeepaliveConfig::default(), clock.clone()),
+            clock,
+        )
+    }
+
+    fn ping(tick: Tick) -> [u8; 8] {
+        match tick {
+            Tick::Ping(Message::Ping(nonce)) => nonce,
+            tick => panic!("expected ping, got {:?}", tick),
+        }
+    }
+
+    #[test]
+    fn rtt_stats() {
+        let (mut keepalive, clock) = keepalive();
+        let start = clock.get();
+        let nonce = ping(keepalive.tick());
+        assert_eq!(keepalive.tick(), Tick::Idle);
+        assert_eq!(keepalive.deadline(), Some(start + Duration::from_secs(10)));
+        advance(&clock, 2);
+        assert_eq!(keepalive.on_pong(nonce), Ok(Duration::from_secs(2)));
+        assert_eq!(keepalive.on_pong(nonce), Err(UnsolicitedPong(nonce)));
+        assert_eq!(keepalive.deadline(), Some(start + Duration::from_secs(30)));
+
+        advance(&clock, 28);
+        let next = ping(keepalive.tick());
+        assert_ne!(next, nonce);
+        advance(&clock, 10);
+        assert_eq!(keepalive.tick(), Tick::Idle);
+        assert_eq!(keepalive.misses(), 1);
+        assert_eq!(keepalive.on_pong(next), Err(UnsolicitedPong(next)));
+
+        advance(&clock, 20);
+        let nonce = ping(keepalive.tick());
+        advance(&clock, 6);
+        assert_eq!(keepalive.on_pong(nonce), Ok(Duration::from_secs(6)));
+        assert_eq!(keepalive.misses(), 0);
+        let stats = keepalive.stats();
+        assert_eq!(stats.samples, 2);
+        assert_eq!(stats.last, Some(Duration::from_secs(6)));
+        assert_eq!(stats.min, Some(Duration::from_secs(2)));
+        assert_eq!(stats.max, Some(Duration::from_secs(6)));
+        assert_eq!(stats.smoothed, Some(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn dead_peer() {
+        let (mut keepalive, clock) = keepalive();
+        for _ in 0..3 {
+            ping(keepalive.tick());
+            advance(&clock, 30);
+        }
+        assert_eq!(keepalive.tick(), Tick::Dead);
+        assert_eq!(keepalive.misses(), 3);
+    }
+
+    #[test]
+    fn endless_durations() {
+        let clock = Rc::new(Cell::new(Instant::now()));
+        let config = KeepaliveConfig {
+            interval: Duration::MAX,
+            timeout: Duration::MAX,
+            max_misses: 1,
+        };
+        let mut keepalive = Keepalive::with_clock(config, clock.clone());
+        assert_eq!(keepalive.deadline(), Some(clock.get()));
+        ping(keepalive.tick());
+        // Neither the next ping nor expiration of this one ever comes
+        assert_eq!(keepalive.deadline(), None);
+        advance(&clock, 1_000_000);
+        assert_eq!(keepalive.tick(), Tick::Idle);
+        assert_eq!(keepalive.misses(), 0);
+    }
+}
@@ -1,4 +1,23 @@
+pub mod addrbook;
+pub mod annotate;
+#[cfg(feature = "tokio")]
+pub mod async_client;
+pub mod client;
+#[cfg(feature = "tokio")]
+pub mod codec;
+pub mod compression;
+pub mod error;
+pub mod handshake;
+pub mod keepalive;
 pub mod message;
+pub mod onion;
+pub mod parser;
+pub mod scoring;
+#[cfg(feature = "serde")]
+mod serde_util;
+pub mod server;
+pub mod stream;
+pub mod transport;
 pub mod util;
 
 extern crate consensus_encode;
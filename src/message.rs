@@ -1,3 +1,10 @@
+pub use crate::compression::CodecKind;
+use crate::compression::{Gzip, PayloadCodec};
+use crate::error::*;
+pub use crate::onion::OnionV3Address;
+use crate::onion::{base32_decode, base32_encode, OnionAddressError};
+#[cfg(feature = "serde")]
+use crate::serde_util::{hex_bytes, hex_vec, rate};
 use crate::util::*;
 use consensus_encode::util::hex::ToHex;
 pub use consensus_encode::util::stream_reader::StreamReader;
@@ -6,10 +13,14 @@ pub use consensus_encode::{
     VarInt, MAX_VEC_SIZE,
 };
 use fix::aliases::si::Centi;
-use flate2::write::{GzDecoder, GzEncoder};
-use flate2::Compression;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
-use std::io::{Cursor, Write};
+use std::io::{Cursor, Read};
+use std::ops::BitOr;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{io, net};
 
 macro_rules! impl_pure_encodable {
@@ -26,12 +37,19 @@ macro_rules! impl_pure_encodable {
                 Encodable::consensus_encode(&self.$meth_enc(), s)
             }
         }
+        impl EncodedLen for $ty {
+            #[inline]
+            fn encoded_len(&self) -> usize {
+                self.$meth_enc().encoded_len()
+            }
+        }
     };
 }
 
 /// Currencies that protocol aware of, there can be currencies that will never be implemented but
 /// index in protocol is known.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Currency {
     Btc,
     TBtc,
@@ -78,7 +96,48 @@ impl Display for Currency {
     }
 }
 
+/// Parses lowercase ticker like `btc` or `tbtc` and the name that `Display` prints, ignoring case
+impl FromStr for Currency {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let lower = s.to_lowercase();
+        if let Some(i) = lower.strip_prefix("unknown currency ") {
+            let i = i
+                .parse()
+                .map_err(|_| ParseError::new("currency", "index", s))?;
+            return Ok(Currency::from_index(i));
+        }
+        (0..)
+            .map(Currency::from_index)
+            .take_while(|c| !matches!(c, Currency::Unknown(_)))
+            .find(|c| c.ticker() == Some(&lower) || c.to_string().to_lowercase() == lower)
+            .ok_or_else(|| ParseError::new("currency", "ticker", s))
+    }
+}
+
 impl Currency {
+    /// Lowercase ticker, testnet tickers have `t` prefix
+    pub fn ticker(&self) -> Option<&'static str> {
+        match self {
+            Currency::Btc => Some("btc"),
+            Currency::TBtc => Some("tbtc"),
+            Currency::Ergo => Some("erg"),
+            Currency::TErgo => Some("terg"),
+            Currency::UsdtOmni => Some("usdt"),
+            Currency::TUsdtOmni => Some("tusdt"),
+            Currency::Ltc => Some("ltc"),
+            Currency::TLtc => Some("tltc"),
+            Currency::Zec => Some("zec"),
+            Currency::TZec => Some("tzec"),
+            Currency::Cpr => Some("cpr"),
+            Currency::TCpr => Some("tcpr"),
+            Currency::Dash => Some("dash"),
+            Currency::TDash => Some("tdash"),
+            Currency::Unknown(_) => None,
+        }
+    }
+
     pub fn as_index(&self) -> u32 {
         match self {
             Currency::Btc => 0,
@@ -129,7 +188,12 @@ impl Currency {
 }
 impl_pure_encodable!(Currency, unpack, pack);
 
+impl MinEncodedSize for Currency {
+    const MIN_ENCODED_SIZE: usize = 1;
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Ord, PartialOrd, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Fiat {
     Usd,
     Eur,
@@ -148,7 +212,39 @@ impl Display for Fiat {
     }
 }
 
+/// Parses ISO 4217 code like `USD` and the name that `Display` prints, ignoring case
+impl FromStr for Fiat {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let upper = s.to_uppercase();
+        if let Some(i) = upper.strip_prefix("UNKNOWN FIAT CURRENCY ") {
+            let i = i.parse().map_err(|_| ParseError::new("fiat", "index", s))?;
+            return Ok(Fiat::from_index(i));
+        }
+        if upper == "RUR" {
+            // Code of ruble before 1998 denomination, still used by some exchanges
+            return Ok(Fiat::Rub);
+        }
+        (0..)
+            .map(Fiat::from_index)
+            .take_while(|c| !matches!(c, Fiat::Unknown(_)))
+            .find(|c| c.iso_code() == Some(&upper) || c.to_string().to_uppercase() == upper)
+            .ok_or_else(|| ParseError::new("fiat", "ISO code", s))
+    }
+}
+
 impl Fiat {
+    /// Code of ISO 4217
+    pub fn iso_code(&self) -> Option<&'static str> {
+        match self {
+            Fiat::Usd => Some("USD"),
+            Fiat::Eur => Some("EUR"),
+            Fiat::Rub => Some("RUB"),
+            Fiat::Unknown(_) => None,
+        }
+    }
+
     pub fn as_index(&self) -> u32 {
         match self {
             Fiat::Usd => 0,
@@ -177,11 +273,21 @@ impl Fiat {
 }
 impl_pure_encodable!(Fiat, unpack, pack);
 
+impl MinEncodedSize for Fiat {
+    const MIN_ENCODED_SIZE: usize = 1;
+}
+
 #[derive(Clone, PartialEq, Eq, Ord, PartialOrd, Debug, Hash)]
 pub enum Address {
     Ipv4(net::SocketAddrV4),
     Ipv6(net::SocketAddrV6),
-    OnionV3([u8; 56], u16),
+    OnionV3(OnionV3Address, u16),
+    /// I2P destination given by SHA-256 hash of it, as in `.b32.i2p` names. Only addrv2.
+    I2p([u8; 32], u16),
+    /// CJDNS address from `fc00::/8` range. Only addrv2.
+    Cjdns(net::Ipv6Addr, u16),
+    /// DNS host name in lowercase. Only addrv2.
+    Hostname(String, u16),
 }
 
 impl Display for Address {
@@ -189,11 +295,10 @@ impl Display for Address {
         match self {
             Address::Ipv4(addr) => write!(f, "{}", addr),
             Address::Ipv6(addr) => write!(f, "{}", addr),
-            Address::OnionV3(addr, p) => {
-                let str_addr =
-                    String::from_utf8(addr.to_vec()).unwrap_or_else(|_| format!("{:?}", addr));
-                write!(f, "{}:{}", str_addr, p)
-            }
+            Address::OnionV3(addr, p) => write!(f, "{}.onion:{}", addr, p),
+            Address::I2p(hash, p) => write!(f, "{}.b32.i2p:{}", base32_encode(hash), p),
+            Address::Cjdns(ip, p) => write!(f, "cjdns:[{}]:{}", ip, p),
+            Address::Hostname(host, p) => write!(f, "{}:{}", host, p),
         }
     }
 }
@@ -213,33 +318,114 @@ fn ipv6_to_be(addr: [u16; 8]) -> [u16; 8] {
 
 impl Decodable for Address {
     #[inline]
-    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
-        let t: u8 = Decodable::consensus_decode(&mut d)?;
-        match t {
-            0 => {
-                let b: [u8; 4] = Decodable::consensus_decode(&mut d)?;
-                let ip = net::Ipv4Addr::new(b[0], b[1], b[2], b[3]);
-                let p: u16 = Decodable::consensus_decode(&mut d)?;
-                let addr = net::SocketAddrV4::new(ip, p.to_be());
-                Ok(Address::Ipv4(addr))
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Self, Error> {
+        decode_address_v1(d).field("address")
+    }
+}
+
+fn decode_address_v1<D: io::Read>(mut d: D) -> Result<Address, Error> {
+    let t: u8 = Decodable::consensus_decode(&mut d)?;
+    match t {
+        0 => {
+            let b: [u8; 4] = Decodable::consensus_decode(&mut d)?;
+            let ip = net::Ipv4Addr::new(b[0], b[1], b[2], b[3]);
+            let p: u16 = Decodable::consensus_decode(&mut d)?;
+            let addr = net::SocketAddrV4::new(ip, p.to_be());
+            Ok(Address::Ipv4(addr))
+        }
+        1 => {
+            let b: [u16; 8] = ipv6_to_be(Decodable::consensus_decode(&mut d)?);
+            let ip = net::Ipv6Addr::new(b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]);
+            let p: u16 = Decodable::consensus_decode(&mut d)?;
+            let addr = net::SocketAddrV6::new(ip, p.to_be(), 0, 0);
+            Ok(Address::Ipv6(addr))
+        }
+        2 => {
+            let mut b = [0; 56];
+            d.read_exact(&mut b)?;
+            let addr = OnionV3Address::new(b)
+                .map_err(|_| field_error("address", ProtocolErrorKind::InvalidOnionAddress))?;
+            let p: u16 = Decodable::consensus_decode(&mut d)?;
+            Ok(Address::OnionV3(addr, p.to_be()))
+        }
+        _ => Err(field_error(
+            "address",
+            ProtocolErrorKind::UnknownAddressType,
+        )),
+    }
+}
+
+impl EncodedLen for Address {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        match self {
+            Address::Ipv4(_) => 1 + 4 + 2,
+            Address::Ipv6(_) => 1 + 16 + 2,
+            Address::OnionV3(addr, _) => 1 + addr.as_bytes().len() + 2,
+            // Have no encoding of their own here, both `Message::encode_with` and
+            // `Message::encoded_len_with` refuse them. Size of addrv2 encoding is still a size
+            // they take in a payload.
+            Address::I2p(..) | Address::Cjdns(..) | Address::Hostname(..) => self.encoded_len_v2(),
+        }
+    }
+}
+
+impl MinEncodedSize for Address {
+    /// Type byte, IPv4 and port
+    const MIN_ENCODED_SIZE: usize = 7;
+}
+/// Parses `1.2.3.4:8667`, `[::1]:8667`, `<56 characters>.onion:8667`, `<52 characters>.b32.i2p:8667`,
+/// `cjdns:[fc00::1]:8667` and `example.com:8667`, the forms that `Display` prints. Onion addresses
+/// are accepted without the suffix too.
+impl FromStr for Address {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        match s.parse::<net::SocketAddr>() {
+            Ok(net::SocketAddr::V4(addr)) => return Ok(Address::Ipv4(addr)),
+            Ok(net::SocketAddr::V6(addr)) => return Ok(Address::Ipv6(addr)),
+            Err(_) => (),
+        }
+        if let Some(cjdns) = s.strip_prefix("cjdns:") {
+            return match cjdns.parse::<net::SocketAddrV6>() {
+                Ok(addr) if is_cjdns(addr.ip()) => Ok(Address::Cjdns(*addr.ip(), addr.port())),
+                _ => Err(ParseError::new("address", "cjdns host", s)),
+            };
+        }
+        let i = s
+            .rfind(':')
+            .ok_or_else(|| ParseError::new("address", "port", s))?;
+        let (host, port) = (&s[..i], &s[i + 1..]);
+        let port = port
+            .parse()
+            .map_err(|_| ParseError::new("address", "port", s))?;
+        if host.parse::<net::Ipv4Addr>().is_ok() || host.starts_with('[') {
+            // Valid IP with valid port would be parsed as socket address
+            return Err(ParseError::new("address", "host", s));
+        }
+        let lower = host.to_ascii_lowercase();
+        if let Some(i2p) = lower.strip_suffix(".b32.i2p") {
+            return match base32_decode(i2p).map(<[u8; 32]>::try_from) {
+                Some(Ok(hash)) if i2p.len() == 52 => Ok(Address::I2p(hash, port)),
+                _ => Err(ParseError::new("address", "i2p host", s)),
+            };
+        }
+        match host.parse::<OnionV3Address>() {
+            Ok(onion) => Ok(Address::OnionV3(onion, port)),
+            Err(_) if !lower.ends_with(".onion") && is_hostname(&lower) => {
+                Ok(Address::Hostname(lower, port))
             }
-            1 => {
-                let b: [u16; 8] = ipv6_to_be(Decodable::consensus_decode(&mut d)?);
-                let ip = net::Ipv6Addr::new(b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]);
-                let p: u16 = Decodable::consensus_decode(&mut d)?;
-                let addr = net::SocketAddrV6::new(ip, p.to_be(), 0, 0);
-                Ok(Address::Ipv6(addr))
+            Err(OnionAddressError::Checksum) => {
+                Err(ParseError::new("address", "onion checksum", s))
             }
-            2 => {
-                let mut b = [0; 56];
-                d.read_exact(&mut b)?;
-                let p: u16 = Decodable::consensus_decode(&mut d)?;
-                Ok(Address::OnionV3(b, p.to_be()))
+            Err(OnionAddressError::Version(_)) => {
+                Err(ParseError::new("address", "onion version", s))
             }
-            _ => Err(Error::ParseFailed("Unknown address type")),
+            Err(_) => Err(ParseError::new("address", "host", s)),
         }
     }
 }
+
 impl Encodable for Address {
     #[inline]
     fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
@@ -258,18 +444,331 @@ impl Encodable for Address {
                     + Encodable::consensus_encode(&sock.port().to_be(), &mut s)?;
                 Ok(len)
             }
-            Address::OnionV3(b, p) => {
+            Address::OnionV3(addr, p) => {
                 let addr_type: u8 = 2;
-                let len = Encodable::consensus_encode(&addr_type, &mut s)?
-                    + s.write(b)?
-                    + Encodable::consensus_encode(&p.to_be(), &mut s)?;
+                let b = addr.as_bytes();
+                let mut len = Encodable::consensus_encode(&addr_type, &mut s)?;
+                s.write_all(b)?;
+                len += b.len() + Encodable::consensus_encode(&p.to_be(), &mut s)?;
                 Ok(len)
             }
+            Address::I2p(..) | Address::Cjdns(..) | Address::Hostname(..) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                ADDR_V2_REQUIRED,
+            )),
         }
     }
 }
 
+/// Network ids of addrv2 encoding
+const NET_IPV4: u8 = 0;
+const NET_IPV6: u8 = 1;
+const NET_ONION_V3: u8 = 2;
+const NET_I2P: u8 = 3;
+const NET_CJDNS: u8 = 4;
+const NET_HOSTNAME: u8 = 5;
+
+/// Maximum size of address body in addrv2 encoding. Entries of unknown networks are skipped only
+/// within this limit.
+pub const MAX_ADDR_V2_BODY: usize = 512;
+
+/// Network id, empty body and port
+const MIN_ADDR_V2_SIZE: usize = 4;
+
+fn is_cjdns(ip: &net::Ipv6Addr) -> bool {
+    ip.octets()[0] == 0xfc
+}
+
+/// Whether the string is a valid DNS name in lowercase. Names that end with numeric label are
+/// refused, so mistyped IPv4 addresses are not taken for host names.
+fn is_hostname(host: &str) -> bool {
+    let valid_label = |l: &str| {
+        !l.is_empty()
+            && l.len() <= 63
+            && !l.starts_with('-')
+            && !l.ends_with('-')
+            && l.bytes()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-')
+    };
+    let last_label = host.rsplit('.').next().unwrap_or("");
+    let last_numeric = last_label.bytes().all(|c| c.is_ascii_digit());
+    host.len() <= 253 && !last_numeric && host.split('.').all(valid_label)
+}
+
+impl Address {
+    /// Whether the address can be sent in the original encoding understood by all peers
+    pub fn has_v1_encoding(&self) -> bool {
+        matches!(
+            self,
+            Address::Ipv4(_) | Address::Ipv6(_) | Address::OnionV3(..)
+        )
+    }
+
+    /// Call `f` with network id, body and port of addrv2 encoding. Body is borrowed, so
+    /// encoding a long list of addresses doesn't allocate for every one of them.
+    fn with_v2_parts<R, F: FnOnce(u8, &[u8], u16) -> R>(&self, f: F) -> R {
+        match self {
+            Address::Ipv4(addr) => f(NET_IPV4, &addr.ip().octets(), addr.port()),
+            Address::Ipv6(addr) => f(NET_IPV6, &addr.ip().octets(), addr.port()),
+            Address::OnionV3(addr, p) => f(NET_ONION_V3, &addr.public_key(), *p),
+            Address::I2p(hash, p) => f(NET_I2P, hash, *p),
+            Address::Cjdns(ip, p) => f(NET_CJDNS, &ip.octets(), *p),
+            Address::Hostname(host, p) => f(NET_HOSTNAME, host.as_bytes(), *p),
+        }
+    }
+
+    /// Fails unless the address has the original encoding, see `has_v1_encoding`
+    fn check_v1(&self) -> Result<(), io::Error> {
+        if self.has_v1_encoding() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                ADDR_V2_REQUIRED,
+            ))
+        }
+    }
+
+    /// Fails if cjdns address is outside of fc00::/8 or host name is not valid lowercase DNS name
+    fn check_v2(&self) -> Result<(), io::Error> {
+        match self {
+            Address::Cjdns(ip, _) if !is_cjdns(ip) => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, INVALID_ADDRESS))
+            }
+            Address::Hostname(host, _) if !is_hostname(host) => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, INVALID_ADDRESS))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Encode in addrv2 format of `Version::ADDR_V2`: network id, length prefixed body and big
+    /// endian port. Onion addresses are sent as public keys.
+    pub fn encode_v2<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
+        self.check_v2()?;
+        self.with_v2_parts(|net, body, port| -> Result<usize, io::Error> {
+            let len = net.consensus_encode(&mut s)?
+                + VarInt(body.len() as u64).consensus_encode(&mut s)?;
+            s.write_all(body)?;
+            s.write_all(&port.to_be_bytes())?;
+            Ok(len + body.len() + 2)
+        })
+    }
+
+    /// Size of addrv2 encoding
+    pub fn encoded_len_v2(&self) -> usize {
+        self.with_v2_parts(|_, body, _| 1 + bytes_encoded_len(body.len()) + 2)
+    }
+
+    /// Decode address in addrv2 format. Address of unknown network is consumed and `None` is
+    /// returned, so newer peers can announce networks this version doesn't know.
+    pub fn decode_v2<D: io::Read>(d: D) -> Result<Option<Address>, Error> {
+        Address::decode_v2_fields(d).field("address")
+    }
+
+    fn decode_v2_fields<D: io::Read>(mut d: D) -> Result<Option<Address>, Error> {
+        let net: u8 = Decodable::consensus_decode(&mut d)?;
+        let body = decode_bytes(&mut d, MAX_ADDR_V2_BODY)?;
+        let mut port = [0; 2];
+        d.read_exact(&mut port)?;
+        let port = u16::from_be_bytes(port);
+        fn invalid<E>(_: E) -> Error {
+            field_error("address", ProtocolErrorKind::InvalidAddress)
+        }
+        let addr = match net {
+            NET_IPV4 => {
+                let ip = <[u8; 4]>::try_from(&body[..]).map_err(invalid)?;
+                Address::Ipv4(net::SocketAddrV4::new(ip.into(), port))
+            }
+            NET_IPV6 => {
+                let ip = <[u8; 16]>::try_from(&body[..]).map_err(invalid)?;
+                Address::Ipv6(net::SocketAddrV6::new(ip.into(), port, 0, 0))
+            }
+            NET_ONION_V3 => {
+                let key = <[u8; 32]>::try_from(&body[..]).map_err(invalid)?;
+                Address::OnionV3(OnionV3Address::from_public_key(key), port)
+            }
+            NET_I2P => Address::I2p(<[u8; 32]>::try_from(&body[..]).map_err(invalid)?, port),
+            NET_CJDNS => {
+                let ip = net::Ipv6Addr::from(<[u8; 16]>::try_from(&body[..]).map_err(invalid)?);
+                if !is_cjdns(&ip) {
+                    return Err(invalid(()));
+                }
+                Address::Cjdns(ip, port)
+            }
+            NET_HOSTNAME => match String::from_utf8(body) {
+                Ok(host) if is_hostname(&host) => Address::Hostname(host, port),
+                _ => return Err(invalid(())),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(addr))
+    }
+}
+
+/// Addresses in addrv2 encoding with count prefix
+struct AddrV2Vec<'a>(&'a [Address]);
+
+impl Encodable for AddrV2Vec<'_> {
+    fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut s)?;
+        for addr in self.0 {
+            len += addr.encode_v2(&mut s)?;
+        }
+        Ok(len)
+    }
+}
+
+impl EncodedLen for AddrV2Vec<'_> {
+    fn encoded_len(&self) -> usize {
+        VarInt(self.0.len() as u64).len()
+            + self.0.iter().map(Address::encoded_len_v2).sum::<usize>()
+    }
+}
+
+/// Decode addresses in addrv2 encoding that take the rest of the payload skipping unknown
+/// networks
+fn decode_addrs_v2(d: &mut Cursor<&[u8]>) -> Result<Vec<Address>, Error> {
+    let count = VarInt::consensus_decode(&mut *d).field("addresses")?.0;
+    check_amount(count, MIN_ADDR_V2_SIZE, cursor_remaining(d))?;
+    let mut addrs = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if let Some(addr) = Address::decode_v2(&mut *d)? {
+            addrs.push(addr);
+        }
+    }
+    Ok(addrs)
+}
+
+/// Set of services that an indexer provides
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Services(pub u64);
+
+impl Services {
+    pub const NONE: Services = Services(0);
+    /// Block filters, `GetFilters`
+    pub const FILTERS: Services = Services(1);
+    /// Fee estimations, `GetFee`
+    pub const FEES: Services = Services(1 << 1);
+    /// Exchange rates, `GetRates`
+    pub const RATES: Services = Services(1 << 2);
+    /// Mempool filters and transactions, `GetMemFilters` and `GetMempool`
+    pub const MEMPOOL: Services = Services(1 << 3);
+
+    /// Whether all services of `other` are provided
+    pub fn contains(&self, other: Services) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn pack(&self) -> u64 {
+        self.0
+    }
+
+    fn unpack(bits: u64) -> Self {
+        Services(bits)
+    }
+}
+impl_pure_encodable!(Services, unpack, pack);
+
+impl BitOr for Services {
+    type Output = Services;
+
+    fn bitor(self, other: Services) -> Services {
+        Services(self.0 | other.0)
+    }
+}
+
+/// Indexer address with its freshness and capabilities. Address is in addrv2 encoding.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PeerRecord {
+    pub address: Address,
+    /// Last time the indexer was seen online, 0 if unknown
+    pub last_seen: Timestamp,
+    /// Currencies that the indexer serves
+    pub currencies: Vec<Currency>,
+    pub services: Services,
+}
+
+/// Record of a peer that announced only its address, time and capabilities are unknown
+impl From<Address> for PeerRecord {
+    fn from(address: Address) -> Self {
+        PeerRecord {
+            address,
+            last_seen: 0,
+            currencies: vec![],
+            services: Services::NONE,
+        }
+    }
+}
+
+impl From<PeerRecord> for Address {
+    fn from(record: PeerRecord) -> Self {
+        record.address
+    }
+}
+
+impl Display for PeerRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} seen at {} services {:#x} currencies: ",
+            self.address, self.last_seen, self.services.0
+        )?;
+        fmt_vec(&self.currencies, f)
+    }
+}
+
+impl Encodable for PeerRecord {
+    fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
+        let mut len = self.address.encode_v2(&mut s)?;
+        len += self.last_seen.consensus_encode(&mut s)?;
+        len += LengthVecRef(&self.currencies).consensus_encode(&mut s)?;
+        len += self.services.consensus_encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl EncodedLen for PeerRecord {
+    fn encoded_len(&self) -> usize {
+        self.address.encoded_len_v2()
+            + self.last_seen.encoded_len()
+            + LengthVecRef(&self.currencies).encoded_len()
+            + self.services.encoded_len()
+    }
+}
+
+/// Address, time, empty currencies and services
+const MIN_PEER_RECORD_SIZE: usize = MIN_ADDR_V2_SIZE + 8 + 1 + 8;
+
+/// Decode peer records that take the rest of the payload skipping records of unknown networks
+fn decode_peer_records(d: &mut Cursor<&[u8]>) -> Result<Vec<PeerRecord>, Error> {
+    let count = VarInt::consensus_decode(&mut *d).field("records")?.0;
+    check_amount(count, MIN_PEER_RECORD_SIZE, cursor_remaining(d))?;
+    let mut records = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let address = Address::decode_v2(&mut *d)?;
+        let last_seen = Decodable::consensus_decode(&mut *d).field("last seen")?;
+        let remaining = cursor_remaining(d);
+        let currencies = LengthVec::decode_bounded(&mut *d, remaining)
+            .field("currencies")?
+            .0;
+        let services = Decodable::consensus_decode(&mut *d).field("services")?;
+        if let Some(address) = address {
+            records.push(PeerRecord {
+                address,
+                last_seen,
+                currencies,
+                services,
+            });
+        }
+    }
+    Ok(records)
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Version {
     pub major: u16, // used only 10 bits
     pub minor: u16,
@@ -288,16 +787,77 @@ impl Display for Version {
     }
 }
 
+/// Parses `major.minor.patch` form that `Display` prints
+impl FromStr for Version {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let mut parts = s.split('.');
+        let mut next = |part| {
+            parts
+                .next()
+                .and_then(|p| p.parse::<u16>().ok())
+                .filter(|v| *v < 1 << 10)
+                .ok_or_else(|| ParseError::new("version", part, s))
+        };
+        let version = Version {
+            major: next("major")?,
+            minor: next("minor")?,
+            patch: next("patch")?,
+        };
+        match parts.next() {
+            Some(_) => Err(ParseError::new("version", "patch", s)),
+            None => Ok(version),
+        }
+    }
+}
+
 impl Version {
     /// Current implemented version
     pub fn current() -> Self {
         Version {
             major: 2,
-            minor: 0,
+            minor: 3,
             patch: 0,
         }
     }
 
+    /// First version that understands `Message::Tagged`
+    pub const REQUEST_IDS: Version = Version {
+        major: 2,
+        minor: 1,
+        patch: 0,
+    };
+
+    /// Whether peer of this version can be sent tagged requests
+    pub fn supports_request_ids(&self) -> bool {
+        *self >= Version::REQUEST_IDS
+    }
+
+    /// First version that understands `Message::PeersV2` and `Message::PeerIntroduceV2`
+    pub const ADDR_V2: Version = Version {
+        major: 2,
+        minor: 2,
+        patch: 0,
+    };
+
+    /// Whether peer of this version can be sent addresses in addrv2 encoding
+    pub fn supports_addr_v2(&self) -> bool {
+        *self >= Version::ADDR_V2
+    }
+
+    /// First version that understands `Message::GetPeerRecords`
+    pub const PEER_RECORDS: Version = Version {
+        major: 2,
+        minor: 3,
+        patch: 0,
+    };
+
+    /// Whether peer of this version can be asked for peer records
+    pub fn supports_peer_records(&self) -> bool {
+        *self >= Version::PEER_RECORDS
+    }
+
     /// Check whether versions compatible
     pub fn compatible(&self, v: &Self) -> bool {
         self.major == v.major
@@ -329,6 +889,7 @@ impl Version {
 impl_pure_encodable!(Version, unpack_be, pack_be);
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Message {
     Version(VersionMessage),
     VersionAck,
@@ -341,8 +902,8 @@ pub enum Message {
     Fee(Vec<FeeResp>),
     PeerIntroduce(Vec<Address>),
     Reject(RejectMessage),
-    Ping([u8; 8]),
-    Pong([u8; 8]),
+    Ping(#[cfg_attr(feature = "serde", serde(with = "hex_bytes"))] [u8; 8]),
+    Pong(#[cfg_attr(feature = "serde", serde(with = "hex_bytes"))] [u8; 8]),
     GetRates(Vec<RateReq>),
     Rates(Vec<RateResp>),
     FullFilterInv,
@@ -352,6 +913,30 @@ pub enum Message {
     MemFilters(Vec<FilterPrefixPair>),
     GetMempool(Vec<TxPrefix>),
     MempoolChunk(MempoolChunkResp),
+    /// `Peers` in addrv2 encoding that carries any `Address` and lets unknown networks be
+    /// skipped. Must be sent only to peers that support `Version::ADDR_V2`.
+    PeersV2(Vec<Address>),
+    /// `PeerIntroduce` in addrv2 encoding, the same rules as for `PeersV2` apply
+    PeerIntroduceV2(Vec<Address>),
+    /// Request of known indexers with their freshness and capabilities. Must be sent only to
+    /// peers that support `Version::PEER_RECORDS`, older peers are asked with `GetPeers`.
+    GetPeerRecords,
+    /// Reply to `GetPeerRecords`, records of unknown networks are skipped
+    PeerRecords(Vec<PeerRecord>),
+    /// Message with type id that this version of protocol doesn't know. Such messages are kept
+    /// as is to not break connection with newer peers and are encoded back byte-for-byte.
+    Unknown {
+        id: u32,
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+        payload: Vec<u8>,
+    },
+    /// Message with request id. Peers that support `Version::REQUEST_IDS` answer tagged request
+    /// with replies and rejects tagged by the same id. Must not be sent to older peers and can't
+    /// be nested.
+    Tagged {
+        request_id: u64,
+        message: Box<Message>,
+    },
 }
 
 fn fmt_vec<T: Display>(v: &[T], f: &mut Formatter) -> std::fmt::Result {
@@ -374,7 +959,7 @@ impl Display for Message {
             Message::Filters(msg) => msg.fmt(f),
             Message::Filter(msg) => msg.fmt(f),
             Message::GetPeers => write!(f, "reqpeers"),
-            Message::Peers(msg) => {
+            Message::Peers(msg) | Message::PeersV2(msg) => {
                 write!(f, "peers: ")?;
                 fmt_vec(msg, f)
             }
@@ -386,10 +971,15 @@ impl Display for Message {
                 write!(f, "fee: ")?;
                 fmt_vec(msg, f)
             }
-            Message::PeerIntroduce(msg) => {
+            Message::PeerIntroduce(msg) | Message::PeerIntroduceV2(msg) => {
                 write!(f, "peer announce: ")?;
                 fmt_vec(msg, f)
             }
+            Message::GetPeerRecords => write!(f, "reqpeerrecords"),
+            Message::PeerRecords(msg) => {
+                write!(f, "peer records: ")?;
+                fmt_vec(msg, f)
+            }
             Message::Reject(msg) => msg.fmt(f),
             Message::Ping(nonce) => write!(f, "ping {}", nonce.to_hex()),
             Message::Pong(nonce) => write!(f, "pong {}", nonce.to_hex()),
@@ -412,219 +1002,792 @@ impl Display for Message {
                 write!(f, "memfilters: ")?;
                 fmt_vec(msg, f)
             }
-            Message::GetMempool(msg) => {
-                write!(f, "getmempool: ")?;
-                fmt_vec(msg, f)
+            Message::GetMempool(msg) => {
+                write!(f, "getmempool: ")?;
+                fmt_vec(msg, f)
+            }
+            Message::MempoolChunk(msg) => {
+                write!(f, "mempoolchunk: ")?;
+                msg.fmt(f)
+            }
+            Message::Unknown { id, payload } => {
+                write!(f, "unknown message {}: {}", id, payload.to_hex())
+            }
+            Message::Tagged {
+                request_id,
+                message,
+            } => write!(f, "request {}: {}", request_id, message),
+        }
+    }
+}
+
+/// Maximum size of message in bytes
+pub const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Default limit for inflated size of compressed filters and mempool payloads
+pub const MAX_DECOMPRESSED_SIZE: usize = 4 * MAX_MESSAGE_SIZE;
+
+/// Limits applied while decoding messages from untrusted peers
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DecodeOptions {
+    /// Maximum size of compressed part of payload after decompression
+    pub max_decompressed_size: usize,
+    /// Codec of compressed part of payload agreed for the connection
+    pub codec: CodecKind,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            max_decompressed_size: MAX_DECOMPRESSED_SIZE,
+            codec: CodecKind::Gzip,
+        }
+    }
+}
+
+impl DecodeOptions {
+    /// Decompress payload with the agreed codec applying the size limit
+    fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>, Error> {
+        self.codec
+            .codec()
+            .ok_or(ProtocolErrorKind::Decompression)
+            .and_then(|codec| codec.decompress(buf, self.max_decompressed_size))
+            .map_err(|kind| field_error("compressed body", kind))
+    }
+}
+
+/// Check that declared amount of items fits into available bytes
+fn check_amount(amount: u64, min_item_size: usize, available: usize) -> Result<(), Error> {
+    if amount.saturating_mul(min_item_size as u64) > available as u64 {
+        return Err(field_error(
+            "amount",
+            ProtocolErrorKind::AmountExceedsPayload,
+        ));
+    }
+    Ok(())
+}
+
+fn into_io_error(e: ProtocolErrorKind) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Header of message frame: type id and length of payload that follows it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FrameHeader {
+    pub id: u32,
+    pub payload_len: usize,
+    /// Size of encoded id and length in bytes
+    pub header_len: usize,
+}
+
+/// Type id of message from its varint. Larger ids can't be told from the ids they truncate to,
+/// so they fail the header.
+fn message_id(id: u64) -> Result<u32, ProtocolErrorKind> {
+    u32::try_from(id).map_err(|_| ProtocolErrorKind::IdOutOfRange(id))
+}
+
+impl FrameHeader {
+    /// Try to parse frame header from the beginning of the buffer. Returns `Ok(None)` when more
+    /// bytes are required and fails as soon as declared payload exceeds `MAX_MESSAGE_SIZE`.
+    pub fn peek(buf: &[u8]) -> Result<Option<FrameHeader>, ProtocolError> {
+        let (id, id_len) = match peek_varint(buf) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let id = message_id(id).map_err(|kind| ProtocolError::new(None, "id", 0, kind))?;
+        if !Message::has_payload(id) {
+            return Ok(Some(FrameHeader {
+                id,
+                payload_len: 0,
+                header_len: id_len,
+            }));
+        }
+        let (len, len_len) = match peek_varint(&buf[id_len..]) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if len > MAX_MESSAGE_SIZE as u64 {
+            return Err(ProtocolError::new(
+                Some(id),
+                "length",
+                id_len as u64,
+                ProtocolErrorKind::Oversize {
+                    size: len,
+                    max: MAX_MESSAGE_SIZE,
+                },
+            ));
+        }
+        Ok(Some(FrameHeader {
+            id,
+            payload_len: len as usize,
+            header_len: id_len + len_len,
+        }))
+    }
+
+    /// Read frame header from blocking reader, fails as soon as declared payload exceeds
+    /// `MAX_MESSAGE_SIZE`.
+    pub fn read_from<R: io::Read>(mut r: R) -> Result<FrameHeader, ProtocolError> {
+        let id = VarInt::consensus_decode(&mut r)
+            .map_err(|e| ProtocolError::from_encode(None, "id", 0, e))?;
+        let id_len = id.len();
+        let id = message_id(id.0).map_err(|kind| ProtocolError::new(None, "id", 0, kind))?;
+        if !Message::has_payload(id) {
+            return Ok(FrameHeader {
+                id,
+                payload_len: 0,
+                header_len: id_len,
+            });
+        }
+        let len = VarInt::consensus_decode(&mut r)
+            .map_err(|e| ProtocolError::from_encode(Some(id), "length", id_len as u64, e))?;
+        if len.0 > MAX_MESSAGE_SIZE as u64 {
+            return Err(ProtocolError::new(
+                Some(id),
+                "length",
+                id_len as u64,
+                ProtocolErrorKind::Oversize {
+                    size: len.0,
+                    max: MAX_MESSAGE_SIZE,
+                },
+            ));
+        }
+        Ok(FrameHeader {
+            id,
+            payload_len: len.0 as usize,
+            header_len: id_len + len.len(),
+        })
+    }
+
+    /// Full size of the frame including header
+    pub fn frame_len(&self) -> usize {
+        self.header_len + self.payload_len
+    }
+}
+
+impl Message {
+    pub fn id(&self) -> u32 {
+        match self {
+            Message::Version(_) => 0,
+            Message::VersionAck => 1,
+            Message::GetFilters(_) => 2,
+            Message::Filters(_) => 3,
+            Message::Filter(_) => 4,
+            Message::GetPeers => 5,
+            Message::Peers(_) => 6,
+            Message::GetFee(_) => 7,
+            Message::Fee(_) => 8,
+            Message::PeerIntroduce(_) => 9,
+            Message::Reject(_) => 10,
+            Message::Ping(_) => 11,
+            Message::Pong(_) => 12,
+            Message::GetRates(_) => 13,
+            Message::Rates(_) => 14,
+            Message::FullFilterInv => 15,
+            Message::GetFullFilter => 16,
+            Message::FullFilter(_) => 17,
+            Message::GetMemFilters => 18,
+            Message::MemFilters(_) => 19,
+            Message::GetMempool(_) => 20,
+            Message::MempoolChunk(_) => 21,
+            Message::PeersV2(_) => 23,
+            Message::PeerIntroduceV2(_) => 24,
+            Message::GetPeerRecords => 25,
+            Message::PeerRecords(_) => 26,
+            Message::Unknown { id, .. } => *id,
+            Message::Tagged { .. } => 22,
+        }
+    }
+
+    /// Reply to `GetPeers` in the encoding that the peer understands. Addresses that the
+    /// original encoding can't carry are left out for older peers.
+    pub fn peers(addrs: Vec<Address>, addr_v2: bool) -> Message {
+        if addr_v2 {
+            Message::PeersV2(addrs)
+        } else {
+            Message::Peers(addrs.into_iter().filter(Address::has_v1_encoding).collect())
+        }
+    }
+
+    /// Announcement of peers in the encoding that the peer understands, like `Message::peers`
+    pub fn peer_introduce(addrs: Vec<Address>, addr_v2: bool) -> Message {
+        if addr_v2 {
+            Message::PeerIntroduceV2(addrs)
+        } else {
+            Message::PeerIntroduce(addrs.into_iter().filter(Address::has_v1_encoding).collect())
+        }
+    }
+
+    /// Addresses carried by peers messages of either encoding
+    pub fn addresses(&self) -> Option<&[Address]> {
+        match self {
+            Message::Peers(addrs)
+            | Message::PeerIntroduce(addrs)
+            | Message::PeersV2(addrs)
+            | Message::PeerIntroduceV2(addrs) => Some(addrs),
+            _ => None,
+        }
+    }
+
+    /// Wrap message with request id
+    pub fn tagged(self, request_id: u64) -> Message {
+        Message::Tagged {
+            request_id,
+            message: Box::new(self),
+        }
+    }
+
+    /// Split request id from the message, it is `None` for untagged messages
+    pub fn untagged(self) -> (Option<u64>, Message) {
+        match self {
+            Message::Tagged {
+                request_id,
+                message,
+            } => (Some(request_id), *message),
+            msg => (None, msg),
+        }
+    }
+
+    /// Check whether message with given type id carries length prefixed payload
+    pub fn has_payload(id: u32) -> bool {
+        !matches!(id, 1 | 5 | 15 | 16 | 18)
+    }
+
+    /// Get human readable name of message by its type id
+    pub fn name_from_id(i: u32) -> Option<&'static str> {
+        match i {
+            0 => Some("version"),
+            1 => Some("version ack"),
+            2 => Some("req filters"),
+            3 => Some("filters"),
+            4 => Some("filter"),
+            5 => Some("req peers"),
+            6 => Some("peers"),
+            7 => Some("req fee"),
+            8 => Some("fee"),
+            9 => Some("peer announce"),
+            10 => Some("reject"),
+            11 => Some("ping"),
+            12 => Some("pong"),
+            13 => Some("req rates"),
+            14 => Some("rates"),
+            15 => Some("full filter inv"),
+            17 => Some("full fulter"),
+            18 => Some("get mempool filters"),
+            19 => Some("mempool filters"),
+            20 => Some("get mempool"),
+            21 => Some("mempool chunk"),
+            22 => Some("tagged"),
+            23 => Some("peers v2"),
+            24 => Some("peer announce v2"),
+            25 => Some("req peer records"),
+            26 => Some("peer records"),
+            _ => None,
+        }
+    }
+}
+
+impl Encodable for Message {
+    #[inline]
+    fn consensus_encode<S: io::Write>(&self, s: S) -> Result<usize, io::Error> {
+        self.encode_with(s, CodecKind::Gzip)
+    }
+}
+
+/// Payload with its compressed part prepared in advance, so the payload length is known before
+/// anything is written.
+struct Compressed<'a, T> {
+    payload: &'a T,
+    body: Vec<u8>,
+}
+
+impl<'a> Compressed<'a, FiltersResp> {
+    fn filters(payload: &'a FiltersResp, codec: CodecKind) -> Result<Self, io::Error> {
+        let body = FiltersResp::compress_with(payload.filters.iter(), codec.require()?)?;
+        Ok(Compressed { payload, body })
+    }
+}
+
+impl Encodable for Compressed<'_, FiltersResp> {
+    fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
+        let mut len = 0;
+        len += self.payload.currency.consensus_encode(&mut s)?;
+        len += VarInt(self.payload.filters.len() as u64).consensus_encode(&mut s)?;
+        s.write_all(&self.body)?;
+        len += self.body.len();
+        Ok(len)
+    }
+}
+
+impl EncodedLen for Compressed<'_, FiltersResp> {
+    fn encoded_len(&self) -> usize {
+        self.payload.currency.encoded_len()
+            + VarInt(self.payload.filters.len() as u64).len()
+            + self.body.len()
+    }
+}
+
+impl<'a> Compressed<'a, MempoolChunkResp> {
+    fn mempool(payload: &'a MempoolChunkResp, codec: CodecKind) -> Result<Self, io::Error> {
+        let body = MempoolChunkResp::compress_with(payload.txs.iter(), codec.require()?)?;
+        Ok(Compressed { payload, body })
+    }
+}
+
+impl Encodable for Compressed<'_, MempoolChunkResp> {
+    fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
+        let mut len = 0;
+        len += self.payload.prefix.consensus_encode(&mut s)?;
+        len += VarInt(self.payload.txs.len() as u64).consensus_encode(&mut s)?;
+        s.write_all(&self.body)?;
+        len += self.body.len();
+        Ok(len)
+    }
+}
+
+impl EncodedLen for Compressed<'_, MempoolChunkResp> {
+    fn encoded_len(&self) -> usize {
+        self.payload.prefix.encoded_len()
+            + VarInt(self.payload.txs.len() as u64).len()
+            + self.body.len()
+    }
+}
+
+/// Payload of tagged message with encoded frame of the wrapped one
+struct TaggedFrame {
+    request_id: u64,
+    frame: Vec<u8>,
+}
+
+impl TaggedFrame {
+    fn new(request_id: u64, message: &Message, codec: CodecKind) -> Result<Self, io::Error> {
+        if let Message::Tagged { .. } = message {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, NESTED_TAGGED));
+        }
+        let mut frame = vec![];
+        message.encode_with(&mut frame, codec)?;
+        Ok(TaggedFrame { request_id, frame })
+    }
+}
+
+impl Encodable for TaggedFrame {
+    fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
+        let len = VarInt(self.request_id).consensus_encode(&mut s)?;
+        s.write_all(&self.frame)?;
+        Ok(len + self.frame.len())
+    }
+}
+
+impl EncodedLen for TaggedFrame {
+    fn encoded_len(&self) -> usize {
+        VarInt(self.request_id).len() + self.frame.len()
+    }
+}
+
+/// Write frame with length prefixed payload directly into the sink
+fn write_frame<S, T>(mut s: S, id: u32, v: &T) -> Result<usize, io::Error>
+where
+    S: io::Write,
+    T: Encodable + EncodedLen + ?Sized,
+{
+    let payload_len = v.encoded_len();
+    let mut len = 0;
+    len += VarInt(id as u64).consensus_encode(&mut s)?;
+    len += VarInt(payload_len as u64).consensus_encode(&mut s)?;
+    let written = v.consensus_encode(&mut s)?;
+    debug_assert_eq!(written, payload_len, "encoded_len doesn't match encoding");
+    len += written;
+    Ok(len)
+}
+
+impl Message {
+    /// Encode message compressing filters and mempool payloads with codec agreed for the
+    /// connection. Fails if the codec is not compiled in. Payloads are compressed and checked,
+    /// including addresses that can't be sent in the encoding of the message, before anything is
+    /// written to the sink.
+    pub fn encode_with<S: io::Write>(
+        &self,
+        mut s: S,
+        codec: CodecKind,
+    ) -> Result<usize, io::Error> {
+        let id = self.id();
+        match self {
+            Message::Version(msg) => write_frame(&mut s, id, msg),
+            Message::GetFilters(msg) => write_frame(&mut s, id, msg),
+            Message::Filters(msg) => write_frame(&mut s, id, &Compressed::filters(msg, codec)?),
+            Message::Filter(msg) => write_frame(&mut s, id, msg),
+            Message::Peers(msg) => {
+                msg.iter().try_for_each(Address::check_v1)?;
+                write_frame(&mut s, id, &LengthVecRef(msg))
+            }
+            Message::GetFee(msg) => write_frame(&mut s, id, &LengthVecRef(msg)),
+            Message::Fee(msg) => {
+                msg.iter().try_for_each(FeeResp::check_currency)?;
+                write_frame(&mut s, id, &LengthVecRef(msg))
+            }
+            Message::PeerIntroduce(msg) => {
+                msg.iter().try_for_each(Address::check_v1)?;
+                write_frame(&mut s, id, &LengthVecRef(msg))
+            }
+            Message::Reject(msg) => write_frame(&mut s, id, msg),
+            Message::Ping(msg) => write_frame(&mut s, id, msg),
+            Message::Pong(msg) => write_frame(&mut s, id, msg),
+            Message::GetRates(msg) => write_frame(&mut s, id, &LengthVecRef(msg)),
+            Message::Rates(msg) => write_frame(&mut s, id, &LengthVecRef(msg)),
+            Message::FullFilter(msg) => write_frame(&mut s, id, msg),
+            Message::MemFilters(msg) => write_frame(&mut s, id, &LengthVecRef(msg)),
+            Message::GetMempool(msg) => write_frame(&mut s, id, &LengthVecRef(msg)),
+            Message::MempoolChunk(msg) => {
+                write_frame(&mut s, id, &Compressed::mempool(msg, codec)?)
+            }
+            Message::PeersV2(msg) => {
+                msg.iter().try_for_each(Address::check_v2)?;
+                write_frame(&mut s, id, &AddrV2Vec(msg))
+            }
+            Message::PeerIntroduceV2(msg) => {
+                msg.iter().try_for_each(Address::check_v2)?;
+                write_frame(&mut s, id, &AddrV2Vec(msg))
+            }
+            Message::PeerRecords(msg) => {
+                msg.iter().try_for_each(|r| r.address.check_v2())?;
+                write_frame(&mut s, id, &LengthVecRef(msg))
+            }
+            Message::Unknown { payload, .. } => {
+                Message::check_unknown_id(id)?;
+                let len = VarInt(id as u64).consensus_encode(&mut s)?;
+                Ok(len + payload.consensus_encode(&mut s)?)
+            }
+            Message::Tagged {
+                request_id,
+                message,
+            } => write_frame(&mut s, id, &TaggedFrame::new(*request_id, message, codec)?),
+            // Empty payload is length prefixed, so peers that don't know the message skip it
+            Message::GetPeerRecords => {
+                let len = VarInt(id as u64).consensus_encode(&mut s)?;
+                Ok(len + VarInt(0).consensus_encode(&mut s)?)
+            }
+            Message::VersionAck
+            | Message::GetPeers
+            | Message::FullFilterInv
+            | Message::GetFullFilter
+            | Message::GetMemFilters => VarInt(id as u64).consensus_encode(&mut s),
+        }
+    }
+
+    /// Size of payload without its length prefix, `None` for messages without payload. Filters
+    /// and mempool payloads are compressed with given codec to learn their size.
+    fn payload_len(&self, codec: CodecKind) -> Result<Option<usize>, io::Error> {
+        let len = match self {
+            Message::Version(msg) => Some(msg.encoded_len()),
+            Message::GetFilters(msg) => Some(msg.encoded_len()),
+            Message::Filters(msg) => Some(Compressed::filters(msg, codec)?.encoded_len()),
+            Message::Filter(msg) => Some(msg.encoded_len()),
+            Message::Peers(msg) | Message::PeerIntroduce(msg) => {
+                msg.iter().try_for_each(Address::check_v1)?;
+                Some(LengthVecRef(msg).encoded_len())
+            }
+            Message::GetFee(msg) => Some(LengthVecRef(msg).encoded_len()),
+            Message::Fee(msg) => Some(LengthVecRef(msg).encoded_len()),
+            Message::Reject(msg) => Some(msg.encoded_len()),
+            Message::Ping(msg) => Some(msg.encoded_len()),
+            Message::Pong(msg) => Some(msg.encoded_len()),
+            Message::GetRates(msg) => Some(LengthVecRef(msg).encoded_len()),
+            Message::Rates(msg) => Some(LengthVecRef(msg).encoded_len()),
+            Message::FullFilter(msg) => Some(msg.encoded_len()),
+            Message::MemFilters(msg) => Some(LengthVecRef(msg).encoded_len()),
+            Message::GetMempool(msg) => Some(LengthVecRef(msg).encoded_len()),
+            Message::MempoolChunk(msg) => Some(Compressed::mempool(msg, codec)?.encoded_len()),
+            Message::PeersV2(msg) | Message::PeerIntroduceV2(msg) => {
+                msg.iter().try_for_each(Address::check_v2)?;
+                Some(AddrV2Vec(msg).encoded_len())
+            }
+            Message::PeerRecords(msg) => {
+                msg.iter().try_for_each(|r| r.address.check_v2())?;
+                Some(LengthVecRef(msg).encoded_len())
+            }
+            Message::Unknown { id, payload } => {
+                Message::check_unknown_id(*id)?;
+                Some(payload.len())
+            }
+            Message::Tagged {
+                request_id,
+                message,
+            } => Some(VarInt(*request_id).len() + message.encoded_len_with(codec)?),
+            Message::GetPeerRecords => Some(0),
+            Message::VersionAck
+            | Message::GetPeers
+            | Message::FullFilterInv
+            | Message::GetFullFilter
+            | Message::GetMemFilters => None,
+        };
+        Ok(len)
+    }
+
+    /// Known ids are framed by their own variants. `Unknown` with such id would be written with
+    /// a length prefix that peers don't expect for messages without payload.
+    fn check_unknown_id(id: u32) -> Result<(), io::Error> {
+        match Message::name_from_id(id) {
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, KNOWN_ID)),
+            None => Ok(()),
+        }
+    }
+
+    /// Size of the whole frame as `encode_with` writes it. Fails in the same cases as encoding.
+    pub fn encoded_len_with(&self, codec: CodecKind) -> Result<usize, io::Error> {
+        let id_len = VarInt(self.id() as u64).len();
+        Ok(match self.payload_len(codec)? {
+            Some(len) => id_len + bytes_encoded_len(len),
+            None => id_len,
+        })
+    }
+}
+
+impl Message {
+    /// Decode payload of message with given type id. The whole payload must be consumed. On
+    /// failure returns offset in the payload where decoding stopped together with the error.
+    pub(crate) fn decode_payload(
+        id: u32,
+        buf: &[u8],
+        options: &DecodeOptions,
+    ) -> Result<Message, (u64, FieldError)> {
+        let mut d = Cursor::new(buf);
+        let res = Message::decode_payload_from(id, &mut d, options);
+        let offset = d.position();
+        match res {
+            Ok(_) if offset != buf.len() as u64 => Err((
+                offset,
+                FieldError::new("payload", ProtocolErrorKind::TrailingBytes),
+            )),
+            Ok(msg) => Ok(msg),
+            Err(e) => Err((offset, FieldError::from_decode("payload", e))),
+        }
+    }
+
+    fn decode_payload_from(
+        id: u32,
+        d: &mut Cursor<&[u8]>,
+        options: &DecodeOptions,
+    ) -> Result<Message, Error> {
+        let msg = match id {
+            0 => {
+                let remaining = cursor_remaining(d);
+                Message::Version(VersionMessage::decode_bounded(&mut *d, remaining)?)
+            }
+            1 => Message::VersionAck,
+            2 => Message::GetFilters(Decodable::consensus_decode(&mut *d)?),
+            3 => Message::Filters(FiltersResp::decode_with(&mut *d, options)?),
+            4 => Message::Filter(Decodable::consensus_decode(&mut *d)?),
+            5 => Message::GetPeers,
+            6 => Message::Peers(decode_vec(d).field("addresses")?),
+            7 => Message::GetFee(decode_vec(d).field("currencies")?),
+            8 => Message::Fee(decode_vec(d).field("fees")?),
+            9 => Message::PeerIntroduce(decode_vec(d).field("addresses")?),
+            10 => Message::Reject(Decodable::consensus_decode(&mut *d)?),
+            11 | 12 => {
+                let nonce = Decodable::consensus_decode(&mut *d).field("nonce")?;
+                // Nonce is the only field, tail of payload is ignored
+                d.set_position(d.get_ref().len() as u64);
+                if id == 11 {
+                    Message::Ping(nonce)
+                } else {
+                    Message::Pong(nonce)
+                }
+            }
+            13 => {
+                let reqs = decode_nested(d, |d, left| RateReq::decode_bounded(d, left));
+                Message::GetRates(reqs.field("requests")?)
+            }
+            14 => {
+                let rates = decode_nested(d, |d, left| RateResp::decode_bounded(d, left));
+                Message::Rates(rates.field("rates")?)
             }
-            Message::MempoolChunk(msg) => {
-                write!(f, "mempoolchunk: ")?;
-                msg.fmt(f)
+            15 => Message::FullFilterInv,
+            16 => Message::GetFullFilter,
+            17 => Message::FullFilter(Decodable::consensus_decode(&mut *d).field("filter")?),
+            18 => Message::GetMemFilters,
+            19 => Message::MemFilters(decode_vec(d).field("filters")?),
+            20 => Message::GetMempool(decode_vec(d).field("prefixes")?),
+            21 => Message::MempoolChunk(MempoolChunkResp::decode_with(&mut *d, options)?),
+            22 => {
+                let request_id = VarInt::consensus_decode(&mut *d).field("request id")?.0;
+                Message::Tagged {
+                    request_id,
+                    message: Box::new(decode_tagged(d, request_id, options)?),
+                }
             }
-        }
+            23 => Message::PeersV2(decode_addrs_v2(d).field("addresses")?),
+            24 => Message::PeerIntroduceV2(decode_addrs_v2(d).field("addresses")?),
+            25 => Message::GetPeerRecords,
+            26 => Message::PeerRecords(decode_peer_records(d).field("records")?),
+            _ => {
+                let mut payload = vec![];
+                d.read_to_end(&mut payload)?;
+                Message::Unknown { id, payload }
+            }
+        };
+        Ok(msg)
     }
-}
 
-/// Maximum size of message in bytes
-pub const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+    /// Decode message from buffer that contains exactly one frame. Unlike `deserialize` reports
+    /// where and why decoding failed.
+    pub fn from_frame(frame: &[u8]) -> Result<Message, ProtocolError> {
+        Message::from_frame_with(frame, &DecodeOptions::default())
+    }
 
-impl Message {
-    pub fn id(&self) -> u32 {
-        match self {
-            Message::Version(_) => 0,
-            Message::VersionAck => 1,
-            Message::GetFilters(_) => 2,
-            Message::Filters(_) => 3,
-            Message::Filter(_) => 4,
-            Message::GetPeers => 5,
-            Message::Peers(_) => 6,
-            Message::GetFee(_) => 7,
-            Message::Fee(_) => 8,
-            Message::PeerIntroduce(_) => 9,
-            Message::Reject(_) => 10,
-            Message::Ping(_) => 11,
-            Message::Pong(_) => 12,
-            Message::GetRates(_) => 13,
-            Message::Rates(_) => 14,
-            Message::FullFilterInv => 15,
-            Message::GetFullFilter => 16,
-            Message::FullFilter(_) => 17,
-            Message::GetMemFilters => 18,
-            Message::MemFilters(_) => 19,
-            Message::GetMempool(_) => 20,
-            Message::MempoolChunk(_) => 21,
+    /// Decode message from buffer that contains exactly one frame applying given limits
+    pub fn from_frame_with(
+        frame: &[u8],
+        options: &DecodeOptions,
+    ) -> Result<Message, ProtocolError> {
+        let header = match FrameHeader::peek(frame)? {
+            Some(header) => header,
+            None => {
+                let (id, field) = match peek_varint(frame) {
+                    Some((id, _)) => (Some(id as u32), "length"),
+                    None => (None, "id"),
+                };
+                return Err(ProtocolError::new(
+                    id,
+                    field,
+                    frame.len() as u64,
+                    ProtocolErrorKind::Truncated,
+                ));
+            }
+        };
+        if frame.len() < header.frame_len() {
+            return Err(ProtocolError::new(
+                Some(header.id),
+                "payload",
+                frame.len() as u64,
+                ProtocolErrorKind::Truncated,
+            ));
         }
+        if frame.len() > header.frame_len() {
+            return Err(ProtocolError::new(
+                Some(header.id),
+                "payload",
+                header.frame_len() as u64,
+                ProtocolErrorKind::TrailingBytes,
+            ));
+        }
+        Message::decode_payload(header.id, &frame[header.header_len..], options).map_err(
+            |(offset, e)| ProtocolError::payload(header.id, (header.header_len as u64) + offset, e),
+        )
     }
 
-    /// Get human readable name of message by its type id
-    pub fn name_from_id(i: u32) -> Option<&'static str> {
-        match i {
-            0 => Some("version"),
-            1 => Some("version ack"),
-            2 => Some("req filters"),
-            3 => Some("filters"),
-            4 => Some("filter"),
-            5 => Some("req peers"),
-            6 => Some("peers"),
-            7 => Some("req fee"),
-            8 => Some("fee"),
-            9 => Some("peer announce"),
-            10 => Some("reject"),
-            11 => Some("ping"),
-            12 => Some("pong"),
-            13 => Some("req rates"),
-            14 => Some("rates"),
-            15 => Some("full filter inv"),
-            17 => Some("full fulter"),
-            18 => Some("get mempool filters"),
-            19 => Some("mempool filters"),
-            20 => Some("get mempool"),
-            21 => Some("mempool chunk"),
-            _ => None,
-        }
+    /// Read next message from blocking reader. Unlike `consensus_decode` reports where and why
+    /// decoding failed.
+    pub fn read_from<R: io::Read>(r: R) -> Result<Message, ProtocolError> {
+        Message::read_from_with(r, &DecodeOptions::default())
+    }
+
+    /// Read next message from blocking reader applying given limits
+    pub fn read_from_with<R: io::Read>(
+        mut r: R,
+        options: &DecodeOptions,
+    ) -> Result<Message, ProtocolError> {
+        let header = FrameHeader::read_from(&mut r)?;
+        Message::read_payload_with(&header, r, options)
+    }
+
+    /// Read payload of the frame which header is already read from the reader
+    pub fn read_payload_with<R: io::Read>(
+        header: &FrameHeader,
+        mut r: R,
+        options: &DecodeOptions,
+    ) -> Result<Message, ProtocolError> {
+        let offset = header.header_len as u64;
+        let mut buf = vec![0; header.payload_len];
+        r.read_exact(&mut buf).map_err(|e| {
+            ProtocolError::from_encode(Some(header.id), "payload", offset, e.into())
+        })?;
+        Message::decode_payload(header.id, &buf, options).map_err(|(payload_offset, e)| {
+            ProtocolError::payload(header.id, offset + payload_offset, e)
+        })
     }
 }
 
-impl Encodable for Message {
-    #[inline]
-    fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
-        fn write_payload<S, T>(mut s: &mut S, v: &T) -> Result<usize, io::Error>
-        where
-            S: io::Write,
-            T: Encodable + ?Sized,
-        {
-            let payload = serialize(v);
-            let mut len = 0;
-            len += VarInt(payload.len() as u64).consensus_encode(&mut s)?;
-            len += s.write(&payload)?;
-            Ok(len)
+/// Decode frame of tagged message that takes the rest of the payload. Failures after the header
+/// of the frame carry the request id, so the reject can be tagged.
+fn decode_tagged(
+    d: &mut Cursor<&[u8]>,
+    request_id: u64,
+    options: &DecodeOptions,
+) -> Result<Message, Error> {
+    let start = d.position() as usize;
+    let rest = &d.get_ref()[start..];
+    let header = match FrameHeader::peek(rest) {
+        Ok(Some(header)) => header,
+        Ok(None) => return Err(field_error("message", ProtocolErrorKind::Truncated)),
+        Err(e) => return Err(field_error("message", e.kind)),
+    };
+    if header.id == 22 {
+        return Err(field_error("message", ProtocolErrorKind::NestedTagged));
+    }
+    if rest.len() < header.frame_len() {
+        d.set_position(d.get_ref().len() as u64);
+        let e = FieldError::new("message", ProtocolErrorKind::Truncated);
+        return Err(e.tagged(request_id, header.id).into());
+    }
+    let payload = &rest[header.header_len..header.frame_len()];
+    match Message::decode_payload(header.id, payload, options) {
+        Ok(msg) => {
+            d.set_position((start + header.frame_len()) as u64);
+            Ok(msg)
         }
-
-        let mut len = 0;
-        len += VarInt(self.id() as u64).consensus_encode(&mut s)?;
-        match self {
-            Message::Version(msg) => len += write_payload(&mut s, msg)?,
-            Message::VersionAck => (),
-            Message::GetFilters(msg) => len += write_payload(&mut s, msg)?,
-            Message::Filters(msg) => len += write_payload(&mut s, msg)?,
-            Message::Filter(msg) => len += write_payload(&mut s, msg)?,
-            Message::GetPeers => (),
-            Message::Peers(msg) => len += write_payload(&mut s, &LengthVecRef(msg))?,
-            Message::GetFee(msg) => len += write_payload(&mut s, &LengthVecRef(msg))?,
-            Message::Fee(msg) => len += write_payload(&mut s, &LengthVecRef(msg))?,
-            Message::PeerIntroduce(msg) => len += write_payload(&mut s, &LengthVecRef(msg))?,
-            Message::Reject(msg) => len += write_payload(&mut s, msg)?,
-            Message::Ping(msg) => len += write_payload(&mut s, msg)?,
-            Message::Pong(msg) => len += write_payload(&mut s, msg)?,
-            Message::GetRates(msg) => len += write_payload(&mut s, &LengthVecRef(msg))?,
-            Message::Rates(msg) => len += write_payload(&mut s, &LengthVecRef(msg))?,
-            Message::FullFilterInv => (),
-            Message::GetFullFilter => (),
-            Message::FullFilter(msg) => len += write_payload(&mut s, msg)?,
-            Message::GetMemFilters => (),
-            Message::MemFilters(msg) => len += write_payload(&mut s, &LengthVecRef(msg))?,
-            Message::GetMempool(msg) => len += write_payload(&mut s, &LengthVecRef(msg))?,
-            Message::MempoolChunk(msg) => len += write_payload(&mut s, msg)?,
+        Err((offset, e)) => {
+            d.set_position((start + header.header_len) as u64 + offset);
+            Err(e.tagged(request_id, header.id).into())
         }
-        Ok(len)
     }
 }
 
+/// Decode vector that takes the rest of the payload
+fn decode_vec<T: Decodable + MinEncodedSize>(d: &mut Cursor<&[u8]>) -> Result<Vec<T>, Error> {
+    let remaining = cursor_remaining(d);
+    Ok(LengthVec::decode_bounded(d, remaining)?.0)
+}
+
+/// Decode vector that takes the rest of the payload which elements hold vectors themselves.
+/// Each element is decoded by `decode` with the amount of bytes left in the payload.
+fn decode_nested<T: MinEncodedSize>(
+    d: &mut Cursor<&[u8]>,
+    decode: impl Fn(&mut Cursor<&[u8]>, usize) -> Result<T, Error>,
+) -> Result<Vec<T>, Error> {
+    let remaining = cursor_remaining(d);
+    LengthVec::decode_bounded_with(d, remaining, |d| {
+        let d = &mut **d;
+        let remaining = cursor_remaining(d);
+        decode(d, remaining)
+    })
+    .map(|v| v.0)
+}
+
 impl Decodable for Message {
     #[inline]
     fn consensus_decode<D: ::std::io::Read>(mut d: D) -> Result<Message, consensus_encode::Error> {
-        fn read_payload<F, D>(mut d: &mut D, mut f: F) -> Result<Message, consensus_encode::Error>
-        where
-            F: FnMut(&mut [u8]) -> Result<Message, consensus_encode::Error>,
-            D: io::Read,
-        {
-            let len = VarInt::consensus_decode(&mut d)?.0 as u32;
-            if len as usize > MAX_MESSAGE_SIZE {
-                Err(Error::ParseFailed("Message size is too large"))
-            } else {
-                let mut buf = vec![0; len as usize];
-                d.read_exact(&mut buf)?;
-                f(&mut buf)
-            }
-        }
-
-        let id = VarInt::consensus_decode(&mut d)?.0 as u32;
-        match id {
-            0 => read_payload(&mut d, |buf| {
-                Ok(Message::Version(deserialize::<VersionMessage>(&buf)?))
-            }),
-            1 => Ok(Message::VersionAck),
-            2 => read_payload(&mut d, |buf| {
-                Ok(Message::GetFilters(deserialize::<FiltersReq>(&buf)?))
-            }),
-            3 => read_payload(&mut d, |buf| {
-                Ok(Message::Filters(deserialize::<FiltersResp>(&buf)?))
-            }),
-            4 => read_payload(&mut d, |buf| {
-                Ok(Message::Filter(deserialize::<FilterEvent>(&buf)?))
-            }),
-            5 => Ok(Message::GetPeers),
-            6 => read_payload(&mut d, |buf| {
-                Ok(Message::Peers(deserialize::<LengthVec<Address>>(&buf)?.0))
-            }),
-            7 => read_payload(&mut d, |buf| {
-                Ok(Message::GetFee(deserialize::<LengthVec<Currency>>(&buf)?.0))
-            }),
-            8 => read_payload(&mut d, |buf| {
-                Ok(Message::Fee(deserialize::<LengthVec<FeeResp>>(&buf)?.0))
-            }),
-            9 => read_payload(&mut d, |buf| {
-                Ok(Message::PeerIntroduce(
-                    deserialize::<LengthVec<Address>>(&buf)?.0,
-                ))
-            }),
-            10 => read_payload(&mut d, |buf| {
-                Ok(Message::Reject(deserialize::<RejectMessage>(&buf)?))
-            }),
-            11 => read_payload(&mut d, |buf| {
-                let mut nonce: [u8; 8] = Default::default();
-                nonce.copy_from_slice(&buf[0..8]);
-                Ok(Message::Ping(nonce))
-            }),
-            12 => read_payload(&mut d, |buf| {
-                let mut nonce: [u8; 8] = Default::default();
-                nonce.copy_from_slice(&buf[0..8]);
-                Ok(Message::Pong(nonce))
-            }),
-            13 => read_payload(&mut d, |buf| {
-                Ok(Message::GetRates(
-                    deserialize::<LengthVec<RateReq>>(&buf)?.0,
-                ))
-            }),
-            14 => read_payload(&mut d, |buf| {
-                Ok(Message::Rates(deserialize::<LengthVec<RateResp>>(&buf)?.0))
-            }),
-            15 => Ok(Message::FullFilterInv),
-            16 => Ok(Message::GetFullFilter),
-            17 => read_payload(&mut d, |buf| {
-                Ok(Message::FullFilter(deserialize::<MemFilter>(&buf)?))
-            }),
-            18 => Ok(Message::GetMemFilters),
-            19 => read_payload(&mut d, |buf| {
-                Ok(Message::MemFilters(
-                    deserialize::<LengthVec<FilterPrefixPair>>(&buf)?.0,
-                ))
-            }),
-            20 => read_payload(&mut d, |buf| {
-                Ok(Message::GetMempool(
-                    deserialize::<LengthVec<TxPrefix>>(&buf)?.0,
-                ))
-            }),
-            21 => read_payload(&mut d, |buf| {
-                Ok(Message::MempoolChunk(deserialize::<MempoolChunkResp>(
-                    &buf,
-                )?))
-            }),
-            _ => Err(Error::ParseFailed("Unknown message type")),
+        let id = VarInt::consensus_decode(&mut d)?.0;
+        let id = message_id(id).map_err(|kind| field_error("id", kind))?;
+        let mut buf = vec![];
+        if Message::has_payload(id) {
+            let len = VarInt::consensus_decode(&mut d)?.0;
+            if len > MAX_MESSAGE_SIZE as u64 {
+                let kind = ProtocolErrorKind::Oversize {
+                    size: len,
+                    max: MAX_MESSAGE_SIZE,
+                };
+                return Err(field_error("length", kind));
+            }
+            buf.resize(len as usize, 0);
+            d.read_exact(&mut buf)?;
         }
+        Message::decode_payload(id, &buf, &DecodeOptions::default()).map_err(|(_, e)| e.into())
     }
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ScanBlock {
     pub currency: Currency,
     pub version: Version,
@@ -654,6 +1817,16 @@ impl Encodable for ScanBlock {
     }
 }
 
+impl EncodedLen for ScanBlock {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        self.currency.encoded_len()
+            + self.version.encoded_len()
+            + VarInt(self.scan_height).len()
+            + VarInt(self.height).len()
+    }
+}
+
 impl Decodable for ScanBlock {
     #[inline]
     fn consensus_decode<D: ::std::io::Read>(
@@ -668,10 +1841,21 @@ impl Decodable for ScanBlock {
     }
 }
 
+impl MinEncodedSize for ScanBlock {
+    const MIN_ENCODED_SIZE: usize = 7;
+}
+
+/// Unix timestamp in seconds
+pub type Timestamp = u64;
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct VersionMessage {
     pub version: Version,
-    pub time: u64,
+    /// Preferred codec for compressed payloads, packed into reserved bits of version
+    pub codec: CodecKind,
+    pub time: Timestamp,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub nonce: [u8; 8],
     pub scan_blocks: Vec<ScanBlock>,
 }
@@ -689,11 +1873,30 @@ impl Display for VersionMessage {
     }
 }
 
+impl VersionMessage {
+    /// Version message of current protocol version stamped with current time. The nonce should
+    /// be random for every connection, it allows to detect connections to self.
+    pub fn new(nonce: [u8; 8], scan_blocks: Vec<ScanBlock>) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        VersionMessage {
+            version: Version::current(),
+            codec: CodecKind::preferred(),
+            time,
+            nonce,
+            scan_blocks,
+        }
+    }
+}
+
 impl Encodable for VersionMessage {
     #[inline]
     fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
         let mut len = 0;
-        len += self.version.consensus_encode(&mut s)?;
+        let w = self.version.pack() | self.codec.to_bits();
+        len += w.to_be().consensus_encode(&mut s)?;
         len += self.time.consensus_encode(&mut s)?;
         len += self.nonce.consensus_encode(&mut s)?;
         len += LengthVecRef(&self.scan_blocks).consensus_encode(&mut s)?;
@@ -701,19 +1904,48 @@ impl Encodable for VersionMessage {
     }
 }
 
-impl Decodable for VersionMessage {
+impl EncodedLen for VersionMessage {
     #[inline]
-    fn consensus_decode<D: io::Read>(mut d: D) -> Result<VersionMessage, consensus_encode::Error> {
+    fn encoded_len(&self) -> usize {
+        self.version.encoded_len()
+            + self.time.encoded_len()
+            + self.nonce.encoded_len()
+            + LengthVecRef(&self.scan_blocks).encoded_len()
+    }
+}
+
+impl VersionMessage {
+    /// Decode version which scan blocks must fit into `remaining` bytes
+    pub fn decode_bounded<D: io::Read>(
+        mut d: D,
+        remaining: usize,
+    ) -> Result<VersionMessage, Error> {
+        let w = u32::consensus_decode(&mut d).field("version")?.to_be();
+        let time = Decodable::consensus_decode(&mut d).field("time")?;
+        let nonce = Decodable::consensus_decode(&mut d).field("nonce")?;
+        // Version word, time and nonce
+        let remaining = remaining.saturating_sub(4 + 8 + 8);
         Ok(VersionMessage {
-            version: Decodable::consensus_decode(&mut d)?,
-            time: Decodable::consensus_decode(&mut d)?,
-            nonce: Decodable::consensus_decode(&mut d)?,
-            scan_blocks: LengthVec::consensus_decode(&mut d)?.0,
+            version: Version::unpack(w),
+            codec: CodecKind::from_bits(w),
+            time,
+            nonce,
+            scan_blocks: LengthVec::decode_bounded(&mut d, remaining)
+                .field("scan blocks")?
+                .0,
         })
     }
 }
 
+impl Decodable for VersionMessage {
+    #[inline]
+    fn consensus_decode<D: io::Read>(d: D) -> Result<VersionMessage, consensus_encode::Error> {
+        VersionMessage::decode_bounded(d, MAX_MESSAGE_SIZE)
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FiltersReq {
     pub currency: Currency,
     pub start: u64,
@@ -741,20 +1973,30 @@ impl Encodable for FiltersReq {
     }
 }
 
+impl EncodedLen for FiltersReq {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        self.currency.encoded_len() + VarInt(self.start).len() + VarInt(self.amount as u64).len()
+    }
+}
+
 impl Decodable for FiltersReq {
     #[inline]
     fn consensus_decode<D: io::Read>(mut d: D) -> Result<FiltersReq, consensus_encode::Error> {
         Ok(FiltersReq {
-            currency: Decodable::consensus_decode(&mut d)?,
-            start: VarInt::consensus_decode(&mut d)?.0,
-            amount: VarInt::consensus_decode(&mut d)?.0 as u32,
+            currency: Decodable::consensus_decode(&mut d).field("currency")?,
+            start: VarInt::consensus_decode(&mut d).field("start")?.0,
+            amount: VarInt::consensus_decode(&mut d).field("amount")?.0 as u32,
         })
     }
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Filter {
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub block_id: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub filter: Vec<u8>,
 }
 
@@ -773,24 +2015,40 @@ impl Encodable for Filter {
     #[inline]
     fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
         let mut len = 0;
-        len += s.write(&self.block_id)?;
+        s.write_all(&self.block_id)?;
+        len += self.block_id.len();
         len += self.filter.consensus_encode(&mut s)?;
         Ok(len)
     }
 }
 
-impl Decodable for Filter {
-    #[inline]
-    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Filter, consensus_encode::Error> {
+impl Filter {
+    /// Decode filter which body must fit into `remaining` bytes
+    pub fn decode_bounded<D: io::Read>(mut d: D, remaining: usize) -> Result<Filter, Error> {
         let bid: [u8; 32] = Decodable::consensus_decode(&mut d)?;
         Ok(Filter {
             block_id: bid.to_vec(),
-            filter: Decodable::consensus_decode(&mut d)?,
+            filter: decode_bytes(&mut d, remaining.saturating_sub(bid.len()))?,
         })
     }
 }
 
+impl EncodedLen for Filter {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        self.block_id.len() + self.filter.encoded_len()
+    }
+}
+
+impl Decodable for Filter {
+    #[inline]
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Filter, consensus_encode::Error> {
+        Filter::decode_bounded(d, MAX_MESSAGE_SIZE)
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FiltersResp {
     pub currency: Currency,
     pub filters: Vec<Filter>,
@@ -798,56 +2056,56 @@ pub struct FiltersResp {
 
 impl FiltersResp {
     pub fn compress<'a, I: Iterator<Item = &'a Filter>>(filters: I) -> Result<Vec<u8>, io::Error> {
-        let mut e = GzEncoder::new(Vec::new(), Compression::default());
-        for f in filters {
-            e.write_all(&serialize(&f))?;
-        }
-        e.finish()
+        FiltersResp::compress_with(filters, &Gzip)
     }
 
-    pub fn decompress(buf: &[u8]) -> Result<Vec<u8>, io::Error> {
-        let mut gz = GzDecoder::new(Vec::new());
-        gz.write_all(&buf)?;
-        gz.finish()
+    /// Compress filters with given codec
+    pub fn compress_with<'a, I: Iterator<Item = &'a Filter>>(
+        mut filters: I,
+        codec: &dyn PayloadCodec,
+    ) -> Result<Vec<u8>, io::Error> {
+        codec.compress_with(&mut |w| {
+            for f in &mut filters {
+                f.consensus_encode(&mut *w)?;
+            }
+            Ok(())
+        })
     }
-}
 
-impl Display for FiltersResp {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Filters for {}:", self.currency)?;
-        fmt_vec(&self.filters, f)
+    /// Decompress filters, output is limited by `MAX_DECOMPRESSED_SIZE`
+    pub fn decompress(buf: &[u8]) -> Result<Vec<u8>, io::Error> {
+        Gzip.decompress(buf, MAX_DECOMPRESSED_SIZE)
+            .map_err(into_io_error)
     }
-}
-
-impl Encodable for FiltersResp {
-    #[inline]
-    fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
-        let mut len = 0;
-        len += self.currency.consensus_encode(&mut s)?;
-        len += VarInt(self.filters.len() as u64).consensus_encode(&mut s)?;
 
-        let compressed_bytes = FiltersResp::compress(self.filters.iter())?;
-        s.write_all(&compressed_bytes)?;
-        len += compressed_bytes.len();
+    /// Decompress filters with codec and limits from options
+    pub fn decompress_with(buf: &[u8], options: &DecodeOptions) -> Result<Vec<u8>, Error> {
+        options.decompress(buf)
+    }
 
-        Ok(len)
+    /// Encode payload compressing filters with given codec
+    pub fn encode_with<S: io::Write>(&self, s: S, codec: CodecKind) -> Result<usize, io::Error> {
+        Compressed::filters(self, codec)?.consensus_encode(s)
     }
-}
 
-impl Decodable for FiltersResp {
-    #[inline]
-    fn consensus_decode<D: io::Read>(mut d: D) -> Result<FiltersResp, consensus_encode::Error> {
-        let cur = Decodable::consensus_decode(&mut d)?;
-        let amount = VarInt::consensus_decode(&mut d)?.0;
+    /// Decode payload applying given limits
+    pub fn decode_with<D: io::Read>(
+        mut d: D,
+        options: &DecodeOptions,
+    ) -> Result<FiltersResp, Error> {
+        let cur = Decodable::consensus_decode(&mut d).field("currency")?;
+        let amount = VarInt::consensus_decode(&mut d).field("amount")?.0;
 
         let mut buf = vec![];
         d.read_to_end(&mut buf)?;
-        let uncompressed = FiltersResp::decompress(&buf)?;
+        let uncompressed = FiltersResp::decompress_with(&buf, options)?;
+        check_amount(amount, MIN_FILTER_SIZE, uncompressed.len())?;
 
         let mut decoder = Cursor::new(uncompressed);
-        let mut fs = vec![];
+        let mut fs = Vec::with_capacity(amount as usize);
         for _ in 0..amount {
-            fs.push(Filter::consensus_decode(&mut decoder)?);
+            let remaining = cursor_remaining(&decoder);
+            fs.push(Filter::decode_bounded(&mut decoder, remaining).field("filters")?);
         }
 
         Ok(FiltersResp {
@@ -857,11 +2115,38 @@ impl Decodable for FiltersResp {
     }
 }
 
+/// Encoded filter takes at least block id and length of empty filter
+const MIN_FILTER_SIZE: usize = 32 + 1;
+
+impl Display for FiltersResp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Filters for {}:", self.currency)?;
+        fmt_vec(&self.filters, f)
+    }
+}
+
+impl Encodable for FiltersResp {
+    #[inline]
+    fn consensus_encode<S: io::Write>(&self, s: S) -> Result<usize, io::Error> {
+        self.encode_with(s, CodecKind::Gzip)
+    }
+}
+
+impl Decodable for FiltersResp {
+    #[inline]
+    fn consensus_decode<D: io::Read>(d: D) -> Result<FiltersResp, consensus_encode::Error> {
+        FiltersResp::decode_with(d, &DecodeOptions::default())
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FilterEvent {
     pub currency: Currency,
     pub height: u64,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub block_id: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub filter: Vec<u8>,
 }
 
@@ -891,23 +2176,34 @@ impl Encodable for FilterEvent {
     }
 }
 
+impl EncodedLen for FilterEvent {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        self.currency.encoded_len()
+            + VarInt(self.height).len()
+            + self.block_id.len()
+            + self.filter.encoded_len()
+    }
+}
+
 impl Decodable for FilterEvent {
     #[inline]
     fn consensus_decode<D: io::Read>(mut d: D) -> Result<FilterEvent, consensus_encode::Error> {
         let mut buf = [0; 32];
         Ok(FilterEvent {
-            currency: Decodable::consensus_decode(&mut d)?,
-            height: VarInt::consensus_decode(&mut d)?.0,
+            currency: Decodable::consensus_decode(&mut d).field("currency")?,
+            height: VarInt::consensus_decode(&mut d).field("height")?.0,
             block_id: {
-                d.read_exact(&mut buf)?;
+                d.read_exact(&mut buf).field("block id")?;
                 buf.to_vec()
             },
-            filter: Decodable::consensus_decode(&mut d)?,
+            filter: Decodable::consensus_decode(&mut d).field("filter")?,
         })
     }
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FeeBtc {
     pub fast_conserv: u64,
     pub fast_econom: u64,
@@ -951,6 +2247,18 @@ impl Encodable for FeeBtc {
     }
 }
 
+impl EncodedLen for FeeBtc {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        VarInt(self.fast_conserv).len()
+            + VarInt(self.fast_econom).len()
+            + VarInt(self.moderate_conserv).len()
+            + VarInt(self.moderate_econom).len()
+            + VarInt(self.cheap_conserv).len()
+            + VarInt(self.cheap_econom).len()
+    }
+}
+
 impl Decodable for FeeBtc {
     #[inline]
     fn consensus_decode<D: io::Read>(mut d: D) -> Result<FeeBtc, consensus_encode::Error> {
@@ -966,6 +2274,7 @@ impl Decodable for FeeBtc {
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FeeOther {
     pub fast: u64,
     pub moderate: u64,
@@ -991,6 +2300,13 @@ impl Encodable for FeeOther {
     }
 }
 
+impl EncodedLen for FeeOther {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        VarInt(self.fast).len() + VarInt(self.moderate).len() + VarInt(self.cheap).len()
+    }
+}
+
 impl Decodable for FeeOther {
     #[inline]
     fn consensus_decode<D: io::Read>(mut d: D) -> Result<FeeOther, consensus_encode::Error> {
@@ -1003,6 +2319,7 @@ impl Decodable for FeeOther {
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum FeeResp {
     Btc((Currency, FeeBtc)),
     Other((Currency, FeeOther)),
@@ -1023,16 +2340,30 @@ impl Display for FeeResp {
     }
 }
 
+impl FeeResp {
+    /// Only bitcoin fees can be encoded as `FeeBtc`
+    fn check_currency(&self) -> Result<(), io::Error> {
+        match self {
+            FeeResp::Btc((currency, _))
+                if *currency != Currency::Btc && *currency != Currency::TBtc =>
+            {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "FeeBtc currency must be Btc or TBtc!",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 impl Encodable for FeeResp {
     #[inline]
     fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
+        self.check_currency()?;
         let mut len = 0;
         match self {
             FeeResp::Btc((currency, fee)) => {
-                assert!(
-                    *currency == Currency::Btc || *currency == Currency::TBtc,
-                    "FeeBtc currency must be Btc or TBtc!"
-                );
                 len += currency.consensus_encode(&mut s)?;
                 len += fee.consensus_encode(&mut s)?
             }
@@ -1045,6 +2376,16 @@ impl Encodable for FeeResp {
     }
 }
 
+impl EncodedLen for FeeResp {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        match self {
+            FeeResp::Btc((currency, fee)) => currency.encoded_len() + fee.encoded_len(),
+            FeeResp::Other((currency, fee)) => currency.encoded_len() + fee.encoded_len(),
+        }
+    }
+}
+
 impl Decodable for FeeResp {
     #[inline]
     fn consensus_decode<D: io::Read>(mut d: D) -> Result<FeeResp, consensus_encode::Error> {
@@ -1058,7 +2399,12 @@ impl Decodable for FeeResp {
     }
 }
 
+impl MinEncodedSize for FeeResp {
+    const MIN_ENCODED_SIZE: usize = 4;
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum RejectData {
     HeaderParsing,
     PayloadParsing,
@@ -1115,6 +2461,7 @@ impl RejectData {
 impl_pure_encodable!(RejectData, unpack, pack);
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RejectMessage {
     pub id: u32,
     pub data: RejectData,
@@ -1144,18 +2491,26 @@ impl Encodable for RejectMessage {
     }
 }
 
+impl EncodedLen for RejectMessage {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        VarInt(self.id as u64).len() + self.data.encoded_len() + self.message.encoded_len()
+    }
+}
+
 impl Decodable for RejectMessage {
     #[inline]
     fn consensus_decode<D: io::Read>(mut d: D) -> Result<RejectMessage, consensus_encode::Error> {
         Ok(RejectMessage {
-            id: VarInt::consensus_decode(&mut d)?.0 as u32,
-            data: Decodable::consensus_decode(&mut d)?,
-            message: Decodable::consensus_decode(&mut d)?,
+            id: VarInt::consensus_decode(&mut d).field("rejected id")?.0 as u32,
+            data: Decodable::consensus_decode(&mut d).field("code")?,
+            message: Decodable::consensus_decode(&mut d).field("message")?,
         })
     }
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RateReq {
     pub currency: Currency,
     pub fiats: Vec<Fiat>,
@@ -1178,16 +2533,36 @@ impl Encodable for RateReq {
     }
 }
 
-impl Decodable for RateReq {
+impl EncodedLen for RateReq {
     #[inline]
-    fn consensus_decode<D: io::Read>(mut d: D) -> Result<RateReq, consensus_encode::Error> {
+    fn encoded_len(&self) -> usize {
+        self.currency.encoded_len() + LengthVecRef(&self.fiats).encoded_len()
+    }
+}
+
+impl RateReq {
+    /// Decode request which fiats must fit into `remaining` bytes
+    pub fn decode_bounded<D: io::Read>(mut d: D, remaining: usize) -> Result<RateReq, Error> {
+        let currency: Currency = Decodable::consensus_decode(&mut d)?;
+        let remaining = remaining.saturating_sub(currency.encoded_len());
         Ok(RateReq {
-            currency: Decodable::consensus_decode(&mut d)?,
-            fiats: LengthVec::consensus_decode(&mut d)?.0,
+            currency,
+            fiats: LengthVec::decode_bounded(&mut d, remaining)?.0,
         })
     }
 }
 
+impl Decodable for RateReq {
+    #[inline]
+    fn consensus_decode<D: io::Read>(d: D) -> Result<RateReq, consensus_encode::Error> {
+        RateReq::decode_bounded(d, MAX_MESSAGE_SIZE)
+    }
+}
+
+impl MinEncodedSize for RateReq {
+    const MIN_ENCODED_SIZE: usize = 2;
+}
+
 /// Fiat value with 2 decimals after point
 pub type Rate = Centi<u64>;
 
@@ -1211,8 +2586,10 @@ impl Decodable for RateWord {
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FiatRate {
     pub fiat: Fiat,
+    #[cfg_attr(feature = "serde", serde(with = "rate"))]
     pub rate: Rate,
 }
 
@@ -1232,6 +2609,13 @@ impl Encodable for FiatRate {
     }
 }
 
+impl EncodedLen for FiatRate {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        self.fiat.encoded_len() + self.rate.bits.encoded_len()
+    }
+}
+
 impl Decodable for FiatRate {
     #[inline]
     fn consensus_decode<D: io::Read>(mut d: D) -> Result<FiatRate, consensus_encode::Error> {
@@ -1242,7 +2626,12 @@ impl Decodable for FiatRate {
     }
 }
 
+impl MinEncodedSize for FiatRate {
+    const MIN_ENCODED_SIZE: usize = 9;
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RateResp {
     pub currency: Currency,
     pub rates: Vec<FiatRate>,
@@ -1265,30 +2654,60 @@ impl Encodable for RateResp {
     }
 }
 
-impl Decodable for RateResp {
+impl EncodedLen for RateResp {
     #[inline]
-    fn consensus_decode<D: io::Read>(mut d: D) -> Result<RateResp, consensus_encode::Error> {
+    fn encoded_len(&self) -> usize {
+        self.currency.encoded_len() + LengthVecRef(&self.rates).encoded_len()
+    }
+}
+
+impl RateResp {
+    /// Decode response which rates must fit into `remaining` bytes
+    pub fn decode_bounded<D: io::Read>(mut d: D, remaining: usize) -> Result<RateResp, Error> {
+        let currency: Currency = Decodable::consensus_decode(&mut d)?;
+        let remaining = remaining.saturating_sub(currency.encoded_len());
         Ok(RateResp {
-            currency: Decodable::consensus_decode(&mut d)?,
-            rates: LengthVec::consensus_decode(&mut d)?.0,
+            currency,
+            rates: LengthVec::decode_bounded(&mut d, remaining)?.0,
         })
     }
 }
 
+impl Decodable for RateResp {
+    #[inline]
+    fn consensus_decode<D: io::Read>(d: D) -> Result<RateResp, consensus_encode::Error> {
+        RateResp::decode_bounded(d, MAX_MESSAGE_SIZE)
+    }
+}
+
+impl MinEncodedSize for RateResp {
+    const MIN_ENCODED_SIZE: usize = 2;
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
-pub struct MemFilter(pub Vec<u8>);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MemFilter(#[cfg_attr(feature = "serde", serde(with = "hex_bytes"))] pub Vec<u8>);
 
 impl MemFilter {
     pub fn compress(&self) -> Result<Vec<u8>, io::Error> {
-        let mut e = GzEncoder::new(Vec::new(), Compression::default());
-        e.write_all(&self.0)?;
-        e.finish()
+        self.compress_with(&Gzip)
+    }
+
+    /// Compress filter with given codec
+    pub fn compress_with(&self, codec: &dyn PayloadCodec) -> Result<Vec<u8>, io::Error> {
+        codec.compress(&self.0)
     }
 
+    /// Decompress filter, output is limited by `MAX_DECOMPRESSED_SIZE`
     pub fn decompress(buf: &[u8]) -> Result<MemFilter, io::Error> {
-        let mut gz = GzDecoder::new(Vec::new());
-        gz.write_all(&buf)?;
-        gz.finish().map(MemFilter)
+        Gzip.decompress(buf, MAX_DECOMPRESSED_SIZE)
+            .map(MemFilter)
+            .map_err(into_io_error)
+    }
+
+    /// Decompress filter with codec and limits from options
+    pub fn decompress_with(buf: &[u8], options: &DecodeOptions) -> Result<MemFilter, Error> {
+        options.decompress(buf).map(MemFilter)
     }
 }
 
@@ -1305,6 +2724,13 @@ impl Encodable for MemFilter {
     }
 }
 
+impl EncodedLen for MemFilter {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        self.0.encoded_len()
+    }
+}
+
 impl Decodable for MemFilter {
     #[inline]
     fn consensus_decode<D: io::Read>(mut d: D) -> Result<MemFilter, consensus_encode::Error> {
@@ -1313,7 +2739,8 @@ impl Decodable for MemFilter {
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
-pub struct TxPrefix(pub [u8; 2]);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TxPrefix(#[cfg_attr(feature = "serde", serde(with = "hex_bytes"))] pub [u8; 2]);
 
 impl Display for TxPrefix {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -1328,6 +2755,13 @@ impl Encodable for TxPrefix {
     }
 }
 
+impl EncodedLen for TxPrefix {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        self.0.encoded_len()
+    }
+}
+
 impl Decodable for TxPrefix {
     #[inline]
     fn consensus_decode<D: io::Read>(mut d: D) -> Result<TxPrefix, consensus_encode::Error> {
@@ -1335,7 +2769,12 @@ impl Decodable for TxPrefix {
     }
 }
 
+impl MinEncodedSize for TxPrefix {
+    const MIN_ENCODED_SIZE: usize = 2;
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FilterPrefixPair {
     pub prefix: TxPrefix,
     pub filter: MemFilter,
@@ -1358,6 +2797,13 @@ impl Encodable for FilterPrefixPair {
     }
 }
 
+impl EncodedLen for FilterPrefixPair {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        self.prefix.encoded_len() + self.filter.encoded_len()
+    }
+}
+
 impl Decodable for FilterPrefixPair {
     #[inline]
     fn consensus_decode<D: io::Read>(
@@ -1369,25 +2815,70 @@ impl Decodable for FilterPrefixPair {
     }
 }
 
+impl MinEncodedSize for FilterPrefixPair {
+    const MIN_ENCODED_SIZE: usize = 3;
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MempoolChunkResp {
     pub prefix: TxPrefix,
+    #[cfg_attr(feature = "serde", serde(with = "hex_vec"))]
     pub txs: Vec<Vec<u8>>, //byte repersentation of transactions
 }
 
 impl MempoolChunkResp {
     pub fn compress<'a, I: Iterator<Item = &'a Vec<u8>>>(filters: I) -> Result<Vec<u8>, io::Error> {
-        let mut e = GzEncoder::new(Vec::new(), Compression::default());
-        for f in filters {
-            e.write_all(&serialize(&f))?;
-        }
-        e.finish()
+        MempoolChunkResp::compress_with(filters, &Gzip)
+    }
+
+    /// Compress transactions with given codec
+    pub fn compress_with<'a, I: Iterator<Item = &'a Vec<u8>>>(
+        mut txs: I,
+        codec: &dyn PayloadCodec,
+    ) -> Result<Vec<u8>, io::Error> {
+        codec.compress_with(&mut |w| {
+            for tx in &mut txs {
+                tx.consensus_encode(&mut *w)?;
+            }
+            Ok(())
+        })
     }
 
+    /// Decompress transactions, output is limited by `MAX_DECOMPRESSED_SIZE`
     pub fn decompress(buf: &[u8]) -> Result<Vec<u8>, io::Error> {
-        let mut gz = GzDecoder::new(Vec::new());
-        gz.write_all(&buf)?;
-        gz.finish()
+        Gzip.decompress(buf, MAX_DECOMPRESSED_SIZE)
+            .map_err(into_io_error)
+    }
+
+    /// Decompress transactions with codec and limits from options
+    pub fn decompress_with(buf: &[u8], options: &DecodeOptions) -> Result<Vec<u8>, Error> {
+        options.decompress(buf)
+    }
+
+    /// Encode payload compressing transactions with given codec
+    pub fn encode_with<S: io::Write>(&self, s: S, codec: CodecKind) -> Result<usize, io::Error> {
+        Compressed::mempool(self, codec)?.consensus_encode(s)
+    }
+
+    /// Decode payload applying given limits
+    pub fn decode_with<D: io::Read>(mut d: D, options: &DecodeOptions) -> Result<Self, Error> {
+        let prefix = Decodable::consensus_decode(&mut d).field("prefix")?;
+        let amount = VarInt::consensus_decode(&mut d).field("amount")?.0;
+        let mut buf = vec![];
+        d.read_to_end(&mut buf)?;
+        let uncompressed = MempoolChunkResp::decompress_with(&buf, options)?;
+        // Each transaction takes at least its length
+        check_amount(amount, 1, uncompressed.len())?;
+
+        let mut decoder = Cursor::new(uncompressed);
+        let mut txs = Vec::with_capacity(amount as usize);
+        for _ in 0..amount {
+            let remaining = cursor_remaining(&decoder);
+            txs.push(decode_bytes(&mut decoder, remaining).field("transactions")?);
+        }
+
+        Ok(MempoolChunkResp { prefix, txs })
     }
 }
 
@@ -1400,32 +2891,14 @@ impl Display for MempoolChunkResp {
 }
 
 impl Encodable for MempoolChunkResp {
-    fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
-        let mut len = 0;
-        len += self.prefix.consensus_encode(&mut s)?;
-        len += VarInt(self.txs.len() as u64).consensus_encode(&mut s)?;
-        let compressed_bytes = MempoolChunkResp::compress(self.txs.iter())?;
-        s.write_all(&compressed_bytes)?;
-        len += compressed_bytes.len();
-        Ok(len)
+    fn consensus_encode<S: io::Write>(&self, s: S) -> Result<usize, io::Error> {
+        self.encode_with(s, CodecKind::Gzip)
     }
 }
 
 impl Decodable for MempoolChunkResp {
-    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
-        let prefix = Decodable::consensus_decode(&mut d)?;
-        let amount = VarInt::consensus_decode(&mut d)?.0 as u32;
-        let mut buf = vec![];
-        d.read_to_end(&mut buf)?;
-        let uncompressed = FiltersResp::decompress(&buf)?;
-
-        let mut decoder = Cursor::new(uncompressed);
-        let mut txs = vec![];
-        for _ in 0..amount {
-            txs.push(LengthVec::consensus_decode(&mut decoder)?.0);
-        }
-
-        Ok(MempoolChunkResp { prefix, txs })
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Self, Error> {
+        MempoolChunkResp::decode_with(d, &DecodeOptions::default())
     }
 }
 
@@ -1433,7 +2906,7 @@ impl Decodable for MempoolChunkResp {
 mod test {
     use super::*;
     use consensus_encode::util::hex::FromHex;
-    use rand::Rng;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     #[test]
     fn version_test_1() {
@@ -1497,7 +2970,8 @@ mod test {
     #[test]
     fn address_test_onion() {
         let addr = Address::OnionV3(
-            *b"jamie22ezawwi5r3o7lrgsno43jj7vq5en74czuw6wfmjzkhjjryxnid",
+            OnionV3Address::new(*b"jamie22ezawwi5r3o7lrgsno43jj7vq5en74czuw6wfmjzkhjjryxnid")
+                .unwrap(),
             9150,
         );
         let bytes = vec![
@@ -1510,6 +2984,218 @@ mod test {
         assert_eq!(deserialize::<Address>(&bytes).unwrap(), addr);
     }
 
+    #[test]
+    fn address_from_str() {
+        let onion = "jamie22ezawwi5r3o7lrgsno43jj7vq5en74czuw6wfmjzkhjjryxnid";
+        for s in &[
+            "1.2.3.4:8667".to_owned(),
+            "[::1]:8667".to_owned(),
+            format!("{}.onion:8667", onion),
+            format!("{}.b32.i2p:0", "a".repeat(52)),
+            "cjdns:[fc00::1]:8667".to_owned(),
+            "indexer-1.example.com:8667".to_owned(),
+        ] {
+            assert_eq!(s.parse::<Address>().unwrap().to_string(), *s);
+        }
+        assert_eq!(
+            format!("{}:9150", onion.to_uppercase()).parse::<Address>(),
+            Ok(Address::OnionV3(onion.parse().unwrap(), 9150))
+        );
+        let part = |s: &str| s.parse::<Address>().unwrap_err().part;
+        assert_eq!(part("1.2.3.4"), "port");
+        assert_eq!(part("1.2.3.4:86670"), "port");
+        assert_eq!(part("1.2.3:8667"), "host");
+        assert_eq!(part("[::g]:8667"), "host");
+        assert_eq!(part("-example.com:8667"), "host");
+        assert_eq!(part("aaaa.b32.i2p:8667"), "i2p host");
+        assert_eq!(part("cjdns:[fd00::1]:8667"), "cjdns host");
+        assert_eq!(
+            "Example.COM:8667".parse(),
+            Ok(Address::Hostname("example.com".to_owned(), 8667))
+        );
+        let mut bad = onion.to_owned();
+        bad.replace_range(3..4, "a");
+        assert_eq!(part(&format!("{}.onion:8667", bad)), "onion checksum");
+        assert_eq!(
+            "1.2.3:8667".parse::<Address>().unwrap_err().to_string(),
+            "invalid host of address \"1.2.3:8667\""
+        );
+    }
+
+    #[test]
+    fn address_v2_test() {
+        let addrs = vec![
+            Address::Ipv4("1.2.3.4:8667".parse().unwrap()),
+            Address::OnionV3(OnionV3Address::from_public_key([7; 32]), 9150),
+            Address::I2p([3; 32], 0),
+            Address::Cjdns("fc00::1".parse().unwrap(), 8667),
+            Address::Hostname("example.com".to_owned(), 8667),
+        ];
+        let mut bytes = vec![0, 4, 1, 2, 3, 4, 0x21, 0xdb];
+        assert_eq!(serialize_v2(&addrs[0]), bytes);
+        bytes = vec![5, 11];
+        bytes.extend_from_slice(b"example.com");
+        bytes.extend_from_slice(&[0x21, 0xdb]);
+        assert_eq!(serialize_v2(&addrs[4]), bytes);
+        for addr in &addrs {
+            let bytes = serialize_v2(addr);
+            assert_eq!(bytes.len(), addr.encoded_len_v2());
+            assert_eq!(Address::decode_v2(&bytes[..]).unwrap(), Some(addr.clone()));
+        }
+
+        let msg = Message::PeersV2(addrs.clone());
+        let bytes = serialize(&msg);
+        assert_eq!(deserialize::<Message>(&bytes).unwrap(), msg);
+        assert!(!addrs[2].has_v1_encoding());
+        assert!(try_serialize(&Message::Peers(addrs.clone())).is_err());
+        // Size is refused like encoding
+        let peers = Message::Peers(addrs.clone());
+        assert!(peers.encoded_len_with(CodecKind::Gzip).is_err());
+        let bad = Message::PeersV2(vec![Address::Cjdns("fd00::1".parse().unwrap(), 8667)]);
+        assert!(bad.encoded_len_with(CodecKind::Gzip).is_err());
+        assert_eq!(addrs[4].encoded_len(), addrs[4].encoded_len_v2());
+    }
+
+    fn serialize_v2(addr: &Address) -> Vec<u8> {
+        let mut bytes = vec![];
+        addr.encode_v2(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn address_v2_unknown_network() {
+        // Unknown network 9 between two IPv4 addresses is skipped
+        let payload = Vec::from_hex("0300040102030421db09030a0b0c0001000405060708000f").unwrap();
+        let msg = Message::decode_payload(23, &payload, &DecodeOptions::default()).unwrap();
+        assert_eq!(
+            msg,
+            Message::PeersV2(vec![
+                Address::Ipv4("1.2.3.4:8667".parse().unwrap()),
+                Address::Ipv4("5.6.7.8:15".parse().unwrap()),
+            ])
+        );
+        // Known network with wrong body size fails the message
+        let mut bytes = Vec::from_hex("17080100030102030000").unwrap();
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert_eq!(e.field, "address");
+        assert!(matches!(e.kind, ProtocolErrorKind::InvalidAddress));
+        // CJDNS address outside of fc00::/8
+        bytes = Vec::from_hex("1715010410fd0000000000000000000000000000010000").unwrap();
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert!(matches!(e.kind, ProtocolErrorKind::InvalidAddress));
+    }
+
+    #[test]
+    fn peer_records_test() {
+        let record = PeerRecord {
+            address: Address::Ipv4("1.2.3.4:8667".parse().unwrap()),
+            last_seen: 1621437700,
+            currencies: vec![Currency::Btc, Currency::Ergo],
+            services: Services::FILTERS | Services::FEES,
+        };
+        let msg = Message::PeerRecords(vec![record.clone()]);
+        let bytes =
+            Vec::from_hex("1a1c0100040102030421db042da560000000000200020300000000000000").unwrap();
+        assert_eq!(serialize(&msg), bytes);
+        assert_eq!(deserialize::<Message>(&bytes).unwrap(), msg);
+        assert!(record.services.contains(Services::FEES));
+        assert!(!record.services.contains(Services::FEES | Services::RATES));
+
+        // Record of unknown network 9 is skipped
+        let mut payload = Vec::from_hex("020901ff00000000000000000000000000000000000000").unwrap();
+        payload.extend_from_slice(&bytes[3..]);
+        assert_eq!(
+            Message::decode_payload(26, &payload, &DecodeOptions::default()).unwrap(),
+            msg
+        );
+
+        let plain = PeerRecord::from(record.address.clone());
+        assert_eq!(plain.last_seen, 0);
+        assert_eq!(plain.services, Services::NONE);
+        assert_eq!(Address::from(record.clone()), record.address);
+        assert!(Version::current().supports_peer_records());
+        assert!(!Version::ADDR_V2.supports_peer_records());
+
+        // Request has empty payload that older peers skip as unknown message
+        let msg = Message::GetPeerRecords;
+        let bytes = serialize(&msg);
+        assert_eq!(bytes, [25, 0]);
+        assert_eq!(deserialize::<Message>(&bytes).unwrap(), msg);
+        assert_eq!(msg.encoded_len_with(CodecKind::Gzip).unwrap(), 2);
+    }
+
+    #[test]
+    fn peers_for_version() {
+        let addrs = vec![
+            Address::Ipv6("[::1]:8667".parse().unwrap()),
+            Address::Hostname("example.com".to_owned(), 8667),
+        ];
+        assert_eq!(
+            Message::peers(addrs.clone(), false),
+            Message::Peers(addrs[..1].to_vec())
+        );
+        assert_eq!(
+            Message::peer_introduce(addrs.clone(), true),
+            Message::PeerIntroduceV2(addrs.clone())
+        );
+        assert_eq!(
+            Message::PeersV2(addrs.clone()).addresses(),
+            Some(&addrs[..])
+        );
+        let old = Version {
+            major: 2,
+            minor: 1,
+            patch: 0,
+        };
+        assert!(!old.supports_addr_v2());
+        assert!(Version::current().supports_addr_v2());
+    }
+
+    #[test]
+    fn currency_fiat_from_str() {
+        for i in 0..15 {
+            let c = Currency::from_index(i);
+            assert_eq!(c.to_string().parse(), Ok(c));
+            if let Some(ticker) = c.ticker() {
+                assert_eq!(ticker.parse(), Ok(c));
+                assert_eq!(ticker.to_uppercase().parse(), Ok(c));
+            }
+        }
+        assert_eq!("tbtc".parse(), Ok(Currency::TBtc));
+        assert_eq!("testnet bitcoin".parse(), Ok(Currency::TBtc));
+        assert_eq!("xyz".parse::<Currency>().unwrap_err().part, "ticker");
+        assert_eq!(
+            "Unknown currency x".parse::<Currency>().unwrap_err().part,
+            "index"
+        );
+
+        for i in 0..4 {
+            let f = Fiat::from_index(i);
+            assert_eq!(f.to_string().parse(), Ok(f));
+            if let Some(code) = f.iso_code() {
+                assert_eq!(code.parse(), Ok(f));
+            }
+        }
+        assert_eq!("usd".parse(), Ok(Fiat::Usd));
+        assert_eq!("RUR".parse(), Ok(Fiat::Rub));
+        assert_eq!("GBP".parse::<Fiat>().unwrap_err().part, "ISO code");
+    }
+
+    #[test]
+    fn version_from_str() {
+        let version = Version {
+            major: 1,
+            minor: 2,
+            patch: 1023,
+        };
+        assert_eq!(version.to_string().parse(), Ok(version));
+        let part = |s: &str| s.parse::<Version>().unwrap_err().part;
+        assert_eq!(part("x.2.3"), "major");
+        assert_eq!(part("1.1024.3"), "minor");
+        assert_eq!(part("1.2"), "patch");
+        assert_eq!(part("1.2.3.4"), "patch");
+    }
+
     #[test]
     fn version_msg_test() {
         let msg = Message::Version(VersionMessage {
@@ -1518,6 +3204,7 @@ mod test {
                 minor: 2,
                 patch: 4,
             },
+            codec: CodecKind::Gzip,
             time: 1615562102,
             nonce: [0, 1, 2, 3, 4, 5, 6, 7],
             scan_blocks: vec![
@@ -1548,6 +3235,55 @@ mod test {
         assert_eq!(deserialize::<Message>(&bytes).unwrap(), msg);
     }
 
+    #[test]
+    fn version_msg_codec_bits() {
+        let msg = VersionMessage {
+            version: Version {
+                major: 1,
+                minor: 2,
+                patch: 4,
+            },
+            codec: CodecKind::Identity,
+            time: 1615562102,
+            nonce: [0, 1, 2, 3, 4, 5, 6, 7],
+            scan_blocks: vec![],
+        };
+        let bytes = Vec::from_hex("0100200676854b6000000000000102030405060700").unwrap();
+        assert_eq!(serialize(&msg), bytes);
+        assert_eq!(deserialize::<VersionMessage>(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn filters_codecs_roundtrip() {
+        let msg = FiltersResp {
+            currency: Currency::Btc,
+            filters: vec![Filter {
+                block_id: vec![7; 32],
+                filter: vec![1, 2, 3, 4, 1, 2, 3, 4],
+            }],
+        };
+        let gzip = serialize(&msg);
+        let frame = Message::Filters(msg.clone());
+        for kind in [CodecKind::Gzip, CodecKind::Zstd, CodecKind::Identity].iter() {
+            if kind.codec().is_none() {
+                assert!(msg.encode_with(Vec::new(), *kind).is_err());
+                assert!(frame.encoded_len_with(*kind).is_err());
+                continue;
+            }
+            let mut bytes = vec![];
+            msg.encode_with(&mut bytes, *kind).unwrap();
+            assert_eq!(bytes == gzip, *kind == CodecKind::Gzip);
+            let mut frame_bytes = vec![];
+            frame.encode_with(&mut frame_bytes, *kind).unwrap();
+            assert_eq!(frame.encoded_len_with(*kind).unwrap(), frame_bytes.len());
+            let options = DecodeOptions {
+                codec: *kind,
+                ..Default::default()
+            };
+            assert_eq!(FiltersResp::decode_with(&bytes[..], &options).unwrap(), msg);
+        }
+    }
+
     #[test]
     fn version_msg_test_failed_version() {
         let msg = Message::Version(VersionMessage {
@@ -1556,6 +3292,7 @@ mod test {
                 minor: 0,
                 patch: 0,
             },
+            codec: CodecKind::Gzip,
             time: 1617118704,
             nonce: [25, 218, 220, 43, 225, 52, 200, 125],
             scan_blocks: vec![ScanBlock {
@@ -1591,6 +3328,10 @@ mod test {
         let bytes = Vec::from_hex("0b08cf78060000000000").unwrap();
         assert_eq!(serialize(&msg), bytes);
         assert_eq!(deserialize::<Message>(&bytes).unwrap(), msg);
+        // Nonce shorter than 8 bytes
+        let e = Message::from_frame(&Vec::from_hex("0b03cf7806").unwrap()).unwrap_err();
+        assert_eq!(e.field, "nonce");
+        assert!(matches!(e.kind, ProtocolErrorKind::Truncated));
     }
 
     #[test]
@@ -1613,6 +3354,103 @@ mod test {
         assert_eq!(deserialize::<Message>(&bytes).unwrap(), msg);
     }
 
+    #[test]
+    fn unknown_msg_test() {
+        let msg = Message::Unknown {
+            id: 42,
+            payload: vec![0xde, 0xad, 0xbe, 0xef],
+        };
+        let bytes = Vec::from_hex("2a04deadbeef").unwrap();
+        assert_eq!(deserialize::<Message>(&bytes).unwrap(), msg);
+        assert_eq!(serialize(&msg), bytes);
+
+        let bytes = Vec::from_hex("fd2c0100").unwrap();
+        let msg = deserialize::<Message>(&bytes).unwrap();
+        assert_eq!(msg.id(), 300);
+        assert_eq!(serialize(&msg), bytes);
+
+        // Id 2^32 + 1 is not taken for version ack
+        let bytes = Vec::from_hex("ff0100000001000000").unwrap();
+        let e = FrameHeader::peek(&bytes).unwrap_err();
+        assert!(e.is_header());
+        assert!(matches!(
+            e.kind,
+            ProtocolErrorKind::IdOutOfRange(0x100000001)
+        ));
+        let e = FrameHeader::read_from(&bytes[..]).unwrap_err();
+        assert!(matches!(e.kind, ProtocolErrorKind::IdOutOfRange(_)));
+        assert!(deserialize::<Message>(&bytes).is_err());
+
+        // Known ids can't be sent as unknown messages
+        let msg = Message::Unknown {
+            id: 5,
+            payload: vec![],
+        };
+        let mut bytes = vec![];
+        assert!(msg.encode_with(&mut bytes, CodecKind::Gzip).is_err());
+        assert!(bytes.is_empty());
+        assert!(msg.encoded_len_with(CodecKind::Gzip).is_err());
+    }
+
+    #[test]
+    fn tagged_msg_test() {
+        let req = Message::GetFilters(FiltersReq {
+            currency: Currency::Btc,
+            start: 1,
+            amount: 2,
+        });
+        let msg = req.clone().tagged(5);
+        let bytes = Vec::from_hex("1606050203000102").unwrap();
+        assert_eq!(serialize(&msg), bytes);
+        assert_eq!(deserialize::<Message>(&bytes).unwrap(), msg);
+        assert_eq!(Message::from_frame(&bytes).unwrap(), msg);
+        assert_eq!(msg.encoded_len_with(CodecKind::Gzip).unwrap(), bytes.len());
+        assert_eq!(msg.untagged(), (Some(5), req));
+
+        // Compressed payload of wrapped message uses connection codec
+        let msg = Message::Filters(FiltersResp {
+            currency: Currency::Btc,
+            filters: vec![Filter {
+                block_id: vec![1; 32],
+                filter: vec![2; 10],
+            }],
+        })
+        .tagged(u64::MAX);
+        let mut bytes = vec![];
+        msg.encode_with(&mut bytes, CodecKind::Identity).unwrap();
+        let options = DecodeOptions {
+            codec: CodecKind::Identity,
+            ..Default::default()
+        };
+        assert_eq!(Message::from_frame_with(&bytes, &options).unwrap(), msg);
+    }
+
+    #[test]
+    fn tagged_msg_invalid() {
+        let nested = Message::VersionAck.tagged(1).tagged(2);
+        assert!(try_serialize(&nested).is_err());
+        let e = Message::from_frame(&Vec::from_hex("1603011600").unwrap()).unwrap_err();
+        assert_eq!(e.offset, 3);
+        // Wrapped frame declares more bytes than left in payload
+        let e = Message::from_frame(&Vec::from_hex("160401020300").unwrap()).unwrap_err();
+        assert!(matches!(e.kind, ProtocolErrorKind::Truncated));
+        // Bytes after wrapped frame
+        let e = Message::from_frame(&Vec::from_hex("1603010500").unwrap()).unwrap_err();
+        assert!(matches!(e.kind, ProtocolErrorKind::TrailingBytes));
+    }
+
+    #[test]
+    fn version_request_ids() {
+        let old = Version {
+            major: 2,
+            minor: 0,
+            patch: 3,
+        };
+        assert!(old.compatible(&Version::current()));
+        assert!(!old.supports_request_ids());
+        assert!(Version::current().supports_request_ids());
+    }
+
     #[test]
     fn filters_req_test() {
         let msg = Message::GetFilters(FiltersReq {
@@ -1769,7 +3607,8 @@ mod test {
                 0,
             )),
             Address::OnionV3(
-                *b"jamie22ezawwi5r3o7lrgsno43jj7vq5en74czuw6wfmjzkhjjryxnid",
+                OnionV3Address::new(*b"jamie22ezawwi5r3o7lrgsno43jj7vq5en74czuw6wfmjzkhjjryxnid")
+                    .unwrap(),
                 9150,
             ),
         ]);
@@ -1844,7 +3683,8 @@ mod test {
                 0,
             )),
             Address::OnionV3(
-                *b"jamie22ezawwi5r3o7lrgsno43jj7vq5en74czuw6wfmjzkhjjryxnid",
+                OnionV3Address::new(*b"jamie22ezawwi5r3o7lrgsno43jj7vq5en74czuw6wfmjzkhjjryxnid")
+                    .unwrap(),
                 9150,
             ),
         ]);
@@ -2112,4 +3952,292 @@ mod test {
             "Deserialized msg not equal to a specific one"
         );
     }
+
+    #[test]
+    fn nested_length_exceeds_payload() {
+        // Version with a million scan blocks declared in 5 bytes
+        let mut bytes = Vec::from_hex("0019").unwrap();
+        bytes.extend(vec![0; 20]);
+        bytes.extend(Vec::from_hex("fe40420f00").unwrap());
+        // Single rates request and response with a million fiats declared in 5 bytes
+        let frames = vec![
+            bytes,
+            Vec::from_hex("0d070100fe40420f00").unwrap(),
+            Vec::from_hex("0e070100fe40420f00").unwrap(),
+        ];
+        for bytes in frames {
+            let e = Message::from_frame(&bytes).unwrap_err();
+            assert!(matches!(
+                e.kind,
+                ProtocolErrorKind::OversizedVector { max: 5, .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn length_prefix_exceeds_payload() {
+        let bytes = Vec::from_hex("0605fe40420f00").unwrap();
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert!(matches!(
+            e.kind,
+            ProtocolErrorKind::OversizedVector {
+                requested: 7_000_000,
+                max: 5
+            }
+        ));
+
+        // One transaction of 1 MiB is declared while compressed body holds only its length
+        let mut payload = serialize(&TxPrefix([9, 128]));
+        payload.extend(serialize(&VarInt(1)));
+        payload.extend(MemFilter(serialize(&VarInt(1024 * 1024))).compress().unwrap());
+        let mut bytes = serialize(&VarInt(21));
+        bytes.extend(serialize(&VarInt(payload.len() as u64)));
+        bytes.extend(payload);
+        let e = Message::from_frame(&bytes).unwrap_err();
+        assert!(matches!(
+            e.kind,
+            ProtocolErrorKind::OversizedVector {
+                requested: 1_048_576,
+                max: 5
+            }
+        ));
+    }
+
+    #[test]
+    fn fee_resp_wrong_currency() {
+        let msg = Message::Fee(vec![FeeResp::Btc((
+            Currency::Ergo,
+            FeeBtc {
+                fast_conserv: 4,
+                fast_econom: 8,
+                moderate_conserv: 15,
+                moderate_econom: 16,
+                cheap_conserv: 23,
+                cheap_econom: 42,
+            },
+        ))]);
+        assert!(try_serialize(&msg).is_err());
+        // Nothing is written when encoding fails
+        let mut buf = vec![];
+        assert!(msg.consensus_encode(&mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_json_roundtrip() {
+        let mut msgs = fuzz_samples();
+        msgs.push(Message::Peers(vec![
+            Address::Ipv4("127.0.0.1:8667".parse().unwrap()),
+            Address::Ipv6("[::1]:8667".parse().unwrap()),
+        ]));
+        msgs.push(Message::Ping([1; 8]));
+        for msg in msgs {
+            let json = serde_json::to_string(&msg).unwrap();
+            let decoded: Message = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded, msg, "{}", json);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_json_format() {
+        let filter = Filter {
+            block_id: vec![0xab; 2],
+            filter: vec![1, 2],
+        };
+        assert_eq!(
+            serde_json::to_string(&filter).unwrap(),
+            r#"{"block_id":"abab","filter":"0102"}"#
+        );
+        let rate = FiatRate {
+            fiat: Fiat::Usd,
+            rate: Rate::new(6500305),
+        };
+        let json = r#"{"fiat":"Usd","rate":"65003.05"}"#;
+        assert_eq!(serde_json::to_string(&rate).unwrap(), json);
+        assert_eq!(serde_json::from_str::<FiatRate>(json).unwrap(), rate);
+        let parsed: FiatRate = serde_json::from_str(r#"{"fiat":"Usd","rate":"12.5"}"#).unwrap();
+        assert_eq!(parsed.rate, Rate::new(1250));
+        assert!(serde_json::from_str::<FiatRate>(r#"{"fiat":"Usd","rate":"1.234"}"#).is_err());
+        assert_eq!(
+            serde_json::to_string(&Address::Ipv4("127.0.0.1:8667".parse().unwrap())).unwrap(),
+            r#""127.0.0.1:8667""#
+        );
+        assert!(serde_json::from_str::<TxPrefix>(r#""abcdef""#).is_err());
+    }
+
+    #[test]
+    fn encoded_len_matches() {
+        let mut msgs = fuzz_samples();
+        msgs.extend(vec![
+            Message::VersionAck,
+            Message::GetFilters(FiltersReq {
+                currency: Currency::Unknown(70000),
+                start: 445123,
+                amount: 2000,
+            }),
+            Message::Filter(FilterEvent {
+                currency: Currency::Ergo,
+                height: 1 << 40,
+                block_id: vec![1; 32],
+                filter: vec![2; 300],
+            }),
+            Message::Peers(vec![
+                Address::Ipv4("127.0.0.1:8667".parse().unwrap()),
+                Address::Ipv6("[::1]:8667".parse().unwrap()),
+            ]),
+            Message::GetFee(vec![Currency::Btc, Currency::Ergo]),
+            Message::Fee(vec![FeeResp::Btc((
+                Currency::Btc,
+                FeeBtc {
+                    fast_conserv: 4,
+                    fast_econom: 800,
+                    moderate_conserv: 150_000,
+                    moderate_econom: 16,
+                    cheap_conserv: 23,
+                    cheap_econom: 1 << 33,
+                },
+            ))]),
+            Message::Ping([1; 8]),
+            Message::GetRates(vec![RateReq {
+                currency: Currency::Btc,
+                fiats: vec![Fiat::Usd, Fiat::Unknown(300)],
+            }]),
+            Message::FullFilter(MemFilter(vec![7; 1000])),
+            Message::GetMempool(vec![TxPrefix([1, 2])]),
+            Message::Unknown {
+                id: 1000,
+                payload: vec![1, 2, 3],
+            },
+            Message::GetPeers.tagged(300),
+        ]);
+        for msg in msgs {
+            let len = msg.encoded_len_with(CodecKind::Gzip).unwrap();
+            assert_eq!(len, serialize(&msg).len(), "{}", msg);
+        }
+    }
+
+    fn fuzz_samples() -> Vec<Message> {
+        vec![
+            Message::Version(VersionMessage {
+                version: Version::current(),
+                codec: CodecKind::Gzip,
+                time: 1615562102,
+                nonce: [0, 1, 2, 3, 4, 5, 6, 7],
+                scan_blocks: vec![ScanBlock {
+                    currency: Currency::Btc,
+                    version: Version::current(),
+                    scan_height: 674299,
+                    height: 300000,
+                }],
+            }),
+            Message::Filters(FiltersResp {
+                currency: Currency::Btc,
+                filters: vec![Filter {
+                    block_id: b"12345678123456781234567812345678".to_vec(),
+                    filter: b"abcd".to_vec(),
+                }],
+            }),
+            Message::Peers(vec![Address::OnionV3(
+                OnionV3Address::new(*b"jamie22ezawwi5r3o7lrgsno43jj7vq5en74czuw6wfmjzkhjjryxnid")
+                    .unwrap(),
+                9150,
+            )]),
+            Message::PeersV2(vec![
+                Address::I2p([3; 32], 8667),
+                Address::Cjdns("fc00::1".parse().unwrap(), 8667),
+            ]),
+            Message::PeerIntroduceV2(vec![Address::Hostname("example.com".to_owned(), 8667)]),
+            Message::GetPeerRecords,
+            Message::PeerRecords(vec![PeerRecord {
+                address: Address::I2p([3; 32], 8667),
+                last_seen: 1621437700,
+                currencies: vec![Currency::Btc, Currency::Unknown(100)],
+                services: Services::MEMPOOL,
+            }]),
+            Message::Fee(vec![FeeResp::Other((
+                Currency::Dash,
+                FeeOther {
+                    fast: 4,
+                    moderate: 8,
+                    cheap: 15,
+                },
+            ))]),
+            Message::Reject(RejectMessage {
+                id: 2,
+                data: RejectData::InternalError,
+                message: "Something went wrong".to_string(),
+            }),
+            Message::Rates(vec![RateResp {
+                currency: Currency::Btc,
+                rates: vec![FiatRate {
+                    fiat: Fiat::Usd,
+                    rate: Rate::new(6500323),
+                }],
+            }]),
+            Message::MemFilters(vec![FilterPrefixPair {
+                prefix: TxPrefix([1, 2]),
+                filter: MemFilter(vec![1, 2, 3]),
+            }]),
+            Message::MempoolChunk(MempoolChunkResp {
+                prefix: TxPrefix([9, 128]),
+                txs: vec![vec![1, 2, 3], vec![4, 5]],
+            }),
+        ]
+    }
+
+    /// Decoding of arbitrary bytes must fail gracefully instead of panic
+    fn decode_all(bytes: &[u8]) {
+        let _ = deserialize::<Message>(bytes);
+        let _ = Message::from_frame(bytes);
+        let _ = Message::read_from(bytes);
+    }
+
+    #[test]
+    fn fuzz_random_bytes() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..10000 {
+            let n = rng.gen_range(0..256);
+            let bytes: Vec<u8> = (0..n).map(|_| rng.gen()).collect();
+            decode_all(&bytes);
+        }
+    }
+
+    #[test]
+    fn fuzz_random_payloads() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for id in 0..32 {
+            for _ in 0..500 {
+                let n = rng.gen_range(0..128);
+                let mut bytes = serialize(&VarInt(id));
+                if Message::has_payload(id as u32) {
+                    bytes.extend(serialize(&VarInt(n)));
+                }
+                bytes.extend((0..n).map(|_| rng.gen::<u8>()));
+                decode_all(&bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_mutated_messages() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for msg in fuzz_samples() {
+            let bytes = serialize(&msg);
+            assert_eq!(Message::from_frame(&bytes).unwrap(), msg);
+            for _ in 0..1000 {
+                let mut mutated = bytes.clone();
+                for _ in 0..rng.gen_range(1..4) {
+                    let i = rng.gen_range(0..mutated.len());
+                    match rng.gen_range(0..3) {
+                        0 => mutated[i] = rng.gen(),
+                        1 => mutated.truncate(i.max(1)),
+                        _ => mutated.insert(i, rng.gen()),
+                    }
+                }
+                decode_all(&mutated);
+            }
+        }
+    }
 }
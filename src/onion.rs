@@ -0,0 +1,226 @@
+//! Tor onion service v3 addresses. The address is 56 base32 characters that encode ed25519
+//! public key of the service, two bytes of checksum and version byte, see `rend-spec-v3.txt`.
+use sha3::{Digest, Sha3_256};
+use std::convert::TryFrom;
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Length of the address in characters, without ".onion" suffix
+pub const ONION_V3_LEN: usize = 56;
+
+/// Version byte of v3 addresses
+const VERSION: u8 = 3;
+
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Lowercase base32 without padding, the form of onion and `.b32.i2p` names
+pub(crate) fn base32_encode(bytes: &[u8]) -> String {
+    let mut chars = String::with_capacity(bytes.len() * 8 / 5 + 1);
+    let mut bits = 0u32;
+    let mut nbits = 0;
+    for b in bytes {
+        bits = (bits << 8) | *b as u32;
+        nbits += 8;
+        while nbits >= 5 {
+            nbits -= 5;
+            chars.push(ALPHABET[((bits >> nbits) & 0x1f) as usize] as char);
+        }
+    }
+    if nbits > 0 {
+        chars.push(ALPHABET[((bits << (5 - nbits)) & 0x1f) as usize] as char);
+    }
+    chars
+}
+
+/// Decode base32 in any case, `None` if a character is not in the alphabet. Bits that don't
+/// make a whole byte are dropped.
+pub(crate) fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let mut bits = 0u32;
+    let mut nbits = 0;
+    for c in s.bytes() {
+        let c = c.to_ascii_lowercase();
+        let value = ALPHABET.iter().position(|a| *a == c)?;
+        bits = (bits << 5) | value as u32;
+        nbits += 5;
+        if nbits >= 8 {
+            nbits -= 8;
+            bytes.push((bits >> nbits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// Reason why the address is invalid
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OnionAddressError {
+    /// Address doesn't have 56 characters
+    Length(usize),
+    /// Character at given position is not in base32 alphabet
+    Character(usize),
+    /// Version byte is not 3
+    Version(u8),
+    /// Checksum doesn't match the public key
+    Checksum,
+}
+
+impl Display for OnionAddressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OnionAddressError::Length(len) => write!(
+                f,
+                "onion address has {} characters instead of {}",
+                len, ONION_V3_LEN
+            ),
+            OnionAddressError::Character(i) => {
+                write!(f, "onion address has invalid character at {}", i)
+            }
+            OnionAddressError::Version(v) => write!(f, "onion address has version {}", v),
+            OnionAddressError::Checksum => write!(f, "onion address checksum doesn't match"),
+        }
+    }
+}
+
+impl error::Error for OnionAddressError {}
+
+/// Validated onion v3 address in lowercase, without ".onion" suffix
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub struct OnionV3Address([u8; ONION_V3_LEN]);
+
+impl OnionV3Address {
+    /// Validate address characters
+    pub fn new(chars: [u8; ONION_V3_LEN]) -> Result<Self, OnionAddressError> {
+        if let Some(i) = chars.iter().position(|c| !ALPHABET.contains(c)) {
+            return Err(OnionAddressError::Character(i));
+        }
+        let addr = OnionV3Address(chars);
+        let decoded = addr.decode();
+        let key = <[u8; 32]>::try_from(&decoded[..32]).expect("35 bytes are decoded");
+        if decoded[34] != VERSION {
+            return Err(OnionAddressError::Version(decoded[34]));
+        }
+        if decoded[32..34] != checksum(&key) {
+            return Err(OnionAddressError::Checksum);
+        }
+        Ok(addr)
+    }
+
+    /// Address of the service with given ed25519 public key
+    pub fn from_public_key(key: [u8; 32]) -> Self {
+        let mut decoded = key.to_vec();
+        decoded.extend_from_slice(&checksum(&key));
+        decoded.push(VERSION);
+        let chars = <[u8; ONION_V3_LEN]>::try_from(base32_encode(&decoded).as_bytes())
+            .expect("35 bytes encode to 56 characters");
+        OnionV3Address(chars)
+    }
+
+    /// Ed25519 public key of the service
+    pub fn public_key(&self) -> [u8; 32] {
+        <[u8; 32]>::try_from(&self.decode()[..32]).expect("35 bytes are decoded")
+    }
+
+    /// Public key, checksum and version byte
+    fn decode(&self) -> Vec<u8> {
+        base32_decode(self.as_str()).expect("characters are in base32 alphabet")
+    }
+
+    pub fn as_bytes(&self) -> &[u8; ONION_V3_LEN] {
+        &self.0
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).expect("base32 alphabet is ASCII")
+    }
+}
+
+/// First two bytes of SHA3-256 of ".onion checksum", public key and version
+fn checksum(key: &[u8; 32]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(key);
+    hasher.update([VERSION]);
+    let hash = hasher.finalize();
+    [hash[0], hash[1]]
+}
+
+impl TryFrom<[u8; ONION_V3_LEN]> for OnionV3Address {
+    type Error = OnionAddressError;
+
+    fn try_from(chars: [u8; ONION_V3_LEN]) -> Result<Self, OnionAddressError> {
+        OnionV3Address::new(chars)
+    }
+}
+
+impl Display for OnionV3Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parses the address with or without ".onion" suffix in any case
+impl FromStr for OnionV3Address {
+    type Err = OnionAddressError;
+
+    fn from_str(s: &str) -> Result<Self, OnionAddressError> {
+        let s = s.strip_suffix(".onion").unwrap_or(s);
+        let chars = <[u8; ONION_V3_LEN]>::try_from(s.as_bytes())
+            .map_err(|_| OnionAddressError::Length(s.len()))?;
+        OnionV3Address::new(chars.map(|c| c.to_ascii_lowercase()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ADDRESS: &str = "jamie22ezawwi5r3o7lrgsno43jj7vq5en74czuw6wfmjzkhjjryxnid";
+
+    #[test]
+    fn valid_address() {
+        let addr: OnionV3Address = ADDRESS.parse().unwrap();
+        assert_eq!(addr.to_string(), ADDRESS);
+        assert_eq!(addr.as_bytes(), ADDRESS.as_bytes());
+        assert_eq!(format!("{}.onion", ADDRESS).parse(), Ok(addr));
+        assert_eq!(ADDRESS.to_uppercase().parse(), Ok(addr));
+        assert_eq!(OnionV3Address::from_public_key(addr.public_key()), addr);
+        assert_eq!(&addr.public_key()[..4], &[0x48, 0x18, 0x82, 0x6b]);
+    }
+
+    #[test]
+    fn base32() {
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+        assert_eq!(base32_decode("MZXW6YTBOI"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("mzxw1"), None);
+        let addr: OnionV3Address = ADDRESS.parse().unwrap();
+        let decoded = base32_decode(ADDRESS).unwrap();
+        assert_eq!(&decoded[..32], &addr.public_key()[..]);
+        assert_eq!(base32_encode(&decoded), ADDRESS);
+    }
+
+    #[test]
+    fn invalid_address() {
+        let parse = |s: &str| s.parse::<OnionV3Address>();
+        assert_eq!(parse("jamie22"), Err(OnionAddressError::Length(7)));
+        let mut chars = <[u8; ONION_V3_LEN]>::try_from(ADDRESS.as_bytes()).unwrap();
+        chars[3] = b'1';
+        assert_eq!(
+            OnionV3Address::new(chars),
+            Err(OnionAddressError::Character(3))
+        );
+        chars[3] = b'a';
+        assert_eq!(OnionV3Address::new(chars), Err(OnionAddressError::Checksum));
+        // Last character holds version bits
+        let mut chars = <[u8; ONION_V3_LEN]>::try_from(ADDRESS.as_bytes()).unwrap();
+        chars[55] = b'b';
+        assert_eq!(
+            OnionV3Address::new(chars),
+            Err(OnionAddressError::Version(1))
+        );
+        assert_eq!(
+            OnionV3Address::new([b'7'; ONION_V3_LEN]),
+            Err(OnionAddressError::Version(0xff))
+        );
+    }
+}
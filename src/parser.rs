@@ -0,0 +1,197 @@
+//! Incremental parser of protocol messages that doesn't depend on any IO. Useful for event loops
+//! and FFI bindings where bytes arrive in arbitrary chunks.
+use crate::error::ProtocolError;
+use crate::message::{CodecKind, DecodeOptions, FrameHeader, Message};
+
+/// Maximum size of frame header: two varints of at most 9 bytes each.
+const MAX_HEADER_LEN: usize = 18;
+
+/// Push parser of message frames.
+///
+/// Feed received bytes with `push` and take decoded messages with `next`. Parser stops consuming
+/// input at the end of each frame until the decoded message is taken out, so the caller should
+/// push the rest of the chunk again after that:
+///
+/// ```
+/// use ergvein_protocol::parser::MessageParser;
+///
+/// let mut parser = MessageParser::new();
+/// let mut chunk: &[u8] = &[0x01, 0x05, 0x0b];
+/// while !chunk.is_empty() {
+///     let n = parser.push(chunk);
+///     chunk = &chunk[n..];
+///     for msg in &mut parser {
+///         println!("{}", msg.unwrap());
+///     }
+/// }
+/// assert_eq!(parser.consumed(), 3);
+/// assert_eq!(parser.buffered(), 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct MessageParser {
+    buf: Vec<u8>,
+    /// Size of frame being collected, known after its header is parsed
+    frame_len: Option<usize>,
+    ready: Option<Result<Message, ProtocolError>>,
+    /// Framing is lost after malformed header and nothing more can be parsed
+    poisoned: bool,
+    consumed: u64,
+    options: DecodeOptions,
+}
+
+impl MessageParser {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Parser that applies given limits when decoding payloads
+    pub fn with_options(options: DecodeOptions) -> Self {
+        MessageParser {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Feed the parser with next chunk of bytes. Returns amount of bytes consumed from `data`,
+    /// which is less than its length when a message is completed and not taken yet.
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let mut consumed = 0;
+        while !self.poisoned && self.ready.is_none() && consumed < data.len() {
+            let rest = &data[consumed..];
+            match self.frame_len {
+                None => {
+                    let take = rest.len().min(MAX_HEADER_LEN - self.buf.len());
+                    self.buf.extend_from_slice(&rest[..take]);
+                    consumed += take;
+                    match FrameHeader::peek(&self.buf) {
+                        Ok(None) => (),
+                        Ok(Some(header)) => {
+                            // Return bytes of the next frame that were taken with the header
+                            let extra = self.buf.len().saturating_sub(header.frame_len());
+                            self.buf.truncate(self.buf.len() - extra);
+                            consumed -= extra;
+                            self.frame_len = Some(header.frame_len());
+                        }
+                        Err(e) => {
+                            self.buf.clear();
+                            self.ready = Some(Err(e));
+                            self.poisoned = true;
+                        }
+                    }
+                }
+                Some(frame_len) => {
+                    let take = rest.len().min(frame_len - self.buf.len());
+                    self.buf.extend_from_slice(&rest[..take]);
+                    consumed += take;
+                }
+            }
+            if self.frame_len == Some(self.buf.len()) {
+                self.ready = Some(Message::from_frame_with(&self.buf, &self.options));
+                self.buf.clear();
+                self.frame_len = None;
+            }
+        }
+        self.consumed += consumed as u64;
+        consumed
+    }
+
+    /// Total amount of bytes consumed by the parser
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// Amount of consumed bytes that belong to incomplete frame
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Parser met malformed frame header and cannot find boundary of next frame
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Switch codec of compressed payloads after it is agreed in version exchange. Message that
+    /// is already completed keeps the codec it was decoded with.
+    pub fn set_payload_codec(&mut self, codec: CodecKind) {
+        self.options.codec = codec;
+    }
+}
+
+impl Iterator for MessageParser {
+    type Item = Result<Message, ProtocolError>;
+
+    /// Take decoded message if a frame is completed. Payload errors are local to their frame and
+    /// parsing continues after them.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.ready.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{serialize, Currency, FiltersReq, MAX_MESSAGE_SIZE};
+    use rand::Rng;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::VersionAck,
+            Message::GetFilters(FiltersReq {
+                currency: Currency::Btc,
+                start: 445123,
+                amount: 2000,
+            }),
+            Message::GetPeers,
+            Message::Ping([0xCF, 0x78, 0x06, 0, 0, 0, 0, 0]),
+            Message::GetFee(vec![Currency::Btc, Currency::Dash]),
+        ]
+    }
+
+    #[test]
+    fn random_chunks() {
+        let msgs = messages();
+        let bytes: Vec<u8> = msgs.iter().flat_map(serialize).collect();
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let mut parser = MessageParser::new();
+            let mut parsed = vec![];
+            let mut i = 0;
+            while i < bytes.len() {
+                let end = (i + rng.gen_range(1..8)).min(bytes.len());
+                let mut chunk = &bytes[i..end];
+                while !chunk.is_empty() {
+                    let n = parser.push(chunk);
+                    chunk = &chunk[n..];
+                    parsed.extend(&mut parser);
+                }
+                i = end;
+            }
+            let parsed: Vec<Message> = parsed.into_iter().map(Result::unwrap).collect();
+            assert_eq!(parsed, msgs);
+            assert_eq!(parser.consumed(), bytes.len() as u64);
+            assert_eq!(parser.buffered(), 0);
+        }
+    }
+
+    #[test]
+    fn stops_at_frame_boundary() {
+        let bytes: Vec<u8> = messages().iter().flat_map(serialize).collect();
+        let mut parser = MessageParser::new();
+        assert_eq!(parser.push(&bytes), 1);
+        assert_eq!(parser.push(&bytes[1..]), 0);
+        assert_eq!(parser.next().unwrap().unwrap(), Message::VersionAck);
+        assert_eq!(parser.push(&bytes[1..]), 11);
+    }
+
+    #[test]
+    fn oversized_header() {
+        let mut bytes = vec![3, 0xFE];
+        bytes.extend_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+        let mut parser = MessageParser::new();
+        assert_eq!(parser.push(&bytes), bytes.len());
+        assert!(parser.next().unwrap().is_err());
+        assert!(parser.is_poisoned());
+        assert_eq!(parser.push(&[1]), 0);
+        assert!(parser.next().is_none());
+    }
+}
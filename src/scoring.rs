@@ -0,0 +1,293 @@
+//! Misbehaviour scores of peers. Every offence adds penalty points to the address of the peer,
+//! points decay with time and a peer that reaches the threshold is banned for a while. The ban
+//! list is consulted by `client::connect` and `AddressBook::candidates_unbanned`.
+use crate::client::ClientError;
+use crate::error::{ProtocolError, ProtocolErrorKind};
+use crate::handshake::HandshakeError;
+use crate::message::*;
+use std::collections::HashMap;
+
+/// Class of peer misbehaviour
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum Offence {
+    /// Frame or payload can't be decoded
+    Malformed,
+    /// Payload or decompressed data exceeds limits
+    Oversized,
+    /// Filter doesn't match the block it is claimed to be built from
+    InvalidFilter,
+    /// Peer rejected our message
+    Rejected,
+    /// Peer sent message that answers nothing or broke handshake rules
+    Unexpected,
+}
+
+impl Offence {
+    /// Offence of a peer that sent the frame, `None` if the failure is not the peer's fault.
+    /// Closing the connection between frames is not an offence.
+    pub fn of_protocol_error(e: &ProtocolError) -> Option<Offence> {
+        if e.is_end_of_stream() {
+            return None;
+        }
+        match e.kind {
+            ProtocolErrorKind::Io(_) | ProtocolErrorKind::Encode(_) => None,
+            ProtocolErrorKind::Oversize { .. }
+            | ProtocolErrorKind::OversizedVector { .. }
+            | ProtocolErrorKind::DecompressedTooLarge
+            | ProtocolErrorKind::AmountExceedsPayload => Some(Offence::Oversized),
+            _ => Some(Offence::Malformed),
+        }
+    }
+
+    /// Offence of a peer that caused the failure of request or handshake
+    pub fn of_client_error(e: &ClientError) -> Option<Offence> {
+        match e {
+            ClientError::Protocol(e) => Offence::of_protocol_error(e),
+            ClientError::Rejected(_) => Some(Offence::Rejected),
+            ClientError::Unexpected(_) => Some(Offence::Unexpected),
+            ClientError::Handshake(HandshakeError::SelfConnection) => None,
+            ClientError::Handshake(_) => Some(Offence::Unexpected),
+            _ => None,
+        }
+    }
+}
+
+/// Penalty points per offence
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Penalties {
+    pub malformed: u32,
+    pub oversized: u32,
+    pub invalid_filter: u32,
+    pub rejected: u32,
+    pub unexpected: u32,
+}
+
+impl Penalties {
+    pub fn of(&self, offence: Offence) -> u32 {
+        match offence {
+            Offence::Malformed => self.malformed,
+            Offence::Oversized => self.oversized,
+            Offence::InvalidFilter => self.invalid_filter,
+            Offence::Rejected => self.rejected,
+            Offence::Unexpected => self.unexpected,
+        }
+    }
+}
+
+impl Default for Penalties {
+    fn default() -> Self {
+        Penalties {
+            malformed: 20,
+            oversized: 50,
+            invalid_filter: 100,
+            rejected: 5,
+            unexpected: 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ScoringConfig {
+    pub penalties: Penalties,
+    /// Points at which the peer is banned
+    pub ban_threshold: u32,
+    /// Points that are forgiven every hour
+    pub decay_per_hour: u32,
+    /// Duration of a ban in seconds
+    pub ban_duration: u64,
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        ScoringConfig {
+            penalties: Penalties::default(),
+            ban_threshold: 100,
+            decay_per_hour: 10,
+            ban_duration: 24 * 60 * 60,
+        }
+    }
+}
+
+/// Points of a peer at the time they were last updated
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Score {
+    points: u32,
+    updated: Timestamp,
+}
+
+impl Score {
+    fn decayed(&self, decay_per_hour: u32, now: Timestamp) -> u32 {
+        let elapsed = now.saturating_sub(self.updated);
+        let decay = elapsed.saturating_mul(decay_per_hour as u64) / 3600;
+        self.points
+            .saturating_sub(decay.min(u32::MAX as u64) as u32)
+    }
+}
+
+/// Scores and bans of peers
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Scoreboard {
+    config: ScoringConfig,
+    scores: HashMap<Address, Score>,
+    /// Banned addresses and times the bans expire
+    bans: HashMap<Address, Timestamp>,
+}
+
+impl Scoreboard {
+    pub fn new(config: ScoringConfig) -> Self {
+        Scoreboard {
+            config,
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    /// Add penalty of the offence to the peer. Returns whether the peer is banned now.
+    pub fn penalize(&mut self, address: &Address, offence: Offence, now: Timestamp) -> bool {
+        let points = self
+            .score(address, now)
+            .saturating_add(self.config.penalties.of(offence));
+        if points >= self.config.ban_threshold {
+            self.scores.remove(address);
+            self.ban(
+                address.clone(),
+                now.saturating_add(self.config.ban_duration),
+            );
+            return true;
+        }
+        let score = Score {
+            points,
+            updated: now,
+        };
+        self.scores.insert(address.clone(), score);
+        self.is_banned(address, now)
+    }
+
+    /// Penalize the peer if the error is its fault. Returns whether the peer is banned now.
+    pub fn penalize_error(&mut self, address: &Address, e: &ClientError, now: Timestamp) -> bool {
+        match Offence::of_client_error(e) {
+            Some(offence) => self.penalize(address, offence, now),
+            None => self.is_banned(address, now),
+        }
+    }
+
+    /// Current points of the peer after decay
+    pub fn score(&self, address: &Address, now: Timestamp) -> u32 {
+        self.scores
+            .get(address)
+            .map_or(0, |score| score.decayed(self.config.decay_per_hour, now))
+    }
+
+    /// Ban the peer until given time regardless of its score
+    pub fn ban(&mut self, address: Address, until: Timestamp) {
+        let expires = self.bans.entry(address).or_insert(until);
+        *expires = (*expires).max(until);
+    }
+
+    pub fn unban(&mut self, address: &Address) {
+        self.bans.remove(address);
+    }
+
+    pub fn is_banned(&self, address: &Address, now: Timestamp) -> bool {
+        self.banned_until(address, now).is_some()
+    }
+
+    /// Time when ban of the peer expires, `None` if the peer is not banned
+    pub fn banned_until(&self, address: &Address, now: Timestamp) -> Option<Timestamp> {
+        self.bans.get(address).copied().filter(|until| *until > now)
+    }
+
+    /// Forget expired bans and fully decayed scores
+    pub fn prune(&mut self, now: Timestamp) {
+        self.bans.retain(|_, until| *until > now);
+        let decay_per_hour = self.config.decay_per_hour;
+        self.scores
+            .retain(|_, score| score.decayed(decay_per_hour, now) > 0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn address() -> Address {
+        Address::Ipv4(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 8667))
+    }
+
+    #[test]
+    fn decay_and_ban() {
+        let mut scores = Scoreboard::default();
+        let addr = address();
+        assert!(!scores.penalize(&addr, Offence::Oversized, 0));
+        assert_eq!(scores.score(&addr, 0), 50);
+        assert_eq!(scores.score(&addr, 1800), 45);
+        assert_eq!(scores.score(&addr, 3600), 40);
+        assert!(!scores.penalize(&addr, Offence::Oversized, 3600));
+        assert_eq!(scores.score(&addr, 3600), 90);
+        assert!(scores.penalize(&addr, Offence::Malformed, 3600));
+        assert_eq!(scores.banned_until(&addr, 3600), Some(3600 + 24 * 60 * 60));
+        // Score is reset by the ban, so it is not banned again right after expiration
+        assert_eq!(scores.score(&addr, 3600), 0);
+        assert!(!scores.is_banned(&addr, 3600 + 24 * 60 * 60));
+
+        scores.prune(3600 + 24 * 60 * 60);
+        assert_eq!(scores, Scoreboard::default());
+    }
+
+    #[test]
+    fn extreme_config() {
+        let mut scores = Scoreboard::new(ScoringConfig {
+            penalties: Penalties {
+                unexpected: u32::MAX,
+                ..Penalties::default()
+            },
+            ban_threshold: u32::MAX,
+            decay_per_hour: 0,
+            ban_duration: u64::MAX,
+        });
+        let addr = address();
+        assert!(!scores.penalize(&addr, Offence::Rejected, 100));
+        // Points and end of the ban stop at the maximum instead of overflowing
+        assert!(scores.penalize(&addr, Offence::Unexpected, 100));
+        assert_eq!(scores.banned_until(&addr, 100), Some(u64::MAX));
+    }
+
+    #[test]
+    fn penalties_of_errors() {
+        let mut scores = Scoreboard::new(ScoringConfig {
+            ban_threshold: 40,
+            ..ScoringConfig::default()
+        });
+        let addr = address();
+        let oversize = ProtocolError::new(
+            Some(3),
+            "length",
+            1,
+            ProtocolErrorKind::Oversize {
+                size: 1 << 30,
+                max: MAX_MESSAGE_SIZE,
+            },
+        );
+        let io = ProtocolError::new(
+            None,
+            "id",
+            0,
+            ProtocolErrorKind::Io(std::io::ErrorKind::ConnectionReset.into()),
+        );
+        assert_eq!(
+            Offence::of_protocol_error(&oversize),
+            Some(Offence::Oversized)
+        );
+        assert_eq!(Offence::of_protocol_error(&io), None);
+        let closed = ProtocolError::new(None, "id", 0, ProtocolErrorKind::Truncated);
+        let cut = ProtocolError::new(None, "id", 1, ProtocolErrorKind::Truncated);
+        assert_eq!(Offence::of_protocol_error(&closed), None);
+        assert_eq!(Offence::of_protocol_error(&cut), Some(Offence::Malformed));
+        assert!(!scores.penalize_error(&addr, &ClientError::Protocol(io), 0));
+        assert!(!scores.penalize_error(&addr, &ClientError::Timeout, 0));
+        assert_eq!(scores.score(&addr, 0), 0);
+        assert!(!scores.penalize_error(&addr, &ClientError::Unexpected(Message::GetPeers), 0));
+        assert!(scores.penalize_error(&addr, &ClientError::Protocol(oversize), 0));
+    }
+}
@@ -0,0 +1,88 @@
+//! Serde representations of protocol types that are not derived: bytes are hex strings, rates
+//! are decimal strings and addresses are `host:port` strings.
+use crate::message::{Address, Rate};
+use consensus_encode::util::hex::{FromHex, ToHex};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
+
+/// Bytes as hex string, works for vectors and fixed size arrays
+pub(crate) mod hex_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer, T: AsRef<[u8]>>(v: &T, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&v.as_ref().to_hex())
+    }
+
+    pub fn deserialize<'de, D, T>(d: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: TryFrom<Vec<u8>>,
+    {
+        let bytes = Vec::<u8>::from_hex(&String::deserialize(d)?).map_err(D::Error::custom)?;
+        let len = bytes.len();
+        T::try_from(bytes).map_err(|_| D::Error::invalid_length(len, &"bytes of fixed length"))
+    }
+}
+
+/// Vector of byte strings as vector of hex strings
+pub(crate) mod hex_vec {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(v: &[Vec<u8>], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(v.iter().map(|b| b.to_hex()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Vec<u8>>, D::Error> {
+        Vec::<String>::deserialize(d)?
+            .iter()
+            .map(|s| Vec::from_hex(s).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// Rate as decimal string with 2 digits after point, e.g. `"65003.23"`
+pub(crate) mod rate {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(r: &Rate, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(&format_args!("{}.{:02}", r.bits / 100, r.bits % 100))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Rate, D::Error> {
+        let s = String::deserialize(d)?;
+        parse(&s).ok_or_else(|| D::Error::invalid_value(serde::de::Unexpected::Str(&s), &"rate"))
+    }
+
+    fn parse(s: &str) -> Option<Rate> {
+        let (int, frac) = match s.find('.') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, "00"),
+        };
+        if int.is_empty() || frac.is_empty() || frac.len() > 2 {
+            return None;
+        }
+        if !int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let cents = if frac.len() == 1 { 10 } else { 1 };
+        let bits = int
+            .parse::<u64>()
+            .ok()?
+            .checked_mul(100)?
+            .checked_add(frac.parse::<u64>().ok()? * cents)?;
+        Some(Rate::new(bits))
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
+    }
+}
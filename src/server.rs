@@ -0,0 +1,480 @@
+//! Indexer side of the protocol. `Session` performs handshake with a connected client and
+//! dispatches its requests to a `Handler` over any `Read + Write` stream.
+use crate::client::send_to;
+use crate::error::{ProtocolError, ProtocolErrorKind};
+use crate::handshake::{Agreement, Handshake, HandshakeError, Role};
+use crate::message::*;
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+
+/// Failure of handler that is sent to the peer as `Reject` with `InternalError` code
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HandlerError(pub String);
+
+impl HandlerError {
+    pub fn new<T: Into<String>>(message: T) -> Self {
+        HandlerError(message.into())
+    }
+
+    fn not_supported(request_id: u32) -> Self {
+        let name = Message::name_from_id(request_id).unwrap_or("request");
+        HandlerError(format!("{} is not supported", name))
+    }
+}
+
+impl Display for HandlerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl error::Error for HandlerError {}
+
+/// Requests that the indexer serves. Requests that are not implemented are rejected.
+pub trait Handler {
+    fn on_get_filters(&mut self, _req: FiltersReq) -> Result<FiltersResp, HandlerError> {
+        Err(HandlerError::not_supported(2))
+    }
+
+    fn on_get_peers(&mut self) -> Result<Vec<Address>, HandlerError> {
+        Err(HandlerError::not_supported(5))
+    }
+
+    /// Known indexers with their freshness and capabilities. By default built from
+    /// `on_get_peers` with unknown time and capabilities.
+    fn on_get_peer_records(&mut self) -> Result<Vec<PeerRecord>, HandlerError> {
+        self.on_get_peers()
+            .map(|addrs| addrs.into_iter().map(PeerRecord::from).collect())
+    }
+
+    fn on_get_fee(&mut self, _currencies: Vec<Currency>) -> Result<Vec<FeeResp>, HandlerError> {
+        Err(HandlerError::not_supported(7))
+    }
+
+    fn on_get_rates(&mut self, _reqs: Vec<RateReq>) -> Result<Vec<RateResp>, HandlerError> {
+        Err(HandlerError::not_supported(13))
+    }
+
+    fn on_get_full_filter(&mut self) -> Result<MemFilter, HandlerError> {
+        Err(HandlerError::not_supported(16))
+    }
+
+    fn on_get_mem_filters(&mut self) -> Result<Vec<FilterPrefixPair>, HandlerError> {
+        Err(HandlerError::not_supported(18))
+    }
+
+    /// Mempool transactions with given prefixes, every chunk is sent as separate message
+    fn on_get_mempool(
+        &mut self,
+        _prefixes: Vec<TxPrefix>,
+    ) -> Result<Vec<MempoolChunkResp>, HandlerError> {
+        Err(HandlerError::not_supported(20))
+    }
+
+    /// Peer announced addresses of other indexers
+    fn on_peer_introduce(&mut self, _addrs: Vec<Address>) -> Result<(), HandlerError> {
+        Ok(())
+    }
+
+    /// Any other message that is not a request. Returned messages are sent back.
+    fn on_other(&mut self, _msg: Message) -> Result<Vec<Message>, HandlerError> {
+        Ok(vec![])
+    }
+}
+
+/// Failure that ends the session
+#[derive(Debug)]
+pub enum ServerError {
+    Io(io::Error),
+    /// Frame can't be decoded and the stream can't be parsed further
+    Protocol(ProtocolError),
+    /// Peer speaks major version that we don't support
+    IncompatibleVersion(Version),
+    /// Peer sent message that is not allowed during handshake
+    Unexpected(Message),
+    /// Peer broke other handshake rules, e.g. connection to self
+    Handshake(HandshakeError),
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::Io(e) => write!(f, "io error: {}", e),
+            ServerError::Protocol(e) => e.fmt(f),
+            ServerError::IncompatibleVersion(v) => write!(
+                f,
+                "peer version {} is not compatible with {}",
+                v,
+                Version::current()
+            ),
+            ServerError::Unexpected(msg) => write!(f, "unexpected message: {}", msg),
+            ServerError::Handshake(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ServerError::Io(e) => Some(e),
+            ServerError::Protocol(e) => Some(e),
+            ServerError::Handshake(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Io(e)
+    }
+}
+
+impl From<ProtocolError> for ServerError {
+    fn from(e: ProtocolError) -> Self {
+        ServerError::Protocol(e)
+    }
+}
+
+impl From<HandshakeError> for ServerError {
+    fn from(e: HandshakeError) -> Self {
+        match e {
+            HandshakeError::IncompatibleVersion(v) => ServerError::IncompatibleVersion(v),
+            HandshakeError::Unexpected(msg) => ServerError::Unexpected(msg),
+            e => ServerError::Handshake(e),
+        }
+    }
+}
+
+/// Connection with a client after successful handshake
+#[derive(Debug)]
+pub struct Session<S> {
+    stream: S,
+    agreement: Agreement,
+    options: DecodeOptions,
+}
+
+impl<S: Read + Write> Session<S> {
+    /// Wait for version message of the client, answer it with ours and wait for acknowledgement.
+    /// Incompatible versions are rejected with `VersionNotSupported`.
+    pub fn accept(stream: S, version: VersionMessage) -> Result<Self, ServerError> {
+        let mut handshake = Handshake::new(Role::Server, version);
+        let mut stream = stream;
+        let options = DecodeOptions::default();
+        while !handshake.is_done() {
+            let msg = Message::read_from_with(&mut stream, &options)?;
+            match handshake.handle(msg) {
+                Ok(replies) => {
+                    for msg in replies {
+                        send_to(&mut stream, &msg, options.codec)?;
+                    }
+                }
+                Err(e) => {
+                    if let Some(reject) = e.reject() {
+                        send_to(&mut stream, &Message::Reject(reject), options.codec)?;
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        let agreement = handshake.finish().expect("handshake is done");
+        Ok(Session {
+            stream,
+            options: DecodeOptions {
+                codec: agreement.codec,
+                ..options
+            },
+            agreement,
+        })
+    }
+
+    /// Version message that the client sent during handshake
+    pub fn peer_version(&self) -> &VersionMessage {
+        &self.agreement.peer
+    }
+
+    /// Everything that was agreed during handshake, including currencies that the client is
+    /// interested in
+    pub fn agreement(&self) -> &Agreement {
+        &self.agreement
+    }
+
+    /// Codec of compressed payloads agreed with the client
+    pub fn codec(&self) -> CodecKind {
+        self.options.codec
+    }
+
+    /// Send any message to the client
+    pub fn send(&mut self, msg: &Message) -> Result<(), io::Error> {
+        send_to(&mut self.stream, msg, self.options.codec)
+    }
+
+    fn read(&mut self) -> Result<Message, ProtocolError> {
+        Message::read_from_with(&mut self.stream, &self.options)
+    }
+
+    /// Serve requests until the client closes connection. Malformed frames are rejected and
+    /// the session goes on unless framing is lost.
+    pub fn run<H: Handler>(&mut self, handler: &mut H) -> Result<(), ServerError> {
+        loop {
+            let msg = match self.read() {
+                Ok(msg) => msg,
+                // Stream ended at frame boundary, the client is gone
+                Err(e) if e.is_end_of_stream() => return Ok(()),
+                Err(e) => {
+                    if let ProtocolErrorKind::Io(_) = e.kind {
+                        return Err(e.into());
+                    }
+                    self.send(&e.to_reject_message())?;
+                    if e.is_header() {
+                        return Err(e.into());
+                    }
+                    continue;
+                }
+            };
+            self.handle(handler, msg)?;
+        }
+    }
+
+    /// Dispatch one message to the handler and send replies. Replies to tagged requests are
+    /// tagged with the same request id.
+    pub fn handle<H: Handler>(&mut self, handler: &mut H, msg: Message) -> Result<(), io::Error> {
+        let (request_id, msg) = msg.untagged();
+        let id = msg.id();
+        let replies = match msg {
+            Message::GetFilters(req) => handler
+                .on_get_filters(req)
+                .map(|r| vec![Message::Filters(r)]),
+            Message::GetPeers => {
+                let addr_v2 = self.agreement.addr_v2;
+                handler
+                    .on_get_peers()
+                    .map(|r| vec![Message::peers(r, addr_v2)])
+            }
+            Message::GetPeerRecords => handler
+                .on_get_peer_records()
+                .map(|r| vec![Message::PeerRecords(r)]),
+            Message::GetFee(req) => handler.on_get_fee(req).map(|r| vec![Message::Fee(r)]),
+            Message::GetRates(req) => handler.on_get_rates(req).map(|r| vec![Message::Rates(r)]),
+            Message::GetFullFilter => handler
+                .on_get_full_filter()
+                .map(|r| vec![Message::FullFilter(r)]),
+            Message::GetMemFilters => handler
+                .on_get_mem_filters()
+                .map(|r| vec![Message::MemFilters(r)]),
+            Message::GetMempool(req) => handler
+                .on_get_mempool(req)
+                .map(|r| r.into_iter().map(Message::MempoolChunk).collect()),
+            Message::PeerIntroduce(addrs) | Message::PeerIntroduceV2(addrs) => {
+                handler.on_peer_introduce(addrs).map(|_| vec![])
+            }
+            Message::Ping(nonce) => Ok(vec![Message::Pong(nonce)]),
+            Message::Pong(_) | Message::VersionAck => Ok(vec![]),
+            msg => handler.on_other(msg),
+        };
+        let replies = replies.unwrap_or_else(|e| {
+            vec![Message::Reject(RejectMessage {
+                id,
+                data: RejectData::InternalError,
+                message: e.0,
+            })]
+        });
+        for reply in replies {
+            let reply = match request_id {
+                Some(request_id) => reply.tagged(request_id),
+                None => reply,
+            };
+            match self.send(&reply) {
+                // Reply that can't be encoded is not written at all, so it can be rejected
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                    let reject = Message::Reject(RejectMessage {
+                        id,
+                        data: RejectData::InternalError,
+                        message: e.to_string(),
+                    });
+                    match request_id {
+                        Some(request_id) => self.send(&reject.tagged(request_id))?,
+                        None => self.send(&reject)?,
+                    }
+                }
+                res => res?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the stream back, e.g. to shut it down
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{ClientError, Connection};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    struct Indexer;
+
+    impl Handler for Indexer {
+        fn on_get_filters(&mut self, req: FiltersReq) -> Result<FiltersResp, HandlerError> {
+            if req.currency != Currency::Btc {
+                return Err(HandlerError::new("unknown currency"));
+            }
+            Ok(FiltersResp {
+                currency: req.currency,
+                filters: (0..req.amount)
+                    .map(|i| Filter {
+                        block_id: vec![i as u8; 32],
+                        filter: vec![1; 10],
+                    })
+                    .collect(),
+            })
+        }
+
+        fn on_get_fee(&mut self, currencies: Vec<Currency>) -> Result<Vec<FeeResp>, HandlerError> {
+            // Invalid for anything but bitcoin, the session must reject it
+            Ok(currencies
+                .into_iter()
+                .map(|c| {
+                    let fee = FeeBtc {
+                        fast_conserv: 10,
+                        fast_econom: 8,
+                        moderate_conserv: 5,
+                        moderate_econom: 4,
+                        cheap_conserv: 2,
+                        cheap_econom: 1,
+                    };
+                    FeeResp::Btc((c, fee))
+                })
+                .collect())
+        }
+    }
+
+    fn version() -> VersionMessage {
+        VersionMessage::new([9; 8], vec![])
+    }
+
+    /// Run session with `Indexer` in background and connect to it
+    fn spawn_server() -> (TcpStream, thread::JoinHandle<Result<(), ServerError>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut session = Session::accept(stream, version())?;
+            session.run(&mut Indexer)
+        });
+        (TcpStream::connect(addr).unwrap(), server)
+    }
+
+    #[test]
+    fn session() {
+        let (stream, server) = spawn_server();
+        let mut conn = Connection::handshake(stream, VersionMessage::new([1; 8], vec![])).unwrap();
+        assert_eq!(conn.peer_version().nonce, [9; 8]);
+
+        let resp = conn.get_filters(Currency::Btc, 0, 3).unwrap();
+        assert_eq!(resp.filters.len(), 3);
+        match conn.get_filters(Currency::Dash, 0, 3) {
+            Err(ClientError::Rejected(msg)) => {
+                assert_eq!(msg.id, 2);
+                assert_eq!(msg.message, "unknown currency");
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        match conn.get_fee(&[Currency::Dash]) {
+            Err(ClientError::Rejected(msg)) => assert_eq!(msg.data, RejectData::InternalError),
+            res => panic!("unexpected result {:?}", res),
+        }
+        match conn.get_peers() {
+            Err(ClientError::Rejected(msg)) => {
+                assert_eq!(msg.message, "req peers is not supported")
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        // Tagged requests get tagged replies
+        let reply = conn.request(&Message::Ping([4; 8]).tagged(42)).unwrap();
+        assert_eq!(reply, Message::Pong([4; 8]).tagged(42));
+
+        drop(conn);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn session_malformed_frames() {
+        let (mut stream, server) = spawn_server();
+        let client = VersionMessage::new([1; 8], vec![]);
+        stream
+            .write_all(&serialize(&Message::Version(client)))
+            .unwrap();
+        let read = |stream: &mut TcpStream| Message::read_from(stream).unwrap();
+        assert!(matches!(read(&mut stream), Message::Version(_)));
+        assert_eq!(read(&mut stream), Message::VersionAck);
+        stream.write_all(&serialize(&Message::VersionAck)).unwrap();
+
+        // Payload is broken, but framing is intact: the frame is rejected and session goes on
+        stream.write_all(&[6, 8, 1, 7, 0, 0, 0, 0, 0, 0]).unwrap();
+        match read(&mut stream) {
+            Message::Reject(msg) => {
+                assert_eq!(msg.id, 6);
+                assert_eq!(msg.data, RejectData::PayloadParsing);
+            }
+            msg => panic!("unexpected message {}", msg),
+        }
+        stream
+            .write_all(&serialize(&Message::Ping([1; 8])))
+            .unwrap();
+        assert_eq!(read(&mut stream), Message::Pong([1; 8]));
+
+        // Tagged request with truncated inner frame is rejected with its request id
+        let req = serialize(&Message::GetFilters(FiltersReq {
+            currency: Currency::Btc,
+            start: 0,
+            amount: 3,
+        }));
+        let mut frame = vec![22, req.len() as u8 - 1, 7];
+        frame.extend_from_slice(&req[..req.len() - 2]);
+        stream.write_all(&frame).unwrap();
+        match read(&mut stream).untagged() {
+            (Some(7), Message::Reject(msg)) => {
+                assert_eq!(msg.id, 2);
+                assert_eq!(msg.data, RejectData::PayloadParsing);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // Oversized frame breaks framing and ends the session
+        stream
+            .write_all(&[3, 0xfe, 0xff, 0xff, 0xff, 0xff])
+            .unwrap();
+        match read(&mut stream) {
+            Message::Reject(msg) => assert_eq!(msg.data, RejectData::HeaderParsing),
+            msg => panic!("unexpected message {}", msg),
+        }
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(ServerError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn session_incompatible() {
+        let (mut stream, server) = spawn_server();
+        let mut old = version();
+        old.version.major = 1;
+        stream
+            .write_all(&serialize(&Message::Version(old)))
+            .unwrap();
+        match Message::read_from(&mut stream).unwrap() {
+            Message::Reject(msg) => assert_eq!(msg.data, RejectData::VersionNotSupported),
+            msg => panic!("unexpected message {}", msg),
+        }
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(ServerError::IncompatibleVersion(_))
+        ));
+    }
+}
@@ -0,0 +1,338 @@
+//! Streaming decoding of filters responses. Filters are decompressed and decoded one at a time,
+//! so neither the whole decompressed body nor all filters are kept in memory.
+use crate::error::{DecodeField, FieldError, ProtocolError, ProtocolErrorKind};
+use crate::message::*;
+use std::cell::RefCell;
+use std::io::{self, Read};
+use std::rc::Rc;
+
+/// Type id of filters response
+const FILTERS_ID: u32 = 3;
+
+/// Compressed payload shared with decompressor, so the rest of it can be skipped afterwards
+type Source<'a> = Rc<RefCell<io::Take<Box<dyn Read + 'a>>>>;
+
+struct SharedSource<'a>(Source<'a>);
+
+impl Read for SharedSource<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+/// Message read from blocking reader, filters responses are decoded lazily
+pub enum StreamedMessage<'a> {
+    Filters(FiltersStream<'a>),
+    Other(Message),
+}
+
+/// Read next message from blocking reader without buffering filters response. The reader is
+/// positioned at the next frame once the returned stream is exhausted or dropped.
+pub fn read_message<'a, R: Read + 'a>(
+    mut r: R,
+    options: &DecodeOptions,
+) -> Result<StreamedMessage<'a>, ProtocolError> {
+    let header = FrameHeader::read_from(&mut r)?;
+    if header.id == FILTERS_ID {
+        FiltersStream::start(
+            Box::new(r),
+            header.payload_len as u64,
+            header.header_len as u64,
+            options,
+        )
+        .map(StreamedMessage::Filters)
+    } else {
+        Message::read_payload_with(&header, r, options).map(StreamedMessage::Other)
+    }
+}
+
+/// Iterator over filters of compressed filters response
+pub struct FiltersStream<'a> {
+    pub currency: Currency,
+    /// Amount of filters declared in the payload
+    pub amount: u64,
+    left: u64,
+    source: Source<'a>,
+    payload_len: u64,
+    header_len: u64,
+    /// Decompressed filters limited by `max_decompressed_size`, `None` when stream is over
+    decoder: Option<io::Take<Box<dyn Read + 'a>>>,
+}
+
+impl<'a> FiltersStream<'a> {
+    /// Start decoding of filters response payload. The reader must end where the payload ends,
+    /// as the rest of it is skipped when the stream is over.
+    pub fn new<R: Read + 'a>(r: R, options: &DecodeOptions) -> Result<Self, ProtocolError> {
+        FiltersStream::start(Box::new(r), u64::MAX, 0, options)
+    }
+
+    fn start(
+        r: Box<dyn Read + 'a>,
+        payload_len: u64,
+        header_len: u64,
+        options: &DecodeOptions,
+    ) -> Result<Self, ProtocolError> {
+        let source = Rc::new(RefCell::new(r.take(payload_len)));
+        let mut stream = FiltersStream {
+            currency: Currency::Btc,
+            amount: 0,
+            left: 0,
+            source: source.clone(),
+            payload_len,
+            header_len,
+            decoder: None,
+        };
+        let mut reader = SharedSource(source);
+        let header = Currency::consensus_decode(&mut reader)
+            .field("currency")
+            .and_then(|currency| {
+                let amount = VarInt::consensus_decode(&mut reader).field("amount")?.0;
+                Ok((currency, amount))
+            });
+        let (currency, amount) = match header {
+            Ok(v) => v,
+            Err(e) => return Err(stream.fail(FieldError::from_decode("payload", e))),
+        };
+        let decoder = options
+            .codec
+            .codec()
+            .and_then(|codec| codec.decoder(Box::new(reader)).ok())
+            .ok_or_else(|| FieldError::new("compressed body", ProtocolErrorKind::Decompression));
+        match decoder {
+            Ok(decoder) => {
+                stream.currency = currency;
+                stream.amount = amount;
+                stream.left = amount;
+                stream.decoder = Some(decoder.take(options.max_decompressed_size as u64));
+                Ok(stream)
+            }
+            Err(e) => Err(stream.fail(e)),
+        }
+    }
+
+    /// Skip the rest of payload without decoding it
+    pub fn finish(mut self) -> Result<(), ProtocolError> {
+        self.skip_rest().map_err(|e| self.error(skip_error(e)))
+    }
+
+    /// Bytes of payload consumed so far
+    fn offset(&self) -> u64 {
+        self.header_len + (self.payload_len - self.source.borrow().limit())
+    }
+
+    fn error(&self, e: FieldError) -> ProtocolError {
+        ProtocolError::payload(FILTERS_ID, self.offset(), e)
+    }
+
+    /// Convert error and skip the rest of payload to keep the reader at frame boundary
+    fn fail(&mut self, e: FieldError) -> ProtocolError {
+        let e = self.error(e);
+        let _ = self.skip_rest();
+        e
+    }
+
+    fn skip_rest(&mut self) -> Result<(), io::Error> {
+        if self.decoder.take().is_some() || self.source.borrow().limit() > 0 {
+            io::copy(&mut *self.source.borrow_mut(), &mut io::sink())?;
+        }
+        Ok(())
+    }
+}
+
+/// Decode next filter from decompressed data that is limited to the decompression budget
+fn read_filter(d: &mut io::Take<Box<dyn Read + '_>>) -> Result<Filter, FieldError> {
+    let block_id: [u8; 32] =
+        Decodable::consensus_decode(&mut *d).map_err(|e| inflate_error(d, e))?;
+    let len = VarInt::consensus_decode(&mut *d)
+        .map_err(|e| inflate_error(d, e))?
+        .0;
+    if len > d.limit() {
+        return Err(FieldError::new(
+            "compressed body",
+            ProtocolErrorKind::DecompressedTooLarge,
+        ));
+    }
+    let mut filter = vec![];
+    (&mut *d)
+        .take(len)
+        .read_to_end(&mut filter)
+        .map_err(|e| inflate_error(d, e.into()))?;
+    if (filter.len() as u64) < len {
+        return Err(FieldError::new("filters", ProtocolErrorKind::Truncated));
+    }
+    Ok(Filter {
+        block_id: block_id.to_vec(),
+        filter,
+    })
+}
+
+/// Errors of decompressor are reported as corrupted payload, exhausted budget as too large one
+fn inflate_error(d: &io::Take<Box<dyn Read + '_>>, e: Error) -> FieldError {
+    match e {
+        Error::Io(ref io) if io.kind() == io::ErrorKind::UnexpectedEof && d.limit() == 0 => {
+            FieldError::new("compressed body", ProtocolErrorKind::DecompressedTooLarge)
+        }
+        Error::Io(ref io) if io.kind() != io::ErrorKind::UnexpectedEof => {
+            FieldError::new("compressed body", ProtocolErrorKind::Decompression)
+        }
+        e => FieldError::from_decode("filters", e),
+    }
+}
+
+/// Failure to skip the rest of compressed payload
+fn skip_error(e: io::Error) -> FieldError {
+    FieldError::from_decode("payload", e.into())
+}
+
+impl Iterator for FiltersStream<'_> {
+    type Item = Result<Filter, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let decoder = self.decoder.as_mut()?;
+        if self.left == 0 {
+            return self
+                .skip_rest()
+                .err()
+                .map(|e| Err(self.error(skip_error(e))));
+        }
+        match read_filter(decoder) {
+            Ok(filter) => {
+                self.left -= 1;
+                Some(Ok(filter))
+            }
+            Err(e) => Some(Err(self.fail(e))),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.decoder.is_some() {
+            (0, Some(self.left as usize))
+        } else {
+            (0, Some(0))
+        }
+    }
+}
+
+impl Drop for FiltersStream<'_> {
+    fn drop(&mut self) {
+        let _ = self.skip_rest();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression::{Gzip, PayloadCodec};
+    use crate::error::ProtocolErrorKind;
+    use rand::{thread_rng, Rng};
+    use std::io::Cursor;
+
+    fn random_filters(n: usize) -> FiltersResp {
+        let mut rng = thread_rng();
+        FiltersResp {
+            currency: Currency::Btc,
+            filters: (0..n)
+                .map(|_| Filter {
+                    block_id: (0..32).map(|_| rng.gen()).collect(),
+                    filter: (0..rng.gen_range(0..200)).map(|_| rng.gen()).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn expect_filters(msg: StreamedMessage) -> FiltersStream {
+        match msg {
+            StreamedMessage::Filters(stream) => stream,
+            StreamedMessage::Other(msg) => panic!("unexpected message {}", msg),
+        }
+    }
+
+    fn expect_other(msg: StreamedMessage) -> Message {
+        match msg {
+            StreamedMessage::Other(msg) => msg,
+            StreamedMessage::Filters(_) => panic!("unexpected filters"),
+        }
+    }
+
+    #[test]
+    fn stream_frames() {
+        let resp = random_filters(100);
+        let mut bytes = serialize(&Message::Filters(resp.clone()));
+        bytes.extend(serialize(&Message::Ping([1; 8])));
+        bytes.extend(serialize(&Message::Filters(resp.clone())));
+        bytes.extend(serialize(&Message::VersionAck));
+        let options = DecodeOptions::default();
+        let mut r = Cursor::new(bytes);
+
+        let stream = expect_filters(read_message(&mut r, &options).unwrap());
+        assert_eq!(stream.currency, resp.currency);
+        assert_eq!(stream.amount, 100);
+        let filters: Result<Vec<Filter>, _> = stream.collect();
+        assert_eq!(filters.unwrap(), resp.filters);
+
+        let msg = expect_other(read_message(&mut r, &options).unwrap());
+        assert_eq!(msg, Message::Ping([1; 8]));
+
+        // Dropped stream leaves the reader at the next frame
+        let mut stream = expect_filters(read_message(&mut r, &options).unwrap());
+        assert_eq!(stream.next().unwrap().unwrap(), resp.filters[0]);
+        drop(stream);
+        let msg = expect_other(read_message(&mut r, &options).unwrap());
+        assert_eq!(msg, Message::VersionAck);
+    }
+
+    #[test]
+    fn stream_payload_codecs() {
+        let resp = random_filters(20);
+        for kind in [CodecKind::Gzip, CodecKind::Zstd, CodecKind::Identity].iter() {
+            if kind.codec().is_none() {
+                continue;
+            }
+            let mut bytes = vec![];
+            resp.encode_with(&mut bytes, *kind).unwrap();
+            let options = DecodeOptions {
+                codec: *kind,
+                ..Default::default()
+            };
+            let stream = FiltersStream::new(&bytes[..], &options).unwrap();
+            let filters: Result<Vec<Filter>, _> = stream.collect();
+            assert_eq!(filters.unwrap(), resp.filters);
+        }
+    }
+
+    #[test]
+    fn stream_decompression_bomb() {
+        // 65535 empty filters declared, only 31 of them fit into the limit
+        let mut payload = vec![0, 0xfd, 0xff, 0xff];
+        payload.extend(Gzip.compress(&vec![0; 1024 * 1024]).unwrap());
+        let options = DecodeOptions {
+            max_decompressed_size: 1024,
+            ..Default::default()
+        };
+        let stream = FiltersStream::new(&payload[..], &options).unwrap();
+        let filters: Vec<_> = stream.collect();
+        assert_eq!(filters.len(), 32);
+        let e = filters[31].as_ref().unwrap_err();
+        assert_eq!(e.field, "compressed body");
+        assert!(matches!(e.kind, ProtocolErrorKind::DecompressedTooLarge));
+    }
+
+    #[test]
+    fn stream_truncated() {
+        let resp = random_filters(3);
+        let mut bytes = vec![];
+        resp.encode_with(&mut bytes, CodecKind::Identity).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        let options = DecodeOptions {
+            codec: CodecKind::Identity,
+            ..Default::default()
+        };
+        let stream = FiltersStream::new(&bytes[..], &options).unwrap();
+        let filters: Vec<_> = stream.collect();
+        assert_eq!(filters.len(), 3);
+        assert!(matches!(
+            filters[2].as_ref().unwrap_err().kind,
+            ProtocolErrorKind::Truncated
+        ));
+    }
+}
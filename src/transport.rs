@@ -0,0 +1,420 @@
+//! Dialing of peer addresses, directly or through a SOCKS5 proxy like Tor. Onion, I2P and host
+//! name addresses are resolved by the proxy, so they are sent as domain names in the CONNECT
+//! request.
+use crate::message::*;
+use crate::onion::base32_encode;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+const PASSWORD_AUTH_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Username and password of SOCKS5 authentication. Tor puts streams with different credentials
+/// on different circuits, so they are used to isolate connections from each other.
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new<U: Into<String>, P: Into<String>>(username: U, password: P) -> Self {
+        Credentials {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Credentials that differ from all others generated by the process, for a connection
+    /// that should not share circuit with any other
+    pub fn isolated() -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        Credentials::new(format!("ergvein-{:016x}", hasher.finish()), "x")
+    }
+}
+
+/// How peers are reached
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Transport {
+    /// Plain TCP connection, onion and I2P addresses can't be reached
+    Direct,
+    /// Connection through SOCKS5 proxy at given address
+    Socks5(SocketAddr),
+}
+
+impl Transport {
+    /// Connect to the peer. Credentials are sent to the proxy for stream isolation and are
+    /// ignored by direct transport.
+    pub fn connect(
+        &self,
+        address: &Address,
+        credentials: Option<&Credentials>,
+    ) -> Result<TcpStream, io::Error> {
+        match self {
+            Transport::Direct => match address {
+                Address::Ipv4(addr) => TcpStream::connect(addr),
+                Address::Ipv6(addr) => TcpStream::connect(addr),
+                Address::OnionV3(..) => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "onion address needs a proxy",
+                )),
+                Address::I2p(..) => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "I2P address needs a proxy",
+                )),
+                Address::Cjdns(ip, port) => TcpStream::connect((*ip, *port)),
+                Address::Hostname(host, port) => TcpStream::connect((host.as_str(), *port)),
+            },
+            Transport::Socks5(proxy) => {
+                let mut stream = TcpStream::connect(proxy)?;
+                socks5_connect(&mut stream, address, credentials)?;
+                Ok(stream)
+            }
+        }
+    }
+}
+
+/// Proxy broke SOCKS5 protocol
+fn socks_error(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("SOCKS5 proxy: {}", message),
+    )
+}
+
+/// Proxy refused authentication or connection to the peer
+fn socks_refused(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionRefused,
+        format!("SOCKS5 proxy: {}", message),
+    )
+}
+
+/// Perform SOCKS5 negotiation and CONNECT request over the stream to the proxy
+pub fn socks5_connect<S: Read + Write>(
+    stream: &mut S,
+    address: &Address,
+    credentials: Option<&Credentials>,
+) -> Result<(), io::Error> {
+    let method = if credentials.is_some() {
+        METHOD_PASSWORD
+    } else {
+        METHOD_NO_AUTH
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method])?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(socks_error("unsupported version"));
+    }
+    match (reply[1], credentials) {
+        (METHOD_NO_AUTH, None) => (),
+        (METHOD_PASSWORD, Some(credentials)) => authenticate(stream, credentials)?,
+        (METHOD_NONE_ACCEPTABLE, _) => return Err(socks_refused("no acceptable auth method")),
+        _ => return Err(socks_error("unexpected auth method")),
+    }
+
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+    let port = match address {
+        Address::Ipv4(addr) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Address::Ipv6(addr) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Address::Cjdns(ip, port) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+            *port
+        }
+        Address::OnionV3(host, port) => {
+            push_domain(&mut request, &format!("{}.onion", host))?;
+            *port
+        }
+        Address::I2p(hash, port) => {
+            push_domain(&mut request, &format!("{}.b32.i2p", base32_encode(hash)))?;
+            *port
+        }
+        Address::Hostname(host, port) => {
+            push_domain(&mut request, host)?;
+            *port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+    stream.flush()?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(socks_error("unsupported version"));
+    }
+    if reply[1] != 0 {
+        return Err(socks_refused(reply_message(reply[1])));
+    }
+    // Bound address is not used, but it must be consumed before the stream is handed over
+    let bound_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(socks_error("unknown address type in reply")),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+fn push_domain(request: &mut Vec<u8>, domain: &str) -> io::Result<()> {
+    if domain.is_empty() || domain.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SOCKS5 domain must be 1 to 255 bytes long",
+        ));
+    }
+    request.push(ATYP_DOMAIN);
+    request.push(domain.len() as u8);
+    request.extend_from_slice(domain.as_bytes());
+    Ok(())
+}
+
+/// Username and password authentication of RFC 1929
+fn authenticate<S: Read + Write>(stream: &mut S, credentials: &Credentials) -> io::Result<()> {
+    let username = credentials.username.as_bytes();
+    let password = credentials.password.as_bytes();
+    if username.is_empty() || username.len() > 255 || password.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SOCKS5 credentials must be 1 to 255 bytes long",
+        ));
+    }
+    let mut request = vec![PASSWORD_AUTH_VERSION, username.len() as u8];
+    request.extend_from_slice(username);
+    request.push(password.len() as u8);
+    request.extend_from_slice(password);
+    stream.write_all(&request)?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != PASSWORD_AUTH_VERSION {
+        return Err(socks_error("unsupported auth version"));
+    }
+    if reply[1] != 0 {
+        return Err(socks_refused("authentication failed"));
+    }
+    Ok(())
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown failure",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+    use std::thread;
+
+    /// CONNECT request that the proxy received
+    #[derive(Debug, PartialEq)]
+    struct Request {
+        credentials: Option<(Vec<u8>, Vec<u8>)>,
+        atyp: u8,
+        host: Vec<u8>,
+        port: u16,
+    }
+
+    fn read_vec(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn read_u8(stream: &mut TcpStream) -> u8 {
+        read_vec(stream, 1)[0]
+    }
+
+    /// Minimal SOCKS5 proxy that serves one connection, replies with given code and greets
+    /// the client with a ping on success
+    fn spawn_proxy(code: u8) -> (SocketAddr, thread::JoinHandle<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            assert_eq!(read_u8(&mut s), SOCKS_VERSION);
+            let n = read_u8(&mut s) as usize;
+            let methods = read_vec(&mut s, n);
+            let credentials = if methods.contains(&METHOD_PASSWORD) {
+                s.write_all(&[SOCKS_VERSION, METHOD_PASSWORD]).unwrap();
+                assert_eq!(read_u8(&mut s), PASSWORD_AUTH_VERSION);
+                let n = read_u8(&mut s) as usize;
+                let username = read_vec(&mut s, n);
+                let n = read_u8(&mut s) as usize;
+                let password = read_vec(&mut s, n);
+                s.write_all(&[PASSWORD_AUTH_VERSION, 0]).unwrap();
+                Some((username, password))
+            } else {
+                s.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).unwrap();
+                None
+            };
+            assert_eq!(read_vec(&mut s, 3), vec![SOCKS_VERSION, CMD_CONNECT, 0]);
+            let atyp = read_u8(&mut s);
+            let host = match atyp {
+                ATYP_IPV4 => read_vec(&mut s, 4),
+                ATYP_IPV6 => read_vec(&mut s, 16),
+                _ => {
+                    let n = read_u8(&mut s) as usize;
+                    read_vec(&mut s, n)
+                }
+            };
+            let port = u16::from_be_bytes([read_u8(&mut s), read_u8(&mut s)]);
+            s.write_all(&[
+                SOCKS_VERSION,
+                code,
+                0,
+                ATYP_DOMAIN,
+                3,
+                b'a',
+                b'b',
+                b'c',
+                0,
+                80,
+            ])
+            .unwrap();
+            if code == 0 {
+                s.write_all(&serialize(&Message::Ping([7; 8]))).unwrap();
+            }
+            Request {
+                credentials,
+                atyp,
+                host,
+                port,
+            }
+        });
+        (addr, proxy)
+    }
+
+    #[test]
+    fn onion_through_proxy() {
+        let (addr, proxy) = spawn_proxy(0);
+        let host = OnionV3Address::from_public_key([7; 32]);
+        let credentials = Credentials::new("user", "pass");
+        let stream = Transport::Socks5(addr)
+            .connect(&Address::OnionV3(host, 8667), Some(&credentials))
+            .unwrap();
+        assert_eq!(Message::read_from(stream).unwrap(), Message::Ping([7; 8]));
+        assert_eq!(
+            proxy.join().unwrap(),
+            Request {
+                credentials: Some((b"user".to_vec(), b"pass".to_vec())),
+                atyp: ATYP_DOMAIN,
+                host: format!("{}.onion", host).into_bytes(),
+                port: 8667,
+            }
+        );
+    }
+
+    #[test]
+    fn ipv4_through_proxy() {
+        let (addr, proxy) = spawn_proxy(0);
+        let target = Address::Ipv4(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 8667));
+        Transport::Socks5(addr).connect(&target, None).unwrap();
+        assert_eq!(
+            proxy.join().unwrap(),
+            Request {
+                credentials: None,
+                atyp: ATYP_IPV4,
+                host: vec![1, 2, 3, 4],
+                port: 8667,
+            }
+        );
+    }
+
+    #[test]
+    fn hostname_through_proxy() {
+        let (addr, proxy) = spawn_proxy(0);
+        let target = Address::Hostname("indexer.example.com".to_owned(), 8667);
+        Transport::Socks5(addr).connect(&target, None).unwrap();
+        let request = proxy.join().unwrap();
+        assert_eq!(request.atyp, ATYP_DOMAIN);
+        assert_eq!(request.host, b"indexer.example.com".to_vec());
+        assert_eq!(request.port, 8667);
+    }
+
+    #[test]
+    fn proxy_failure() {
+        let (addr, proxy) = spawn_proxy(4);
+        let err = Transport::Socks5(addr)
+            .connect(
+                &Address::OnionV3(OnionV3Address::from_public_key([7; 32]), 8667),
+                None,
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(err.to_string(), "SOCKS5 proxy: host unreachable");
+        proxy.join().unwrap();
+
+        let err = Transport::Direct
+            .connect(
+                &Address::OnionV3(OnionV3Address::from_public_key([7; 32]), 8667),
+                None,
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn auth_version_mismatch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            read_vec(&mut s, 3);
+            s.write_all(&[SOCKS_VERSION, METHOD_PASSWORD]).unwrap();
+            read_vec(&mut s, 11);
+            // Version of SOCKS itself instead of the subnegotiation
+            s.write_all(&[SOCKS_VERSION, 0]).unwrap();
+        });
+        let credentials = Credentials::new("user", "pass");
+        let target = Address::Ipv4(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 8667));
+        let err = Transport::Socks5(addr)
+            .connect(&target, Some(&credentials))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "SOCKS5 proxy: unsupported auth version");
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn isolated_credentials() {
+        assert_ne!(Credentials::isolated(), Credentials::isolated());
+    }
+}
@@ -1,31 +1,151 @@
+use crate::message::MAX_MESSAGE_SIZE;
 use consensus_encode::{Decodable, Encodable, Error, VarInt, MAX_VEC_SIZE};
+use std::io::Cursor;
 use std::{io, mem};
 
-pub struct LengthVec<T>(pub Vec<T>);
+/// Lower bound of encoded size of a value. Length prefixes of vectors are checked against it, so
+/// a peer can't declare more elements than the bytes it sent can hold.
+pub trait MinEncodedSize {
+    const MIN_ENCODED_SIZE: usize;
+}
+
+impl MinEncodedSize for u8 {
+    const MIN_ENCODED_SIZE: usize = 1;
+}
+
+impl MinEncodedSize for Vec<u8> {
+    const MIN_ENCODED_SIZE: usize = 1;
+}
+
+/// Exact size of encoded value computed without serializing it. Allows to write length prefix of
+/// payload before the payload itself.
+pub trait EncodedLen {
+    fn encoded_len(&self) -> usize;
+}
+
+macro_rules! impl_fixed_encoded_len {
+    ($($ty:ty),*) => {
+        $(
+            impl EncodedLen for $ty {
+                #[inline]
+                fn encoded_len(&self) -> usize {
+                    mem::size_of::<$ty>()
+                }
+            }
+        )*
+    };
+}
+impl_fixed_encoded_len!(u8, u16, u32, u64);
+
+impl<const N: usize> EncodedLen for [u8; N] {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        N
+    }
+}
 
-impl<T: Decodable> Decodable for LengthVec<T> {
+impl EncodedLen for VarInt {
     #[inline]
-    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+    fn encoded_len(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Size of length prefixed bytes
+pub fn bytes_encoded_len(len: usize) -> usize {
+    VarInt(len as u64).len() + len
+}
+
+impl EncodedLen for Vec<u8> {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        bytes_encoded_len(self.len())
+    }
+}
+
+impl EncodedLen for String {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        bytes_encoded_len(self.len())
+    }
+}
+
+/// Check that `len` elements of given minimal size fit into `remaining` bytes
+fn check_length(len: u64, min_size: usize, remaining: usize) -> Result<(), Error> {
+    let byte_size = len.saturating_mul(min_size as u64);
+    if byte_size > remaining as u64 {
+        return Err(Error::OversizedVectorAllocation {
+            requested: byte_size.min(usize::MAX as u64) as usize,
+            max: remaining,
+        });
+    }
+    Ok(())
+}
+
+pub struct LengthVec<T>(pub Vec<T>);
+
+impl<T: Decodable + MinEncodedSize> LengthVec<T> {
+    /// Decode vector which elements must fit into `remaining` bytes. Memory is reserved only for
+    /// elements that the remaining bytes can hold, never for the bare declared length.
+    pub fn decode_bounded<D: io::Read>(d: D, remaining: usize) -> Result<Self, Error> {
+        LengthVec::decode_bounded_with(d, remaining, |d| Decodable::consensus_decode(d))
+    }
+}
+
+impl<T: MinEncodedSize> LengthVec<T> {
+    /// Same as `decode_bounded`, but elements are decoded by `decode`, e.g. when they hold
+    /// vectors that must be bounded as well.
+    pub fn decode_bounded_with<D: io::Read, F>(
+        mut d: D,
+        remaining: usize,
+        mut decode: F,
+    ) -> Result<Self, Error>
+    where
+        F: FnMut(&mut D) -> Result<T, Error>,
+    {
         let len = VarInt::consensus_decode(&mut d)?.0;
-        let byte_size = (len as usize)
-            .checked_mul(mem::size_of::<T>())
-            .ok_or(Error::ParseFailed("Invalid length"))?;
-        if byte_size > MAX_VEC_SIZE {
-            return Err(Error::OversizedVectorAllocation {
-                requested: byte_size,
-                max: MAX_VEC_SIZE,
-            });
-        }
-        let mut ret = Vec::with_capacity(len as usize);
+        check_length(len, T::MIN_ENCODED_SIZE, remaining)?;
+        let cap = (len as usize).min(MAX_VEC_SIZE / mem::size_of::<T>().max(1));
+        let mut ret = Vec::with_capacity(cap);
         for _ in 0..len {
-            ret.push(Decodable::consensus_decode(&mut d)?);
+            ret.push(decode(&mut d)?);
         }
         Ok(LengthVec(ret))
     }
 }
 
+impl<T: Decodable + MinEncodedSize> Decodable for LengthVec<T> {
+    /// Elements are bounded by maximum size of message, use `decode_bounded` when the amount of
+    /// remaining bytes is known.
+    #[inline]
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Self, Error> {
+        LengthVec::decode_bounded(d, MAX_MESSAGE_SIZE)
+    }
+}
+
+/// Decode length prefixed bytes that must fit into `remaining` bytes
+pub fn decode_bytes<D: io::Read>(mut d: D, remaining: usize) -> Result<Vec<u8>, Error> {
+    let len = VarInt::consensus_decode(&mut d)?.0;
+    check_length(len, 1, remaining)?;
+    let mut buf = vec![0; len as usize];
+    d.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Amount of bytes left after the cursor position
+pub fn cursor_remaining<T: AsRef<[u8]>>(d: &Cursor<T>) -> usize {
+    (d.get_ref().as_ref().len() as u64).saturating_sub(d.position()) as usize
+}
+
 pub struct LengthVecRef<'a, T>(pub &'a Vec<T>);
 
+impl<'a, T: EncodedLen> EncodedLen for LengthVecRef<'a, T> {
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        VarInt(self.0.len() as u64).len() + self.0.iter().map(|v| v.encoded_len()).sum::<usize>()
+    }
+}
+
 impl<'a, T: Encodable> Encodable for LengthVecRef<'a, T> {
     #[inline]
     fn consensus_encode<S: io::Write>(&self, mut s: S) -> Result<usize, io::Error> {
@@ -37,3 +157,29 @@ impl<'a, T: Encodable> Encodable for LengthVecRef<'a, T> {
         Ok(len)
     }
 }
+
+/// Encode value into a new vector. Unlike `serialize` returns error of the encoder instead of
+/// panicking, e.g. for `FeeResp` with mismatched currency.
+pub fn try_serialize<T: Encodable + ?Sized>(v: &T) -> Result<Vec<u8>, io::Error> {
+    let mut buf = vec![];
+    v.consensus_encode(&mut buf)?;
+    Ok(buf)
+}
+
+/// Try to read `VarInt` from the beginning of the buffer without consuming it. Returns `None` if
+/// the buffer doesn't contain the whole varint yet, otherwise the value and its encoded size.
+pub fn peek_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let size = match first {
+        0xFF => 9,
+        0xFE => 5,
+        0xFD => 3,
+        _ => return Some((first as u64, 1)),
+    };
+    if buf.len() < size {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    bytes[..size - 1].copy_from_slice(&buf[1..size]);
+    Some((u64::from_le_bytes(bytes), size))
+}